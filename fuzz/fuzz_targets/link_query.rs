@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use no_db_verify::verify::{self, CreateParams, ResetParams};
+use secrecy::SecretVec;
+
+fuzz_target!(|query: &[u8]| {
+    // Sealed create links are decrypted while parsing.
+    verify::set_secret_key(SecretVec::new(b"fuzzing key".to_vec()));
+    let _ = serde_urlencoded::from_bytes::<ResetParams>(query);
+    let _ = serde_urlencoded::from_bytes::<CreateParams>(query);
+});
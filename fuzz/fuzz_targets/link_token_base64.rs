@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use no_db_verify::verify::{CreateParams, ResetParams};
+
+// Keeps every other field valid so the input reaches the token's base64 decoding.
+fuzz_target!(|token: &str| {
+    let encoded: String = serde_urlencoded::to_string(&[("token", token)]).unwrap();
+    let reset = format!(
+        "user_id=1&iat=2020-01-01T00%3A00%3A00Z&expires=2020-01-01T03%3A00%3A00Z&{}",
+        encoded
+    );
+    let _ = serde_urlencoded::from_str::<ResetParams>(&reset);
+    let create = format!(
+        "email=a%40example.com&iat=2020-01-01T00%3A00%3A00Z&{}",
+        encoded
+    );
+    let _ = serde_urlencoded::from_str::<CreateParams>(&create);
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use no_db_verify::core;
+
+const KEY: &[u8] = b"fuzzing key";
+
+fuzz_target!(|token: &[u8]| {
+    let version = core::token_version(token);
+    let payload: [&[u8]; 2] = [b"1", b"2020-01-01T00:00:00Z"];
+    for purpose in core::Purpose::ALL.iter().copied() {
+        for algorithm in core::MacAlgorithm::ALL.iter().copied() {
+            let verified = core::verify(algorithm, KEY, purpose, &payload, token);
+            assert!(!verified || version.is_some());
+        }
+        let public_key = core::purpose_public_key(KEY, purpose);
+        let _ = core::verify_with_public_key(&public_key, &payload, token);
+    }
+    let _ = core::verify_with_public_key(&core::public_key(KEY), &payload, token);
+});
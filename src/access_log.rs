@@ -0,0 +1,196 @@
+use hmac::Mac;
+use rand::Rng;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::http::{HeaderMap, HeaderValue, Method};
+use warp::Filter;
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// The peer address of the connection a request came in on. `panics::serve`
+// puts it on each request; requests built in tests have none.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+#[derive(Debug, Clone)]
+enum Destination {
+    Off,
+    Stdout,
+    File(Arc<Mutex<File>>),
+}
+
+// One JSON line per request, to stdout or a file, set by `APP_ACCESS_LOG`
+// (`stdout`, `off` or a path to append to). Client addresses are keyed
+// hashes, so requests from one client can be grouped without the log
+// holding the address. The key is random per process unless
+// `APP_ACCESS_LOG_SALT` pins it, e.g. to compare hashes across restarts.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    destination: Destination,
+    salt: Arc<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    at: String,
+    request_id: &'a str,
+    method: &'a str,
+    route: &'static str,
+    status: u16,
+    latency_ms: f64,
+    client: Option<String>,
+}
+
+impl AccessLog {
+    fn new(destination: Destination) -> Self {
+        let salt = env::var("APP_ACCESS_LOG_SALT")
+            .map(String::into_bytes)
+            .unwrap_or_else(|_| rand::thread_rng().gen::<[u8; 32]>().to_vec());
+        AccessLog {
+            destination,
+            salt: Arc::new(salt),
+        }
+    }
+
+    pub fn off() -> Self {
+        AccessLog::new(Destination::Off)
+    }
+
+    pub fn stdout() -> Self {
+        AccessLog::new(Destination::Stdout)
+    }
+
+    pub fn to_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(Destination::File(Arc::new(Mutex::new(
+            file,
+        )))))
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("APP_ACCESS_LOG").as_deref() {
+            Ok("stdout") | Err(_) => Ok(AccessLog::stdout()),
+            Ok("off") => Ok(AccessLog::off()),
+            Ok(path) => AccessLog::to_file(path)
+                .map_err(|err| format!("APP_ACCESS_LOG: could not open {}: {}", path, err)),
+        }
+    }
+
+    fn hash_ip(&self, ip: IpAddr) -> String {
+        let mut mac = HmacSha256::new_varkey(&self.salt).unwrap();
+        mac.input(ip.to_string().as_bytes());
+        mac.result().code()[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn record(
+        &self,
+        request: &Request,
+        method: &Method,
+        route: &'static str,
+        status: u16,
+        latency: Duration,
+    ) {
+        if let Destination::Off = self.destination {
+            return;
+        }
+        let entry = Entry {
+            at: chrono::Utc::now().to_rfc3339(),
+            request_id: &request.id,
+            method: method.as_str(),
+            route,
+            status,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            client: request.client.map(|addr| self.hash_ip(addr.0.ip())),
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        match &self.destination {
+            Destination::Off => {}
+            Destination::Stdout => println!("{}", line),
+            Destination::File(file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+                    eprintln!("could not write the access log: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub id: String,
+    client: Option<ClientAddr>,
+}
+
+impl Request {
+    pub fn id_header(&self) -> Option<HeaderValue> {
+        HeaderValue::from_str(&self.id).ok()
+    }
+}
+
+// A request id from a proxy in front is kept so the two logs line up, as
+// long as it is short and plain enough to echo back in a header.
+fn usable_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+fn new_request_id() -> String {
+    let bytes: [u8; 8] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn start() -> impl Filter<Extract = (Request,), Error = Infallible> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::ext::optional::<ClientAddr>())
+        .map(|headers: HeaderMap, client: Option<ClientAddr>| Request {
+            id: headers
+                .get(REQUEST_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .filter(|id| usable_request_id(id))
+                .map_or_else(new_request_id, String::from),
+            client,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_request_ids_are_kept() {
+        assert!(usable_request_id("3f2a-9c.b_1"));
+        assert!(!usable_request_id(""));
+        assert!(!usable_request_id("id with spaces"));
+        assert!(!usable_request_id("id\r\nset-cookie: x"));
+        assert!(!usable_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert_eq!(new_request_id().len(), 16);
+    }
+
+    #[test]
+    fn client_addresses_are_hashed_with_the_salt() {
+        let log = AccessLog::off();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let hash = log.hash_ip(ip);
+        assert_eq!(hash, log.hash_ip(ip));
+        assert_ne!(hash, AccessLog::off().hash_ip(ip));
+    }
+}
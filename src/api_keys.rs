@@ -0,0 +1,274 @@
+use crate::env_vars::env_list;
+use crate::sanitize;
+use crate::verify::UtcDateTime;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::Filter;
+
+const MIN_KEY_LEN: usize = 16;
+const CLIENT_KEY_PREFIX: &str = "ndv";
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct MissingScope(pub Scope);
+
+impl warp::reject::Reject for MissingScope {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    ReadUsers,
+    MintTokens,
+    ManageUsers,
+}
+
+pub const ALL_SCOPES: [Scope; 3] = [Scope::ReadUsers, Scope::MintTokens, Scope::ManageUsers];
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::ReadUsers => "read_users",
+            Scope::MintTokens => "mint_tokens",
+            Scope::ManageUsers => "manage_users",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Scope::ReadUsers => "Read users",
+            Scope::MintTokens => "Mint reset and signup tokens",
+            Scope::ManageUsers => "Lock and unlock accounts",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        ALL_SCOPES
+            .iter()
+            .copied()
+            .find(|scope| scope.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: UtcDateTime,
+    pub last_used: Option<UtcDateTime>,
+    digest: [u8; 32],
+}
+
+#[derive(Debug)]
+pub struct NewKeyParams {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl NewKeyParams {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, String> {
+        let mut name = None;
+        let mut scopes = Vec::new();
+        for (field, value) in pairs {
+            match field.as_str() {
+                "name" => name = Some(sanitize::text(&value)),
+                "scope" => {
+                    let scope = Scope::from_name(&value)
+                        .ok_or_else(|| format!("unknown scope `{}`", value))?;
+                    if !scopes.contains(&scope) {
+                        scopes.push(scope);
+                    }
+                }
+                other => {
+                    return Err(format!(
+                        "unknown field `{}`, expected `name` or `scope`",
+                        other
+                    ))
+                }
+            }
+        }
+        let name = name
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| "a key needs a name".to_string())?;
+        if let Some(error) = sanitize::KEY_NAME.check(&name) {
+            return Err(error.to_string());
+        }
+        if scopes.is_empty() {
+            return Err("a key needs at least one scope".to_string());
+        }
+        Ok(NewKeyParams { name, scopes })
+    }
+}
+
+// Keys are only ever stored and compared as digests, which also makes the
+// comparison independent of how long the presented key is.
+#[derive(Debug, Clone)]
+pub struct ApiKeys {
+    digests: Arc<Vec<[u8; 32]>>,
+    clients: Arc<Mutex<BTreeMap<String, ClientKey>>>,
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn same_digest(known: &[u8; 32], candidate: &[u8; 32]) -> bool {
+    known
+        .iter()
+        .zip(candidate.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn bearer(header: Option<&str>) -> Option<&str> {
+    header
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+impl ApiKeys {
+    pub fn new(service_keys: &[String]) -> Result<Self, String> {
+        if service_keys.iter().any(|key| key.len() < MIN_KEY_LEN) {
+            return Err(format!(
+                "APP_SERVICE_API_KEYS entries must be at least {} characters",
+                MIN_KEY_LEN
+            ));
+        }
+        Ok(ApiKeys {
+            digests: Arc::new(service_keys.iter().map(|key| digest(key)).collect()),
+            clients: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        ApiKeys::new(&env_list("APP_SERVICE_API_KEYS").unwrap_or_default())
+    }
+
+    fn accepts(&self, candidate: &str) -> bool {
+        let candidate = digest(candidate);
+        self.digests
+            .iter()
+            .fold(false, |found, known| found | same_digest(known, &candidate))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub async fn create(&self, params: NewKeyParams) -> (ClientKey, String) {
+        let (id, secret) = {
+            let mut rng = rand::thread_rng();
+            (
+                format!("{:012x}", rng.gen::<u64>() >> 16),
+                base64::encode_config(rng.gen::<[u8; 24]>(), base64::URL_SAFE_NO_PAD),
+            )
+        };
+        let key = format!("{}_{}_{}", CLIENT_KEY_PREFIX, id, secret);
+        let client = ClientKey {
+            id: id.clone(),
+            name: params.name,
+            scopes: params.scopes,
+            created: chrono::Utc::now(),
+            last_used: None,
+            digest: digest(&key),
+        };
+        self.clients.lock().await.insert(id, client.clone());
+        (client, key)
+    }
+
+    pub async fn revoke(&self, id: &str) -> Option<ClientKey> {
+        self.clients.lock().await.remove(id)
+    }
+
+    pub async fn list(&self) -> Vec<ClientKey> {
+        self.clients.lock().await.values().cloned().collect()
+    }
+
+    async fn authorize(&self, key: &str, scope: Scope) -> Result<(), warp::reject::Rejection> {
+        let id = key
+            .strip_prefix(CLIENT_KEY_PREFIX)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .and_then(|rest| rest.split('_').next())
+            .ok_or_else(|| warp::reject::custom(Unauthorized))?;
+        let mut clients = self.clients.lock().await;
+        let client = clients
+            .get_mut(id)
+            .filter(|client| same_digest(&client.digest, &digest(key)))
+            .ok_or_else(|| warp::reject::custom(Unauthorized))?;
+        client.last_used = Some(chrono::Utc::now());
+        if client.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(warp::reject::custom(MissingScope(scope)))
+        }
+    }
+
+    pub fn scoped(
+        &self,
+        scope: Scope,
+    ) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+        let keys = self.clone();
+        warp::header::optional::<String>("authorization")
+            .and_then(move |header: Option<String>| {
+                let keys = keys.clone();
+                async move {
+                    match bearer(header.as_deref()) {
+                        Some(key) => keys.authorize(key, scope).await,
+                        None => Err(warp::reject::custom(Unauthorized)),
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    pub fn enabled(&self) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+        let enabled = !self.digests.is_empty();
+        warp::any()
+            .and_then(move || async move {
+                if enabled {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            })
+            .untuple_one()
+    }
+
+    pub fn require(&self) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+        let keys = self.clone();
+        warp::header::optional::<String>("authorization")
+            .and_then(move |header: Option<String>| {
+                let accepted = bearer(header.as_deref()).is_some_and(|key| keys.accepts(key));
+                async move {
+                    if accepted {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            })
+            .untuple_one()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_service_keys_are_an_error() {
+        assert!(ApiKeys::new(&["too short".to_string()]).is_err());
+        let keys = ApiKeys::new(&["a service key long enough".to_string()]).unwrap();
+        assert!(keys.accepts("a service key long enough"));
+        assert!(!keys.accepts("a different service key"));
+    }
+}
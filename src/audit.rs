@@ -0,0 +1,165 @@
+use crate::user::UserId;
+use crate::verify::UtcDateTime;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use warp::Filter;
+
+const HISTORY_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    UserCreated,
+    ResetLinkGenerated,
+    PasswordReset,
+    UserDeleted,
+    ResetTokenRejected,
+    InviteTokenRejected,
+    ShareLinkGenerated,
+    PreferencesChanged,
+    AccountsMerged,
+    EmailAliasAdded,
+    EmailVerificationSent,
+    EmailVerified,
+    PasswordRotationRequested,
+    RevertLinkSent,
+    PasswordResetReverted,
+    AccountLocked,
+    AccountUnlocked,
+    EmailChangeRequested,
+    EmailChangeConfirmed,
+    EmailChanged,
+    EmailChangeCancelled,
+    LinkReported,
+}
+
+impl AuditKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditKind::UserCreated => "user_created",
+            AuditKind::ResetLinkGenerated => "reset_link_generated",
+            AuditKind::PasswordReset => "password_reset",
+            AuditKind::UserDeleted => "user_deleted",
+            AuditKind::ResetTokenRejected => "reset_token_rejected",
+            AuditKind::InviteTokenRejected => "invite_token_rejected",
+            AuditKind::ShareLinkGenerated => "share_link_generated",
+            AuditKind::PreferencesChanged => "preferences_changed",
+            AuditKind::AccountsMerged => "accounts_merged",
+            AuditKind::EmailAliasAdded => "email_alias_added",
+            AuditKind::EmailVerificationSent => "email_verification_sent",
+            AuditKind::EmailVerified => "email_verified",
+            AuditKind::PasswordRotationRequested => "password_rotation_requested",
+            AuditKind::RevertLinkSent => "revert_link_sent",
+            AuditKind::PasswordResetReverted => "password_reset_reverted",
+            AuditKind::AccountLocked => "account_locked",
+            AuditKind::AccountUnlocked => "account_unlocked",
+            AuditKind::EmailChangeRequested => "email_change_requested",
+            AuditKind::EmailChangeConfirmed => "email_change_confirmed",
+            AuditKind::EmailChanged => "email_changed",
+            AuditKind::EmailChangeCancelled => "email_change_cancelled",
+            AuditKind::LinkReported => "link_reported",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub id: u64,
+    pub at: UtcDateTime,
+    pub kind: AuditKind,
+    // Rejected invite tokens belong to nobody yet.
+    pub user_id: Option<UserId>,
+}
+
+#[derive(Debug)]
+struct History {
+    next_id: u64,
+    events: VecDeque<AuditEvent>,
+    sender: broadcast::Sender<AuditEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    history: Arc<Mutex<History>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_LIMIT);
+        let history = History {
+            next_id: 1,
+            events: VecDeque::new(),
+            sender,
+        };
+        AuditLog {
+            history: Arc::new(Mutex::new(history)),
+        }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn record(&self, kind: AuditKind, user_id: impl Into<Option<UserId>>) {
+        let mut history = self.history.lock().unwrap();
+        let event = AuditEvent {
+            id: history.next_id,
+            at: chrono::Utc::now(),
+            kind,
+            user_id: user_id.into(),
+        };
+        history.next_id += 1;
+        if history.events.len() == HISTORY_LIMIT {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Nobody listening is not an error.
+        let _ = history.sender.send(event);
+    }
+
+    // Moves `from`'s history onto `to`, for merged accounts. Returns how many
+    // events moved.
+    pub fn reassign(&self, from: UserId, to: UserId) -> usize {
+        let mut history = self.history.lock().unwrap();
+        let mut moved = 0;
+        for event in history.events.iter_mut() {
+            if event.user_id == Some(from) {
+                event.user_id = Some(to);
+                moved += 1;
+            }
+        }
+        moved
+    }
+
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.history
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(
+        &self,
+        after: Option<u64>,
+    ) -> (Vec<AuditEvent>, broadcast::Receiver<AuditEvent>) {
+        let history = self.history.lock().unwrap();
+        let backlog = match after {
+            Some(last_seen) => history
+                .events
+                .iter()
+                .filter(|event| event.id > last_seen)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (backlog, history.sender.subscribe())
+    }
+}
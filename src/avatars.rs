@@ -0,0 +1,241 @@
+use crate::user::{User, UserId};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use hyper::body::Buf;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use warp::Filter;
+
+pub const MAX_AVATAR_BYTES: u64 = 1024 * 1024;
+// Leaves room for the multipart framing so a slightly oversized image still
+// reaches `save` and gets a readable error instead of a bare 413.
+pub const MAX_UPLOAD_BYTES: u64 = 2 * MAX_AVATAR_BYTES;
+const AVATAR_FIELD: &str = "avatar";
+const DEFAULT_AVATAR_DIR: &str = "avatars";
+const GRAVATAR_STYLES: &[&str] = &[
+    "mp",
+    "identicon",
+    "monsterid",
+    "wavatar",
+    "retro",
+    "robohash",
+    "blank",
+];
+
+#[derive(Debug)]
+pub enum AvatarError {
+    Missing,
+    TooLarge,
+    UnsupportedType,
+    Upload(String),
+    Storage(io::Error),
+}
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AvatarError::Missing => write!(f, "choose an image to upload"),
+            AvatarError::TooLarge => {
+                write!(f, "avatars can be at most {} KiB", MAX_AVATAR_BYTES / 1024)
+            }
+            AvatarError::UnsupportedType => write!(f, "avatars must be PNG, JPEG, GIF or WebP"),
+            AvatarError::Upload(message) => write!(f, "the upload could not be read: {}", message),
+            AvatarError::Storage(err) => write!(f, "the avatar could not be stored: {}", err),
+        }
+    }
+}
+
+pub fn image_type(image: &[u8]) -> Option<&'static str> {
+    match image {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+#[async_trait]
+pub trait BlobStore: fmt::Debug + Send + Sync {
+    async fn put(&self, key: &str, blob: &[u8]) -> io::Result<()>;
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct FileBlobStore {
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileBlobStore { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileBlobStore {
+    async fn put(&self, key: &str, blob: &[u8]) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        let path = self.root.join(key);
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, blob).await?;
+        tokio::fs::rename(&partial, &path).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(blob) => Ok(Some(blob)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Gravatar {
+    default_style: Option<&'static str>,
+}
+
+impl Gravatar {
+    pub fn from_env() -> Self {
+        let default_style = match env::var("APP_GRAVATAR_DEFAULT").as_deref() {
+            Err(_) => Some("identicon"),
+            Ok("off") => None,
+            Ok(style) => Some(
+                GRAVATAR_STYLES
+                    .iter()
+                    .copied()
+                    .find(|known| *known == style)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "APP_GRAVATAR_DEFAULT must be off or one of {}, not {}",
+                            GRAVATAR_STYLES.join(", "),
+                            style
+                        )
+                    }),
+            ),
+        };
+        Gravatar { default_style }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = *self;
+        warp::any().map(move || hanging_copy)
+    }
+
+    pub fn thumbnail(&self, user: &User) -> Option<String> {
+        self.url(user, 64)
+    }
+
+    pub fn portrait(&self, user: &User) -> Option<String> {
+        self.url(user, 256)
+    }
+
+    fn url(&self, user: &User, size: u32) -> Option<String> {
+        let style = self.default_style?;
+        let hash: String = Sha256::digest(user.email.as_str().trim().to_lowercase().as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Some(format!(
+            "https://www.gravatar.com/avatar/{}?d={}&s={}",
+            hash, style, size
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Avatars {
+    store: Arc<dyn BlobStore>,
+}
+
+fn avatar_key(id: UserId) -> String {
+    format!("avatar-{}", id)
+}
+
+impl Avatars {
+    pub fn new(store: impl BlobStore + 'static) -> Self {
+        Avatars {
+            store: Arc::new(store),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("APP_AVATAR_STORE").as_deref() {
+            Ok("file") | Err(_) => {
+                let root = env::var("APP_AVATAR_DIR").unwrap_or_else(|_| DEFAULT_AVATAR_DIR.into());
+                Ok(Avatars::new(FileBlobStore::new(root)))
+            }
+            Ok(other) => Err(format!("APP_AVATAR_STORE must be file, not {}", other)),
+        }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub async fn save(&self, id: UserId, image: &[u8]) -> Result<(), AvatarError> {
+        if image.is_empty() {
+            return Err(AvatarError::Missing);
+        }
+        if image.len() as u64 > MAX_AVATAR_BYTES {
+            return Err(AvatarError::TooLarge);
+        }
+        image_type(image).ok_or(AvatarError::UnsupportedType)?;
+        self.store
+            .put(&avatar_key(id), image)
+            .await
+            .map_err(AvatarError::Storage)
+    }
+
+    pub async fn remove(&self, id: UserId) -> io::Result<()> {
+        self.store.delete(&avatar_key(id)).await
+    }
+
+    // Writes and removes a scratch blob, to find out at startup rather than
+    // on the first upload that the store can't be written to.
+    pub async fn probe(&self) -> io::Result<()> {
+        self.store.put("probe", b"").await?;
+        self.store.delete("probe").await
+    }
+
+    pub async fn load(&self, id: UserId) -> io::Result<Option<(&'static str, Vec<u8>)>> {
+        let image = self.store.get(&avatar_key(id)).await?;
+        Ok(image.and_then(|image| image_type(&image).map(|content_type| (content_type, image))))
+    }
+}
+
+pub async fn read_upload(form: warp::multipart::FormData) -> Result<Vec<u8>, AvatarError> {
+    let parts: Vec<warp::multipart::Part> = form
+        .try_collect()
+        .await
+        .map_err(|err| AvatarError::Upload(err.to_string()))?;
+    let part = parts
+        .into_iter()
+        .find(|part| part.name() == AVATAR_FIELD)
+        .ok_or(AvatarError::Missing)?;
+    part.stream()
+        .try_fold(Vec::new(), |mut image, chunk| async move {
+            image.extend_from_slice(chunk.bytes());
+            Ok(image)
+        })
+        .await
+        .map_err(|err| AvatarError::Upload(err.to_string()))
+}
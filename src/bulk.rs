@@ -0,0 +1,136 @@
+use crate::user::{User, UserId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkAction {
+    ResetLinks,
+    Delete,
+    Export,
+}
+
+impl BulkAction {
+    pub fn title(self) -> &'static str {
+        match self {
+            BulkAction::ResetLinks => "Reset Links",
+            BulkAction::Delete => "Delete",
+            BulkAction::Export => "Export",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BulkAction::ResetLinks => "reset_links",
+            BulkAction::Delete => "delete",
+            BulkAction::Export => "export",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reset_links" => Some(BulkAction::ResetLinks),
+            "delete" => Some(BulkAction::Delete),
+            "export" => Some(BulkAction::Export),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BulkParams {
+    pub action: BulkAction,
+    pub selected: Vec<UserId>,
+}
+
+impl BulkParams {
+    pub fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, String> {
+        let mut action = None;
+        let mut selected = Vec::new();
+        for (field, value) in pairs {
+            match field.as_str() {
+                "action" => {
+                    let parsed = BulkAction::from_name(&value)
+                        .ok_or_else(|| format!("unknown action `{}`", value))?;
+                    action = Some(parsed);
+                }
+                "selected" => {
+                    let id = value
+                        .parse()
+                        .map_err(|_| format!("`{}` is not a user id", value))?;
+                    if !selected.contains(&id) {
+                        selected.push(id);
+                    }
+                }
+                other => {
+                    return Err(format!(
+                        "unknown field `{}`, expected `action` or `selected`",
+                        other
+                    ))
+                }
+            }
+        }
+        let action = action.ok_or_else(|| "missing field `action`".to_string())?;
+        if selected.is_empty() {
+            return Err("no users were selected".to_string());
+        }
+        Ok(BulkParams { action, selected })
+    }
+}
+
+#[derive(Debug)]
+pub struct BulkOutcome {
+    pub id: UserId,
+    pub name: String,
+    pub ok: bool,
+    pub result: String,
+    pub link: Option<String>,
+}
+
+impl BulkOutcome {
+    pub fn done(user: &User, result: &str) -> Self {
+        BulkOutcome {
+            id: user.id,
+            name: user.name.clone(),
+            ok: true,
+            result: result.to_string(),
+            link: None,
+        }
+    }
+
+    pub fn link(user: &User, link: String) -> Self {
+        BulkOutcome {
+            link: Some(link),
+            ..BulkOutcome::done(user, "reset link generated")
+        }
+    }
+
+    pub fn skipped(id: UserId, name: Option<&str>, result: &str) -> Self {
+        BulkOutcome {
+            id,
+            name: name.unwrap_or("-").to_string(),
+            ok: false,
+            result: result.to_string(),
+            link: None,
+        }
+    }
+}
+
+pub fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn export_csv<'a>(users: impl IntoIterator<Item = &'a User>) -> String {
+    let mut csv = String::from("id,username,name,email\n");
+    for user in users {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            user.id,
+            csv_field(&user.username),
+            csv_field(&user.name),
+            csv_field(user.email.as_str())
+        ));
+    }
+    csv
+}
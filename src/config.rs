@@ -0,0 +1,263 @@
+use crate::access_log::AccessLog;
+use crate::api_keys::ApiKeys;
+use crate::avatars::{Avatars, Gravatar};
+use crate::domains::AllowedDomains;
+use crate::env_vars::env_bool;
+use crate::features::{Feature, Features};
+use crate::ids::IdStrategy;
+use crate::limits::Limits;
+use crate::links::Links;
+use crate::names::NameBlocklist;
+use crate::reporting::ReporterChoice;
+use crate::resilience::BackendPolicy;
+use crate::rotation::PasswordRotation;
+use crate::terms::Terms;
+use crate::throttle::ResetThrottle;
+use crate::timing;
+use crate::verify::TokenPolicy;
+use crate::well_known::WellKnown;
+use chrono_tz::Tz;
+use std::env;
+#[cfg(feature = "grpc")]
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub const DEFAULT_APP_NAME: &str = "no-db-verify";
+#[cfg(feature = "grpc")]
+const DEFAULT_GRPC_ADDR: &str = "127.0.0.1:3233";
+
+// A bundle of defaults picked by `APP_ENV`. Each value can still be set on
+// its own with the variable next to it in `Config::from_env`. Leaving
+// `APP_ENV` unset keeps the defaults this app has always had.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Development,
+    Staging,
+    Production,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProfileDefaults {
+    demo: bool,
+    debug_errors: bool,
+    bcrypt_cost: u32,
+    require_tls: bool,
+}
+
+impl Profile {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let name = match env::var("APP_ENV") {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "dev" | "development" => Ok(Some(Profile::Development)),
+            "staging" => Ok(Some(Profile::Staging)),
+            "prod" | "production" => Ok(Some(Profile::Production)),
+            _ => Err(format!(
+                "APP_ENV must be one of development, staging or production, not {}",
+                name
+            )),
+        }
+    }
+
+    fn defaults(profile: Option<Self>) -> ProfileDefaults {
+        match profile {
+            None => ProfileDefaults {
+                demo: false,
+                debug_errors: false,
+                bcrypt_cost: 4,
+                require_tls: false,
+            },
+            Some(Profile::Development) => ProfileDefaults {
+                demo: true,
+                debug_errors: true,
+                bcrypt_cost: 4,
+                require_tls: false,
+            },
+            Some(Profile::Staging) => ProfileDefaults {
+                demo: true,
+                debug_errors: false,
+                bcrypt_cost: 10,
+                require_tls: true,
+            },
+            Some(Profile::Production) => ProfileDefaults {
+                demo: false,
+                debug_errors: false,
+                bcrypt_cost: 12,
+                require_tls: true,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile: Option<Profile>,
+    // Shown in every page title.
+    pub app_name: String,
+    pub demo: bool,
+    pub features: Features,
+    pub well_known: WellKnown,
+    pub gravatar: Gravatar,
+    pub avatars: Avatars,
+    pub api_keys: ApiKeys,
+    pub terms: Terms,
+    pub allowed_domains: AllowedDomains,
+    pub blocked_names: NameBlocklist,
+    pub token_policy: TokenPolicy,
+    pub links: Links,
+    pub reset_throttle: ResetThrottle,
+    // Show error chains and request details on error pages. Development only.
+    pub debug_errors: bool,
+    pub bcrypt_cost: u32,
+    // Mark cookies `Secure` and send HSTS; for use behind a TLS terminator.
+    pub require_tls: bool,
+    pub display_zone: Tz,
+    pub password_rotation: PasswordRotation,
+    pub id_strategy: IdStrategy,
+    pub access_log: AccessLog,
+    pub limits: Limits,
+    // Requests slower than this are logged and counted.
+    pub slow_request_threshold: Duration,
+    // Retries and circuit breaking for calls to remote backends.
+    pub backends: BackendPolicy,
+    pub reporter: ReporterChoice,
+    #[cfg(feature = "grpc")]
+    pub grpc_addr: SocketAddr,
+}
+
+// Keeps reading past a bad variable, so one start lists every mistake
+// instead of stopping at the first.
+fn noted<T>(errors: &mut Vec<String>, result: Result<T, String>) -> Option<T> {
+    result.map_err(|err| errors.push(err)).ok()
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let profile = noted(&mut errors, Profile::from_env()).flatten();
+        let defaults = Profile::defaults(profile);
+        let demo = env::args().skip(1).any(|arg| arg == "--demo")
+            || env_bool("APP_DEMO").unwrap_or(defaults.demo);
+        let mut features = Features::from_env();
+        let api_keys = noted(&mut errors, ApiKeys::from_env());
+        let terms = noted(&mut errors, Terms::from_env());
+        let blocked_names = noted(&mut errors, NameBlocklist::from_env());
+        let token_policy = noted(&mut errors, TokenPolicy::from_env());
+        let links = noted(&mut errors, Links::from_env());
+        let reset_throttle = noted(&mut errors, ResetThrottle::from_env());
+        let bcrypt_cost = match env::var("APP_BCRYPT_COST") {
+            Ok(cost) => noted(
+                &mut errors,
+                cost.parse()
+                    .map_err(|_| format!("APP_BCRYPT_COST must be a number, not {}", cost)),
+            ),
+            Err(_) => Some(defaults.bcrypt_cost),
+        };
+        let display_zone = match env::var("APP_TIMEZONE") {
+            Ok(zone) => noted(
+                &mut errors,
+                zone.parse()
+                    .map_err(|_| format!("APP_TIMEZONE is not a known time zone: {}", zone)),
+            ),
+            Err(_) => Some(Tz::UTC),
+        };
+        let password_rotation = noted(&mut errors, PasswordRotation::from_env());
+        let id_strategy = noted(&mut errors, IdStrategy::from_env());
+        let access_log = noted(&mut errors, AccessLog::from_env());
+        let avatars = noted(&mut errors, Avatars::from_env());
+        let limits = noted(&mut errors, Limits::from_env());
+        let slow_request_threshold = noted(&mut errors, timing::slow_request_threshold());
+        let backends = noted(&mut errors, BackendPolicy::from_env());
+        let reporter = noted(&mut errors, ReporterChoice::from_env());
+        #[cfg(feature = "grpc")]
+        let grpc_addr = {
+            let addr = env::var("APP_GRPC_ADDR").unwrap_or_else(|_| DEFAULT_GRPC_ADDR.to_string());
+            noted(
+                &mut errors,
+                addr.parse()
+                    .map_err(|_| format!("APP_GRPC_ADDR must be a socket address, not {}", addr)),
+            )
+        };
+        // Each `None` left an error behind.
+        let (
+            Some(api_keys),
+            Some(terms),
+            Some(blocked_names),
+            Some(token_policy),
+            Some(links),
+            Some(reset_throttle),
+            Some(bcrypt_cost),
+            Some(display_zone),
+            Some(password_rotation),
+            Some(id_strategy),
+            Some(access_log),
+            Some(avatars),
+            Some(limits),
+            Some(slow_request_threshold),
+            Some(backends),
+            Some(reporter),
+        ) = (
+            api_keys,
+            terms,
+            blocked_names,
+            token_policy,
+            links,
+            reset_throttle,
+            bcrypt_cost,
+            display_zone,
+            password_rotation,
+            id_strategy,
+            access_log,
+            avatars,
+            limits,
+            slow_request_threshold,
+            backends,
+            reporter,
+        )
+        else {
+            return Err(errors);
+        };
+        #[cfg(feature = "grpc")]
+        let Some(grpc_addr) = grpc_addr
+        else {
+            return Err(errors);
+        };
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        if token_policy.invite_only {
+            features.disable(Feature::OpenRegistration);
+        }
+        Ok(Config {
+            profile,
+            app_name: env::var("APP_NAME").unwrap_or_else(|_| DEFAULT_APP_NAME.to_string()),
+            demo,
+            features,
+            well_known: WellKnown::from_env(),
+            gravatar: Gravatar::from_env(),
+            avatars,
+            api_keys,
+            terms,
+            allowed_domains: AllowedDomains::from_env(),
+            blocked_names,
+            token_policy,
+            links,
+            reset_throttle,
+            debug_errors: env_bool("APP_DEBUG_ERRORS").unwrap_or(defaults.debug_errors),
+            bcrypt_cost,
+            require_tls: env_bool("APP_REQUIRE_TLS").unwrap_or(defaults.require_tls),
+            display_zone,
+            password_rotation,
+            id_strategy,
+            access_log,
+            limits,
+            slow_request_threshold,
+            backends,
+            reporter,
+            #[cfg(feature = "grpc")]
+            grpc_addr,
+        })
+    }
+}
@@ -0,0 +1,435 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use hmac::Mac;
+use rand::Rng;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+type HmacSha3_256 = hmac::Hmac<sha3::Sha3_256>;
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+type HkdfSha256 = hkdf::Hkdf<sha2::Sha256>;
+
+const BLAKE3_KEY_CONTEXT: &str = "no-db-verify 2020 token mac";
+const ED25519_SEED_CONTEXT: &str = "no-db-verify 2020 ed25519 seed";
+const PURPOSE_KEY_SALT: &[u8] = b"no-db-verify 2020 purpose keys";
+const SEAL_NONCE_LEN: usize = 24;
+const LEGACY_TOKEN_LEN: usize = 32;
+const VERSION_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MacAlgorithm {
+    #[default]
+    Sha3_256,
+    Sha256,
+    Blake3,
+    Ed25519,
+}
+
+impl MacAlgorithm {
+    pub const ALL: [MacAlgorithm; 4] = [
+        MacAlgorithm::Sha3_256,
+        MacAlgorithm::Sha256,
+        MacAlgorithm::Blake3,
+        MacAlgorithm::Ed25519,
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            MacAlgorithm::Sha3_256 => 1,
+            MacAlgorithm::Sha256 => 2,
+            MacAlgorithm::Blake3 => 3,
+            MacAlgorithm::Ed25519 => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MacAlgorithm::Sha3_256 => "sha3-256",
+            MacAlgorithm::Sha256 => "sha256",
+            MacAlgorithm::Blake3 => "blake3",
+            MacAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn is_asymmetric(self) -> bool {
+        self == MacAlgorithm::Ed25519
+    }
+
+    fn mac(self, key: &[u8], payload: &[&[u8]]) -> Vec<u8> {
+        match self {
+            MacAlgorithm::Sha3_256 => accum_hmac::<HmacSha3_256>(key, payload),
+            MacAlgorithm::Sha256 => accum_hmac::<HmacSha256>(key, payload),
+            MacAlgorithm::Blake3 => blake3_mac(key, payload).as_bytes().to_vec(),
+            MacAlgorithm::Ed25519 => ed25519_key(key).sign(&payload.concat()).to_bytes().to_vec(),
+        }
+    }
+
+    fn verify(self, key: &[u8], payload: &[&[u8]], mac: &[u8]) -> bool {
+        match self {
+            MacAlgorithm::Sha3_256 => verify_hmac::<HmacSha3_256>(key, payload, mac),
+            MacAlgorithm::Sha256 => verify_hmac::<HmacSha256>(key, payload, mac),
+            MacAlgorithm::Blake3 => blake3_mac(key, payload) == *mac,
+            MacAlgorithm::Ed25519 => verify_ed25519(&public_key(key), payload, mac),
+        }
+    }
+}
+
+// Algorithms other than the configured one whose tokens still verify, for
+// moving from one to another without breaking the links already sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MacAlgorithmSet(u8);
+
+impl MacAlgorithmSet {
+    pub const EMPTY: MacAlgorithmSet = MacAlgorithmSet(0);
+
+    pub fn with(self, algorithm: MacAlgorithm) -> Self {
+        MacAlgorithmSet(self.0 | (1 << algorithm.id()))
+    }
+
+    pub fn contains(self, algorithm: MacAlgorithm) -> bool {
+        self.0 & (1 << algorithm.id()) != 0
+    }
+}
+
+impl fmt::Display for MacAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for MacAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown MAC algorithm {}", name))
+    }
+}
+
+fn accum_mac<M: Mac>(key: &[u8], payload: &[&[u8]]) -> M {
+    let mut mac = M::new_varkey(key).unwrap();
+    for part in payload {
+        mac.input(part);
+    }
+    mac
+}
+
+fn blake3_mac(key: &[u8], payload: &[&[u8]]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(&blake3::derive_key(BLAKE3_KEY_CONTEXT, key));
+    for part in payload {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+fn ed25519_key(key: &[u8]) -> SigningKey {
+    SigningKey::from_bytes(&blake3::derive_key(ED25519_SEED_CONTEXT, key))
+}
+
+fn verify_ed25519(public_key: &[u8; 32], payload: &[&[u8]], signature: &[u8]) -> bool {
+    let signature = match ed25519_dalek::Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    VerifyingKey::from_bytes(public_key)
+        .and_then(|public_key| public_key.verify_strict(&payload.concat(), &signature))
+        .is_ok()
+}
+
+pub fn public_key(key: &[u8]) -> [u8; 32] {
+    ed25519_key(key).verifying_key().to_bytes()
+}
+
+pub fn purpose_public_key(key: &[u8], purpose: Purpose) -> [u8; 32] {
+    public_key(&purpose_key(key, purpose))
+}
+
+fn accum_hmac<M: Mac>(key: &[u8], payload: &[&[u8]]) -> Vec<u8> {
+    let mac = accum_mac::<M>(key, payload);
+    Vec::from(mac.result().code().as_slice())
+}
+
+fn verify_hmac<M: Mac>(key: &[u8], payload: &[&[u8]], mac: &[u8]) -> bool {
+    accum_mac::<M>(key, payload).verify(mac).is_ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Reset,
+    Create,
+    EmailChange,
+    Login,
+    Share,
+    Unsubscribe,
+    Merge,
+    Revert,
+    ResetIntent,
+    VerifyEmail,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 10] = [
+        Purpose::Reset,
+        Purpose::Create,
+        Purpose::EmailChange,
+        Purpose::Login,
+        Purpose::Share,
+        Purpose::Unsubscribe,
+        Purpose::Merge,
+        Purpose::Revert,
+        Purpose::ResetIntent,
+        Purpose::VerifyEmail,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Purpose::Reset => "reset",
+            Purpose::Create => "create",
+            Purpose::EmailChange => "email-change",
+            Purpose::Login => "login",
+            Purpose::Share => "share",
+            Purpose::Unsubscribe => "unsubscribe",
+            Purpose::Merge => "merge",
+            Purpose::Revert => "revert",
+            Purpose::ResetIntent => "reset-intent",
+            Purpose::VerifyEmail => "verify-email",
+        }
+    }
+}
+
+fn derive_key(key: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut derived = [0; 32];
+    HkdfSha256::new(Some(PURPOSE_KEY_SALT), key)
+        .expand(info, &mut derived)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    derived
+}
+
+pub fn purpose_key(key: &[u8], purpose: Purpose) -> [u8; 32] {
+    derive_key(key, purpose.name().as_bytes())
+}
+
+fn sealing_cipher(key: &[u8], purpose: Purpose) -> XChaCha20Poly1305 {
+    let info = format!("{} encryption", purpose.name());
+    XChaCha20Poly1305::new(&derive_key(key, info.as_bytes()).into())
+}
+
+// `[nonce][ciphertext and tag]`, with `aad` authenticated but not included.
+pub fn seal(key: &[u8], purpose: Purpose, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce: [u8; SEAL_NONCE_LEN] = rand::thread_rng().gen();
+    let ciphertext = sealing_cipher(key, purpose)
+        .encrypt(
+            &XNonce::from(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("XChaCha20-Poly1305 encryption does not fail for in-memory payloads");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+pub fn open(key: &[u8], purpose: Purpose, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < SEAL_NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(SEAL_NONCE_LEN);
+    let nonce: [u8; SEAL_NONCE_LEN] = nonce.try_into().ok()?;
+    sealing_cipher(key, purpose)
+        .decrypt(
+            &XNonce::from(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TokenVersion {
+    // A bare HMAC-SHA3-256, from before tokens carried any header.
+    V0,
+    // `[algorithm id][mac]`.
+    V1,
+    // `[VERSION_FLAG | 2][algorithm id][mac]`, with the header bytes covered by the mac.
+    V2,
+    // Laid out like V2, but keyed with `purpose_key` instead of the master key.
+    V3,
+    // Laid out and keyed like V3, but every signed part goes in behind its
+    // length, so moving bytes from one part into the next changes the mac.
+    V4,
+}
+
+impl TokenVersion {
+    pub const CURRENT: TokenVersion = TokenVersion::V4;
+}
+
+struct ParsedToken<'t> {
+    version: TokenVersion,
+    algorithm: MacAlgorithm,
+    header: &'t [u8],
+    mac: &'t [u8],
+}
+
+impl<'t> ParsedToken<'t> {
+    fn parse(token: &'t [u8]) -> Option<Self> {
+        if token.len() == LEGACY_TOKEN_LEN {
+            return Some(ParsedToken {
+                version: TokenVersion::V0,
+                algorithm: MacAlgorithm::Sha3_256,
+                header: &[],
+                mac: token,
+            });
+        }
+        match token {
+            [version, id, mac @ ..] if *version == VERSION_FLAG | 4 => Some(ParsedToken {
+                version: TokenVersion::V4,
+                algorithm: MacAlgorithm::from_id(*id)?,
+                header: &token[..2],
+                mac,
+            }),
+            [version, id, mac @ ..] if *version == VERSION_FLAG | 3 => Some(ParsedToken {
+                version: TokenVersion::V3,
+                algorithm: MacAlgorithm::from_id(*id)?,
+                header: &token[..2],
+                mac,
+            }),
+            [version, id, mac @ ..] if *version == VERSION_FLAG | 2 => Some(ParsedToken {
+                version: TokenVersion::V2,
+                algorithm: MacAlgorithm::from_id(*id)?,
+                header: &token[..2],
+                mac,
+            }),
+            [id, mac @ ..] if id & VERSION_FLAG == 0 => Some(ParsedToken {
+                version: TokenVersion::V1,
+                algorithm: MacAlgorithm::from_id(*id)?,
+                header: &[],
+                mac,
+            }),
+            _ => None,
+        }
+    }
+
+    fn signed_message(&self, payload: &[&[u8]]) -> Vec<u8> {
+        let mut parts = Vec::with_capacity(payload.len() + 1);
+        if !self.header.is_empty() {
+            parts.push(self.header);
+        }
+        parts.extend_from_slice(payload);
+        if self.version >= TokenVersion::V4 {
+            framed(&parts)
+        } else {
+            parts.concat()
+        }
+    }
+}
+
+// Each part behind its length as a big-endian u32.
+fn framed(parts: &[&[u8]]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(parts.iter().map(|part| part.len() + 4).sum());
+    for part in parts {
+        let len = u32::try_from(part.len()).expect("token payload parts are under 4 GiB");
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(part);
+    }
+    message
+}
+
+pub fn sign(algorithm: MacAlgorithm, key: &[u8], purpose: Purpose, payload: &[&[u8]]) -> Vec<u8> {
+    let mut token = vec![VERSION_FLAG | 4, algorithm.id()];
+    let mut parts = vec![&token[..]];
+    parts.extend_from_slice(payload);
+    let mac = algorithm.mac(&purpose_key(key, purpose), &[&framed(&parts)]);
+    token.extend(mac);
+    token
+}
+
+pub fn token_version(token: &[u8]) -> Option<TokenVersion> {
+    ParsedToken::parse(token).map(|parsed| parsed.version)
+}
+
+pub fn verify_accepting(
+    key: &[u8],
+    purpose: Purpose,
+    payload: &[&[u8]],
+    token: &[u8],
+    accept: impl Fn(TokenVersion, MacAlgorithm) -> bool,
+) -> bool {
+    match ParsedToken::parse(token) {
+        // Tokens from before V3 were keyed with the master secret and name no
+        // purpose, so a MAC minted for one flow would pass in another. They
+        // are refused whatever `accept` allows.
+        Some(parsed)
+            if parsed.version >= TokenVersion::V3 && accept(parsed.version, parsed.algorithm) =>
+        {
+            let message = parsed.signed_message(payload);
+            parsed
+                .algorithm
+                .verify(&purpose_key(key, purpose), &[&message], parsed.mac)
+        }
+        _ => false,
+    }
+}
+
+// The algorithm is the caller's to choose, never the token's: a token naming
+// any other one is refused.
+pub fn verify(
+    algorithm: MacAlgorithm,
+    key: &[u8],
+    purpose: Purpose,
+    payload: &[&[u8]],
+    token: &[u8],
+) -> bool {
+    verify_accepting(key, purpose, payload, token, |_, used| used == algorithm)
+}
+
+pub fn verify_with_public_key(public_key: &[u8; 32], payload: &[&[u8]], token: &[u8]) -> bool {
+    match ParsedToken::parse(token) {
+        Some(parsed) if parsed.algorithm == MacAlgorithm::Ed25519 => {
+            verify_ed25519(public_key, &[&parsed.signed_message(payload)], parsed.mac)
+        }
+        _ => false,
+    }
+}
+
+pub fn verify_unexpired(
+    algorithm: MacAlgorithm,
+    key: &[u8],
+    purpose: Purpose,
+    payload: &[&[u8]],
+    token: &[u8],
+    expires: SystemTime,
+    now: SystemTime,
+) -> bool {
+    now <= expires && verify(algorithm, key, purpose, payload, token)
+}
+
+pub fn within_lifetime(
+    issued_at: SystemTime,
+    expires: SystemTime,
+    max_lifetime: Duration,
+    leeway: Duration,
+    now: SystemTime,
+) -> bool {
+    issued_at <= now + leeway
+        && now <= expires + leeway
+        && expires
+            .duration_since(issued_at)
+            .is_ok_and(|lifetime| lifetime <= max_lifetime)
+}
@@ -0,0 +1,59 @@
+use crate::env_vars::env_list;
+use std::sync::Arc;
+use warp::Filter;
+
+// An empty list lets any address sign up. Domains match exactly, so
+// allowing `spookysoftware.dev` does not also allow its subdomains.
+#[derive(Debug, Clone)]
+pub struct AllowedDomains {
+    domains: Arc<Vec<String>>,
+}
+
+fn domain_of(email: &str) -> Option<String> {
+    email
+        .trim()
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+impl AllowedDomains {
+    pub fn new(domains: Vec<String>) -> Self {
+        let domains = domains
+            .iter()
+            .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        AllowedDomains {
+            domains: Arc::new(domains),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        AllowedDomains::new(env_list("APP_ALLOWED_EMAIL_DOMAINS").unwrap_or_default())
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn allows(&self, email: &str) -> bool {
+        self.domains.is_empty()
+            || domain_of(email).is_some_and(|domain| self.domains.contains(&domain))
+    }
+
+    pub fn rejection(&self) -> String {
+        let domains: Vec<String> = self
+            .domains
+            .iter()
+            .map(|domain| format!("@{}", domain))
+            .collect();
+        format!(
+            "Sorry, only {} addresses can sign up here.",
+            domains.join(", ")
+        )
+    }
+}
@@ -0,0 +1,29 @@
+use std::env;
+use std::time::Duration;
+
+pub fn env_bool(name: &str) -> Option<bool> {
+    env::var(name)
+        .ok()
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+pub fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+pub fn env_secs(name: &str) -> Result<Option<Duration>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|_| format!("{} must be a number of seconds, not {}", name, value)),
+        Err(_) => Ok(None),
+    }
+}
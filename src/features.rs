@@ -0,0 +1,87 @@
+use crate::env_vars::env_bool;
+use std::collections::HashSet;
+use warp::Filter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    OpenRegistration,
+    ApiEnabled,
+    SmsChannel,
+    LookalikeScreening,
+}
+
+const ALL_FEATURES: [Feature; 4] = [
+    Feature::OpenRegistration,
+    Feature::ApiEnabled,
+    Feature::SmsChannel,
+    Feature::LookalikeScreening,
+];
+
+impl Feature {
+    fn name(self) -> &'static str {
+        match self {
+            Feature::OpenRegistration => "open_registration",
+            Feature::ApiEnabled => "api_enabled",
+            Feature::SmsChannel => "sms_channel",
+            Feature::LookalikeScreening => "lookalike_screening",
+        }
+    }
+
+    fn enabled_by_default(self) -> bool {
+        self == Feature::OpenRegistration
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Features {
+    enabled: HashSet<Feature>,
+}
+
+impl Features {
+    pub fn from_env() -> Self {
+        let enabled = ALL_FEATURES
+            .iter()
+            .copied()
+            .filter(|feature| {
+                let var = format!("APP_FEATURE_{}", feature.name().to_uppercase());
+                env_bool(&var).unwrap_or_else(|| feature.enabled_by_default())
+            })
+            .collect();
+        Features { enabled }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn enable(&mut self, feature: Feature) {
+        self.enabled.insert(feature);
+    }
+
+    pub fn disable(&mut self, feature: Feature) {
+        self.enabled.remove(&feature);
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.enabled.contains(&feature)
+    }
+
+    pub fn require(
+        &self,
+        feature: Feature,
+    ) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+        let enabled = self.is_enabled(feature);
+        warp::any()
+            .and_then(move || async move {
+                if enabled {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            })
+            .untuple_one()
+    }
+}
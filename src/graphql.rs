@@ -0,0 +1,226 @@
+use crate::audit::{AuditKind, AuditLog};
+use crate::features::{Feature, Features};
+use crate::links::Links;
+use crate::names::NameBlocklist;
+use crate::reporting::{self, ErrorEvent};
+use crate::sanitize;
+use crate::server::{CREATE_USER_PATHNAME, RESET_PASSWORD_PATHNAME};
+use crate::service;
+use crate::throttle::ResetThrottle;
+use crate::tokens::UsedTokenStore;
+use crate::user::{User, UserBuilder, UserDatabase, UserError, UserId};
+use crate::verify;
+use crate::waitlist::Waitlist;
+use async_graphql::{Context, EmptySubscription, Object, Result, Schema, SimpleObject, ID};
+use serde::de::DeserializeOwned;
+use zeroize::Zeroizing;
+
+pub type GraphQLSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+#[derive(SimpleObject)]
+#[graphql(name = "User")]
+struct UserObject {
+    id: ID,
+    name: String,
+    username: String,
+    email: String,
+}
+
+impl From<&User> for UserObject {
+    fn from(user: &User) -> Self {
+        UserObject {
+            id: user.id.into(),
+            name: user.name.clone(),
+            username: user.username.clone(),
+            email: user.email.as_str().to_string(),
+        }
+    }
+}
+
+fn parse_id(id: &ID) -> Result<UserId> {
+    id.parse::<UserId>()
+        .map_err(|_| "user ids are unsigned integers".into())
+}
+
+async fn link_params<T: DeserializeOwned>(ctx: &Context<'_>, link: &str) -> Result<T> {
+    ctx.data::<Links>()?
+        .resolve(link)
+        .await
+        .map_err(|err| err.to_string().into())
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<UserObject>> {
+        let users = ctx.data::<UserDatabase>()?.lock().await;
+        let mut users = users.values().collect::<Vec<_>>();
+        users.sort_unstable_by_key(|user| user.id);
+        Ok(users.into_iter().map(UserObject::from).collect())
+    }
+
+    async fn user(&self, ctx: &Context<'_>, id: ID) -> Result<Option<UserObject>> {
+        let id = parse_id(&id)?;
+        let users = ctx.data::<UserDatabase>()?.lock().await;
+        Ok(users.get(&id).map(UserObject::from))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn generate_reset_link(&self, ctx: &Context<'_>, user_id: ID) -> Result<Option<String>> {
+        let id = parse_id(&user_id)?;
+        let users = ctx.data::<UserDatabase>()?.lock().await;
+        let user = match users.get(&id) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        ctx.data::<ResetThrottle>()?
+            .issue(user.id)
+            .map_err(|throttled| throttled.to_string())?;
+        let params = verify::ResetParams::from(user);
+        ctx.data::<AuditLog>()?
+            .record(AuditKind::ResetLinkGenerated, user.id);
+        let link = ctx
+            .data::<Links>()?
+            .url(RESET_PASSWORD_PATHNAME, &params)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(Some(link))
+    }
+
+    async fn generate_signup_link(&self, ctx: &Context<'_>, email: String) -> Result<String> {
+        if !ctx
+            .data::<Features>()?
+            .is_enabled(Feature::OpenRegistration)
+        {
+            return Err("registration is closed".into());
+        }
+        if !ctx.data::<Waitlist>()?.is_open().await {
+            return Err("registration is closed; join the waitlist instead".into());
+        }
+        let params = verify::CreateParams::from(email.as_ref());
+        Ok(ctx
+            .data::<Links>()?
+            .url(CREATE_USER_PATHNAME, &params)
+            .await
+            .map_err(|err| err.to_string())?)
+    }
+
+    async fn revoke_link(&self, ctx: &Context<'_>, link: String) -> Result<bool> {
+        Ok(ctx.data::<Links>()?.revoke(&link).await)
+    }
+
+    async fn reset_password(
+        &self,
+        ctx: &Context<'_>,
+        link: String,
+        new_password: String,
+    ) -> Result<bool> {
+        let new_password = Zeroizing::new(new_password);
+        let params = link_params::<verify::ResetParams>(ctx, &link).await?;
+        let mut users = ctx.data::<UserDatabase>()?.lock().await;
+        let user = match users.get_mut(&params.user_id()) {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        if user.is_locked() {
+            return Err("account locked, contact support".into());
+        }
+        let is_valid = verify::ResetParams::verify(user, &params)
+            && ctx.data::<UsedTokenStore>()?.consume(&params).await;
+        if is_valid {
+            user.reset_password(&new_password).map_err(|err| {
+                reporting::report(
+                    ErrorEvent::new("hash_error", err.to_string()).with("user_id", user.id),
+                );
+                "could not set the new password"
+            })?;
+            let audit = ctx.data::<AuditLog>()?;
+            audit.record(AuditKind::PasswordReset, user.id);
+            let links = ctx.data::<Links>()?;
+            links.spend(&params).await;
+            service::send_revert_link(links, audit, user)
+                .await
+                .map_err(|err| err.to_string())?;
+        } else {
+            ctx.data::<AuditLog>()?
+                .record(AuditKind::ResetTokenRejected, user.id);
+        }
+        Ok(is_valid)
+    }
+
+    async fn create_user(
+        &self,
+        ctx: &Context<'_>,
+        link: String,
+        name: String,
+        username: String,
+        password: String,
+    ) -> Result<bool> {
+        let password = Zeroizing::new(password);
+        let params = link_params::<verify::CreateParams>(ctx, &link).await?;
+        let email = params.email();
+        if !verify::CreateParams::verify(email, &params) {
+            ctx.data::<AuditLog>()?
+                .record(AuditKind::InviteTokenRejected, None);
+            return Ok(false);
+        }
+        let name = sanitize::text(&name);
+        let username = sanitize::text(&username);
+        let mut errors = crate::service::create_user_errors(&name, &username, &password);
+        errors.extend(ctx.data::<NameBlocklist>()?.errors(&name, &username));
+        if let Some(error) = errors.first() {
+            return Err((*error).into());
+        }
+        let mut new_user = UserBuilder::new();
+        new_user
+            .with_email(email)
+            .with_password(&password)
+            .with_name(&name)
+            .with_username(&username)
+            .with_lookalike_screening(
+                ctx.data::<Features>()?
+                    .is_enabled(Feature::LookalikeScreening),
+            );
+        let id = ctx
+            .data::<UserDatabase>()?
+            .add_user(new_user)
+            .await
+            .map_err(|err| {
+                if let UserError::Hash(err) = &err {
+                    reporting::report(ErrorEvent::new("hash_error", err.to_string()));
+                }
+                err.to_string()
+            })?;
+        ctx.data::<AuditLog>()?.record(AuditKind::UserCreated, id);
+        ctx.data::<Links>()?.spend(&params).await;
+        Ok(true)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn schema(
+    db: UserDatabase,
+    used_tokens: UsedTokenStore,
+    audit: AuditLog,
+    features: Features,
+    names: NameBlocklist,
+    links: Links,
+    waitlist: Waitlist,
+    reset_throttle: ResetThrottle,
+) -> GraphQLSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(db)
+        .data(used_tokens)
+        .data(audit)
+        .data(features)
+        .data(names)
+        .data(links)
+        .data(waitlist)
+        .data(reset_throttle)
+        .finish()
+}
@@ -0,0 +1,170 @@
+use crate::audit::{AuditKind, AuditLog};
+use crate::features::{Feature, Features};
+use crate::links::{LinkError, Links};
+use crate::names::NameBlocklist;
+use crate::reporting::{self, ErrorEvent};
+use crate::sanitize;
+use crate::server::RESET_PASSWORD_PATHNAME;
+use crate::throttle::ResetThrottle;
+use crate::tokens::UsedTokenStore;
+use crate::user::{UserBuilder, UserDatabase, UserError};
+use crate::verify;
+use std::net::SocketAddr;
+use tonic::{Request, Response, Status};
+use zeroize::Zeroizing;
+
+pub mod proto {
+    tonic::include_proto!("verify");
+}
+
+use proto::verify_server::{Verify, VerifyServer};
+use proto::{
+    CreateUserReply, CreateUserRequest, GenerateResetTokenReply, GenerateResetTokenRequest,
+    VerifyResetTokenReply, VerifyResetTokenRequest,
+};
+
+pub struct VerifyService {
+    db: UserDatabase,
+    used_tokens: UsedTokenStore,
+    audit: AuditLog,
+    links: Links,
+    reset_throttle: ResetThrottle,
+    names: NameBlocklist,
+    features: Features,
+}
+
+impl VerifyService {
+    pub fn new(
+        db: UserDatabase,
+        used_tokens: UsedTokenStore,
+        audit: AuditLog,
+        links: Links,
+        reset_throttle: ResetThrottle,
+        names: NameBlocklist,
+        features: Features,
+    ) -> Self {
+        VerifyService {
+            db,
+            used_tokens,
+            audit,
+            links,
+            reset_throttle,
+            names,
+            features,
+        }
+    }
+}
+
+fn malformed_link(err: LinkError) -> Status {
+    match err {
+        LinkError::Unknown => Status::not_found(err.to_string()),
+        _ => Status::invalid_argument(err.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl Verify for VerifyService {
+    async fn generate_reset_token(
+        &self,
+        request: Request<GenerateResetTokenRequest>,
+    ) -> Result<Response<GenerateResetTokenReply>, Status> {
+        let users = self.db.lock().await;
+        let user = users
+            .get(&request.get_ref().user_id)
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        self.reset_throttle
+            .issue(user.id)
+            .map_err(|throttled| Status::resource_exhausted(throttled.to_string()))?;
+        let params = verify::ResetParams::from(user);
+        self.audit.record(AuditKind::ResetLinkGenerated, user.id);
+        let link = self
+            .links
+            .url(RESET_PASSWORD_PATHNAME, &params)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(GenerateResetTokenReply { link }))
+    }
+
+    async fn verify_reset_token(
+        &self,
+        request: Request<VerifyResetTokenRequest>,
+    ) -> Result<Response<VerifyResetTokenReply>, Status> {
+        let params = self
+            .links
+            .resolve::<verify::ResetParams>(&request.get_ref().link)
+            .await
+            .map_err(malformed_link)?;
+        let users = self.db.lock().await;
+        let valid = users
+            .get(&params.user_id())
+            .map(|user| !user.is_locked() && verify::ResetParams::verify(user, &params))
+            .unwrap_or(false)
+            && !self.used_tokens.is_used(&params).await;
+        Ok(Response::new(VerifyResetTokenReply {
+            valid,
+            user_id: params.user_id(),
+        }))
+    }
+
+    async fn create_user(
+        &self,
+        request: Request<CreateUserRequest>,
+    ) -> Result<Response<CreateUserReply>, Status> {
+        let CreateUserRequest {
+            link,
+            name,
+            username,
+            password,
+        } = request.into_inner();
+        let password = Zeroizing::new(password);
+        let params = self
+            .links
+            .resolve::<verify::CreateParams>(&link)
+            .await
+            .map_err(malformed_link)?;
+        let email = params.email();
+        if !verify::CreateParams::verify(email, &params) {
+            self.audit.record(AuditKind::InviteTokenRejected, None);
+            return Err(Status::permission_denied("that token seems no good"));
+        }
+        let name = sanitize::text(&name);
+        let username = sanitize::text(&username);
+        let mut errors = crate::service::create_user_errors(&name, &username, &password);
+        errors.extend(self.names.errors(&name, &username));
+        if let Some(error) = errors.first() {
+            return Err(Status::invalid_argument(*error));
+        }
+        let mut new_user = UserBuilder::new();
+        new_user
+            .with_email(email)
+            .with_password(&password)
+            .with_name(&name)
+            .with_username(&username)
+            .with_lookalike_screening(self.features.is_enabled(Feature::LookalikeScreening));
+        let user_id = self.db.add_user(new_user).await.map_err(|err| match err {
+            UserError::Hash(err) => {
+                reporting::report(ErrorEvent::new("hash_error", err.to_string()));
+                Status::internal("could not hash the password")
+            }
+            UserError::EmailTaken | UserError::UsernameTaken => {
+                Status::already_exists(err.to_string())
+            }
+            UserError::Incomplete | UserError::InvalidUsername(_) | UserError::HiddenCharacters => {
+                Status::invalid_argument(err.to_string())
+            }
+        })?;
+        self.audit.record(AuditKind::UserCreated, user_id);
+        self.links.spend(&params).await;
+        Ok(Response::new(CreateUserReply { user_id }))
+    }
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    service: VerifyService,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(VerifyServer::new(service))
+        .serve(addr)
+        .await
+}
@@ -0,0 +1,61 @@
+use crate::metrics::Metrics;
+use crate::pii::PasswordHash;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+
+const DEFAULT_BCRYPT_COST: u32 = 4;
+
+static METRICS: RwLock<Option<Metrics>> = RwLock::new(None);
+static BCRYPT_COST: AtomicU32 = AtomicU32::new(DEFAULT_BCRYPT_COST);
+
+pub fn install(metrics: Metrics, cost: u32) {
+    *METRICS.write().unwrap() = Some(metrics);
+    BCRYPT_COST.store(cost, Ordering::Relaxed);
+}
+
+// Counts the operation as in flight until it is dropped, so the gauge is
+// right even if bcrypt panics.
+struct InFlight {
+    metrics: Option<Metrics>,
+    operation: &'static str,
+    started: Instant,
+}
+
+impl InFlight {
+    fn start(operation: &'static str) -> Self {
+        let metrics = METRICS.read().unwrap().clone();
+        if let Some(metrics) = &metrics {
+            metrics.add_to_gauge("bcrypt_operations_in_flight", 1);
+        }
+        InFlight {
+            metrics,
+            operation,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.add_to_gauge("bcrypt_operations_in_flight", -1);
+            metrics.observe_labeled(
+                "bcrypt_duration_seconds",
+                "operation",
+                self.operation,
+                self.started.elapsed(),
+            );
+        }
+    }
+}
+
+pub fn hash(password: &str) -> Result<PasswordHash, bcrypt::BcryptError> {
+    let _in_flight = InFlight::start("hash");
+    bcrypt::hash(password, BCRYPT_COST.load(Ordering::Relaxed)).map(PasswordHash::new)
+}
+
+pub fn verify(candidate: &str, hash: &PasswordHash) -> bool {
+    let _in_flight = InFlight::start("verify");
+    bcrypt::verify(candidate, hash.as_str()).unwrap_or(false)
+}
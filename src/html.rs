@@ -1,50 +1,359 @@
-use crate::user::{User, UserTable};
+use crate::api_keys::{ClientKey, Scope, ALL_SCOPES};
+use crate::avatars::Gravatar;
+use crate::bulk::{BulkAction, BulkOutcome};
+use crate::config::{self, Config};
+use crate::features::Feature;
+use crate::pii::Email;
+use crate::rotation::{PasswordAge, PasswordRotation};
+use crate::stats::Stats;
+use crate::terms::Terms;
+use crate::user::{NotificationTopic, User, UserId, UserTable};
+use crate::verify::UtcDateTime;
+use crate::waitlist::WaitlistEntry;
 use askama::Template;
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use url::{Position, Url};
+use warp::Filter;
 
 pub trait HtmlStringReply {
-    fn as_html(&self) -> Result<String, askama::Error>;
+    fn as_html(&self) -> Result<String, RenderError>;
 }
 
-pub fn create_url(pathname: &str, qwargs: Option<&impl serde::Serialize>) -> String {
-    match qwargs {
-        Some(params) => {
-            let qwargs = serde_url_params::to_string(params).unwrap();
-            format!("{}?{}", pathname, qwargs)
+// Which template failed, so debug error pages and reports can name it.
+#[derive(Debug)]
+pub struct RenderError {
+    pub template: &'static str,
+    pub source: askama::Error,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} could not be rendered: {}",
+            self.template, self.source
+        )
+    }
+}
+
+// Only used to resolve relative paths; `create_url` never returns the origin.
+const URL_BASE: &str = "http://localhost/";
+
+#[derive(Debug)]
+pub enum UrlError {
+    Path(url::ParseError),
+    Query(serde_urlencoded::ser::Error),
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlError::Path(err) => write!(f, "the link path is not valid: {}", err),
+            UrlError::Query(err) => write!(f, "the link query could not be encoded: {}", err),
         }
-        None => pathname.to_string(),
     }
 }
 
+pub fn encode_query(params: &impl serde::Serialize) -> Result<String, UrlError> {
+    serde_urlencoded::to_string(params).map_err(UrlError::Query)
+}
+
+pub fn create_url(
+    pathname: &str,
+    qwargs: Option<&impl serde::Serialize>,
+) -> Result<String, UrlError> {
+    let base = Url::parse(URL_BASE).expect("URL_BASE is a valid url");
+    let mut url = base.join(pathname).map_err(UrlError::Path)?;
+    if let Some(params) = qwargs {
+        let query: Vec<String> = url
+            .query()
+            .map(str::to_string)
+            .into_iter()
+            .chain(Some(encode_query(params)?))
+            .filter(|part| !part.is_empty())
+            .collect();
+        let query = query.join("&");
+        url.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+    }
+    Ok(url[Position::BeforePath..].to_string())
+}
+
 impl<T: Template> HtmlStringReply for T {
-    fn as_html(&self) -> Result<String, askama::Error> {
-        self.render()
+    fn as_html(&self) -> Result<String, RenderError> {
+        self.render().map_err(|source| RenderError {
+            template: std::any::type_name::<T>(),
+            source,
+        })
+    }
+}
+
+// What the base layout shows on every page. Built once per app and handed to
+// handlers by `PageContext::inject`; `render` makes it the context for the
+// templates rendered inside it, which read it back through `page()`.
+#[derive(Debug, Clone)]
+pub struct PageContext {
+    pub app_name: String,
+    pub open_registration: bool,
+    pub api_enabled: bool,
+}
+
+impl Default for PageContext {
+    fn default() -> Self {
+        PageContext {
+            app_name: config::DEFAULT_APP_NAME.to_string(),
+            open_registration: true,
+            api_enabled: true,
+        }
+    }
+}
+
+thread_local! {
+    static PAGE_CONTEXT: RefCell<Option<PageContext>> = const { RefCell::new(None) };
+}
+
+impl PageContext {
+    pub fn from_config(config: &Config) -> Self {
+        PageContext {
+            app_name: config.app_name.clone(),
+            open_registration: config.features.is_enabled(Feature::OpenRegistration),
+            api_enabled: config.features.is_enabled(Feature::ApiEnabled),
+        }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    // Rendering is synchronous, so the context only has to outlive `render`.
+    // Pages rendered outside one, like the panic page, get the defaults.
+    pub fn render<T>(&self, render: impl FnOnce() -> T) -> T {
+        let outer = PAGE_CONTEXT.with(|page| page.replace(Some(self.clone())));
+        let rendered = render();
+        PAGE_CONTEXT.with(|page| *page.borrow_mut() = outer);
+        rendered
+    }
+}
+
+pub trait Layout {
+    fn page(&self) -> PageContext {
+        PAGE_CONTEXT.with(|page| page.borrow().clone().unwrap_or_default())
+    }
+}
+
+impl<T: Template> Layout for T {}
+
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (amount, unit) = match secs {
+        s if s % 86_400 == 0 => (s / 86_400, "day"),
+        s if s % 3_600 == 0 => (s / 3_600, "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    if amount == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", amount, unit)
     }
 }
 
+// Every timestamp a page shows is rendered in this zone. Stored values stay
+// in UTC.
+static DISPLAY_ZONE: RwLock<Tz> = RwLock::new(Tz::UTC);
+
+const TIMESTAMP_FORMAT: &str = "%-d %b %Y, %H:%M %Z";
+
+pub fn set_display_zone(zone: Tz) {
+    *DISPLAY_ZONE.write().unwrap() = zone;
+}
+
+pub fn timestamp(at: &UtcDateTime) -> String {
+    let zone = *DISPLAY_ZONE.read().unwrap();
+    at.with_timezone(&zone).format(TIMESTAMP_FORMAT).to_string()
+}
+
+fn expiry_note(expires: &UtcDateTime, valid_for: Duration) -> String {
+    format!(
+        "Valid for {}, until {}.",
+        human_duration(valid_for),
+        timestamp(expires)
+    )
+}
+
 #[derive(Template)]
 #[template(path = "generate_reset.html")]
 pub struct GeneratePasswordResetTemplate<'a, 'b> {
     user: &'a User,
     link: &'b str,
+    report_link: &'b str,
+    expiry: String,
+    expires_at: i64,
 }
 
 impl<'a, 'b> GeneratePasswordResetTemplate<'a, 'b> {
-    pub fn from_user_reset_link(user: &'a User, link: &'b str) -> Self {
-        GeneratePasswordResetTemplate { user, link }
+    pub fn from_user_reset_link(
+        user: &'a User,
+        link: &'b str,
+        report_link: &'b str,
+        expires: &UtcDateTime,
+        valid_for: Duration,
+    ) -> Self {
+        GeneratePasswordResetTemplate {
+            user,
+            link,
+            report_link,
+            expiry: expiry_note(expires, valid_for),
+            expires_at: expires.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListedUser<'a> {
+    id: UserId,
+    name: &'a str,
+    username: &'a str,
+    email: &'a str,
+    // The demo page shows hashes; scripts reading the JSON have no need to.
+    #[serde(skip)]
+    password_hash: &'a str,
+    avatar_url: Option<String>,
+    lookalike_of: Option<UserId>,
+    needs_tos_acceptance: bool,
+    password_age: PasswordAge,
+    locked: bool,
+    reported: bool,
+}
+
+// What `/list` shows, whether rendered as the page, as its htmx rows or as
+// JSON for `/list?format=json`.
+#[derive(Debug, Serialize)]
+pub struct UserListing<'a> {
+    users: Vec<ListedUser<'a>>,
+    tos_version: String,
+}
+
+impl<'a> UserListing<'a> {
+    pub fn from_table(
+        table: &'a UserTable,
+        gravatar: Gravatar,
+        terms: &Terms,
+        rotation: &PasswordRotation,
+    ) -> Self {
+        let now = chrono::Utc::now();
+        let mut users = table.values().collect::<Vec<_>>();
+        users.sort_unstable_by_key(|user| user.id);
+        UserListing {
+            users: users
+                .into_iter()
+                .map(|user| ListedUser {
+                    id: user.id,
+                    name: &user.name,
+                    username: &user.username,
+                    email: user.email.as_str(),
+                    password_hash: user.bcrypt_password.as_str(),
+                    avatar_url: if user.has_avatar {
+                        Some(format!("/users/{}/avatar", user.id))
+                    } else {
+                        gravatar.thumbnail(user)
+                    },
+                    lookalike_of: user.lookalike_of,
+                    needs_tos_acceptance: terms.needs_acceptance(user),
+                    password_age: rotation.age(user, now),
+                    locked: user.is_locked(),
+                    reported: user.reported_at.is_some(),
+                })
+                .collect(),
+            tos_version: terms.version().to_string(),
+        }
+    }
+
+    // Case-insensitive substring match on name, username or email.
+    pub fn matching(mut self, filter: &str) -> Self {
+        let filter = filter.to_lowercase();
+        self.users.retain(|user| {
+            [user.name, user.username, user.email]
+                .iter()
+                .any(|field| field.to_lowercase().contains(&filter))
+        });
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "share_link.html")]
+pub struct ShareLinkTemplate<'a> {
+    link: &'a str,
+    filter: Option<&'a str>,
+    expiry: String,
+}
+
+impl<'a> ShareLinkTemplate<'a> {
+    pub fn from_link(
+        link: &'a str,
+        filter: Option<&'a str>,
+        expires: &UtcDateTime,
+        valid_for: Duration,
+    ) -> Self {
+        ShareLinkTemplate {
+            link,
+            filter,
+            expiry: expiry_note(expires, valid_for),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "shared_list.html")]
+pub struct SharedListTemplate<'a> {
+    listing: UserListing<'a>,
+    filter: Option<&'a str>,
+    expiry: String,
+}
+
+impl<'a> SharedListTemplate<'a> {
+    pub fn from_listing(
+        listing: UserListing<'a>,
+        filter: Option<&'a str>,
+        expires: &UtcDateTime,
+    ) -> Self {
+        SharedListTemplate {
+            listing,
+            filter,
+            expiry: format!("This read-only view expires {}.", timestamp(expires)),
+        }
     }
 }
 
 #[derive(Template)]
 #[template(path = "list.html")]
 pub struct ListUsersTemplate<'a> {
-    users: Vec<&'a User>,
+    listing: UserListing<'a>,
 }
 
-impl<'a> From<&'a UserTable> for ListUsersTemplate<'a> {
-    fn from(table: &'a UserTable) -> Self {
-        let mut users = table.values().collect::<Vec<_>>();
-        users.sort_unstable_by_key(|user| user.id);
-        ListUsersTemplate { users }
+impl<'a> ListUsersTemplate<'a> {
+    pub fn from_listing(listing: UserListing<'a>) -> Self {
+        ListUsersTemplate { listing }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "fragments/user_rows.html")]
+pub struct UserRowsTemplate<'a> {
+    listing: UserListing<'a>,
+}
+
+impl<'a> UserRowsTemplate<'a> {
+    pub fn from_listing(listing: UserListing<'a>) -> Self {
+        UserRowsTemplate { listing }
     }
 }
 
@@ -53,6 +362,10 @@ impl<'a> From<&'a UserTable> for ListUsersTemplate<'a> {
 pub struct ResetPasswordTemplate<'a> {
     user: &'a User,
     success: Option<bool>,
+    continue_link: Option<String>,
+    error: Option<&'static str>,
+    // Epoch millis for `countdown.js`; only set while the link is usable.
+    expires_at: Option<i64>,
 }
 
 impl<'a> ResetPasswordTemplate<'a> {
@@ -60,13 +373,33 @@ impl<'a> ResetPasswordTemplate<'a> {
         ResetPasswordTemplate {
             user,
             success: Some(is_valid),
+            continue_link: None,
+            error: None,
+            expires_at: None,
         }
     }
 
-    pub fn from_user(user: &'a User) -> Self {
+    pub fn from_user(user: &'a User, expires: &UtcDateTime) -> Self {
         ResetPasswordTemplate {
             user,
             success: None,
+            continue_link: None,
+            error: None,
+            expires_at: Some(expires.timestamp_millis()),
+        }
+    }
+
+    pub fn with_error(user: &'a User, error: &'static str, expires: &UtcDateTime) -> Self {
+        ResetPasswordTemplate {
+            error: Some(error),
+            ..ResetPasswordTemplate::from_user(user, expires)
+        }
+    }
+
+    pub fn confirm(user: &'a User, continue_link: String, expires: &UtcDateTime) -> Self {
+        ResetPasswordTemplate {
+            continue_link: Some(continue_link),
+            ..ResetPasswordTemplate::from_user(user, expires)
         }
     }
 }
@@ -75,11 +408,65 @@ impl<'a> ResetPasswordTemplate<'a> {
 #[template(path = "new_user.html")]
 pub struct NewUserTemplate<'a> {
     email_info: Option<(&'a str, &'a str)>,
+    check_email: bool,
+    open: bool,
+    waitlisted: Option<&'a str>,
+    error: Option<&'a str>,
+    expiry: String,
 }
 
 impl<'a> NewUserTemplate<'a> {
-    pub fn from_email(email_info: Option<(&'a str, &'a str)>) -> Self {
-        NewUserTemplate { email_info }
+    pub fn form(check_email: bool, open: bool) -> Self {
+        NewUserTemplate {
+            email_info: None,
+            check_email,
+            open,
+            waitlisted: None,
+            error: None,
+            expiry: String::new(),
+        }
+    }
+
+    pub fn rejected(error: &'a str, open: bool) -> Self {
+        NewUserTemplate {
+            error: Some(error),
+            ..NewUserTemplate::form(false, open)
+        }
+    }
+
+    pub fn waitlisted(email: &'a str) -> Self {
+        NewUserTemplate {
+            waitlisted: Some(email),
+            ..NewUserTemplate::form(false, false)
+        }
+    }
+
+    pub fn from_email(
+        email_info: Option<(&'a str, &'a str)>,
+        expires: &UtcDateTime,
+        valid_for: Duration,
+    ) -> Self {
+        NewUserTemplate {
+            email_info,
+            check_email: false,
+            open: true,
+            waitlisted: None,
+            error: None,
+            expiry: expiry_note(expires, valid_for),
+        }
+    }
+}
+
+#[derive(Template, serde::Serialize)]
+#[template(path = "fragments/email_available.html")]
+pub struct EmailAvailableTemplate<'a> {
+    email: &'a str,
+    available: bool,
+}
+
+impl<'a> EmailAvailableTemplate<'a> {
+    pub fn from_email(email: &'a str, available: bool) -> Self {
+        EmailAvailableTemplate { email, available }
     }
 }
 
@@ -87,16 +474,425 @@ impl<'a> NewUserTemplate<'a> {
 #[template(path = "create_user.html")]
 pub struct CreateUserTemplate {
     success: Option<bool>,
+    errors: Vec<&'static str>,
+    tos_version: String,
+    tos_url: Option<String>,
 }
 
 impl CreateUserTemplate {
-    pub fn form() -> Self {
-        CreateUserTemplate { success: None }
+    pub fn form(terms: &Terms) -> Self {
+        CreateUserTemplate::form_with_errors(Vec::new(), terms)
+    }
+
+    pub fn form_with_errors(errors: Vec<&'static str>, terms: &Terms) -> Self {
+        CreateUserTemplate {
+            success: None,
+            errors,
+            tos_version: terms.version().to_string(),
+            tos_url: terms.url().map(str::to_string),
+        }
     }
 
     pub fn report_success(success: bool) -> Self {
         CreateUserTemplate {
             success: Some(success),
+            errors: Vec::new(),
+            tos_version: String::new(),
+            tos_url: None,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "fragments/create_user_form.html")]
+pub struct CreateUserFormTemplate {
+    success: Option<bool>,
+    errors: Vec<&'static str>,
+    tos_version: String,
+    tos_url: Option<String>,
+}
+
+impl From<CreateUserTemplate> for CreateUserFormTemplate {
+    fn from(page: CreateUserTemplate) -> Self {
+        CreateUserFormTemplate {
+            success: page.success,
+            errors: page.errors,
+            tos_version: page.tos_version,
+            tos_url: page.tos_url,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "user_detail.html")]
+pub struct UserDetailTemplate<'a> {
+    user: &'a User,
+    gravatar: Gravatar,
+    success: Option<bool>,
+    message: String,
+    // An email verification link to hand over, since nothing mails it yet.
+    link: Option<String>,
+    // How long the links just minted stay valid.
+    expiry: Option<String>,
+}
+
+impl<'a> UserDetailTemplate<'a> {
+    pub fn from_user(user: &'a User, gravatar: Gravatar) -> Self {
+        UserDetailTemplate {
+            user,
+            gravatar,
+            success: None,
+            message: String::new(),
+            link: None,
+            expiry: None,
+        }
+    }
+
+    pub fn with_notice(user: &'a User, gravatar: Gravatar, success: bool, message: String) -> Self {
+        UserDetailTemplate {
+            success: Some(success),
+            message,
+            ..UserDetailTemplate::from_user(user, gravatar)
+        }
+    }
+
+    pub fn with_link(
+        user: &'a User,
+        gravatar: Gravatar,
+        link: String,
+        expires: &UtcDateTime,
+        valid_for: Duration,
+    ) -> Self {
+        UserDetailTemplate {
+            link: Some(link),
+            ..UserDetailTemplate::with_expiring_notice(
+                user,
+                gravatar,
+                "Follow this link to verify the address:".to_string(),
+                expires,
+                valid_for,
+            )
+        }
+    }
+
+    pub fn with_expiring_notice(
+        user: &'a User,
+        gravatar: Gravatar,
+        message: String,
+        expires: &UtcDateTime,
+        valid_for: Duration,
+    ) -> Self {
+        UserDetailTemplate {
+            expiry: Some(expiry_note(expires, valid_for)),
+            ..UserDetailTemplate::with_notice(user, gravatar, true, message)
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "locked.html")]
+pub struct AccountLockedTemplate;
+
+#[derive(Template)]
+#[template(path = "report.html")]
+pub struct ReportTemplate<'a> {
+    user: &'a User,
+    reported: bool,
+}
+
+impl<'a> ReportTemplate<'a> {
+    pub fn from_user(user: &'a User, reported: bool) -> Self {
+        ReportTemplate { user, reported }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "revert.html")]
+pub struct RevertTemplate<'a> {
+    user: &'a User,
+    reverted: bool,
+}
+
+impl<'a> RevertTemplate<'a> {
+    pub fn from_user(user: &'a User, reverted: bool) -> Self {
+        RevertTemplate { user, reverted }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "unsubscribed.html")]
+pub struct UnsubscribedTemplate {
+    description: &'static str,
+    success: bool,
+}
+
+impl UnsubscribedTemplate {
+    pub fn from_topic(topic: NotificationTopic, success: bool) -> Self {
+        UnsubscribedTemplate {
+            description: topic.description(),
+            success,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "bulk_result.html")]
+pub struct BulkResultTemplate {
+    action: &'static str,
+    applied: bool,
+    outcomes: Vec<BulkOutcome>,
+    // Set for a dry run: the form fields that would apply it for real.
+    fields: Option<Vec<(&'static str, String)>>,
+}
+
+impl BulkResultTemplate {
+    pub fn applied(action: &'static str, outcomes: Vec<BulkOutcome>) -> Self {
+        BulkResultTemplate {
+            action,
+            applied: true,
+            outcomes,
+            fields: None,
+        }
+    }
+
+    pub fn rejected(action: &'static str, outcomes: Vec<BulkOutcome>) -> Self {
+        BulkResultTemplate {
+            action,
+            applied: false,
+            outcomes,
+            fields: None,
+        }
+    }
+
+    pub fn preview(action: BulkAction, selected: &[UserId], outcomes: Vec<BulkOutcome>) -> Self {
+        let mut fields = vec![("action", action.name().to_string())];
+        fields.extend(selected.iter().map(|id| ("selected", id.to_string())));
+        BulkResultTemplate {
+            action: action.title(),
+            applied: false,
+            outcomes,
+            fields: Some(fields),
+        }
+    }
+}
+
+struct ApiKeyRow {
+    id: String,
+    name: String,
+    scopes: String,
+    created: String,
+    last_used: String,
+}
+
+impl From<ClientKey> for ApiKeyRow {
+    fn from(key: ClientKey) -> Self {
+        let scopes: Vec<&str> = key.scopes.iter().map(Scope::name).collect();
+        ApiKeyRow {
+            id: key.id,
+            name: key.name,
+            scopes: scopes.join(", "),
+            created: timestamp(&key.created),
+            last_used: key
+                .last_used
+                .as_ref()
+                .map_or_else(|| "never".to_string(), timestamp),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "api_keys.html")]
+pub struct ApiKeysTemplate {
+    keys: Vec<ApiKeyRow>,
+    scopes: &'static [Scope],
+    notice: Option<String>,
+    created_key: Option<String>,
+}
+
+impl ApiKeysTemplate {
+    pub fn from_keys(keys: Vec<ClientKey>) -> Self {
+        ApiKeysTemplate {
+            keys: keys.into_iter().map(ApiKeyRow::from).collect(),
+            scopes: &ALL_SCOPES,
+            notice: None,
+            created_key: None,
+        }
+    }
+
+    pub fn created(keys: Vec<ClientKey>, name: &str, key: String) -> Self {
+        ApiKeysTemplate {
+            notice: Some(format!(
+                "Created `{}`. Copy the key now; it won't be shown again.",
+                name
+            )),
+            created_key: Some(key),
+            ..ApiKeysTemplate::from_keys(keys)
+        }
+    }
+
+    pub fn revoked(keys: Vec<ClientKey>, name: &str) -> Self {
+        ApiKeysTemplate {
+            notice: Some(format!("Revoked `{}`.", name)),
+            ..ApiKeysTemplate::from_keys(keys)
+        }
+    }
+}
+
+struct WaitlistRow {
+    email: String,
+    joined: String,
+}
+
+impl From<WaitlistEntry> for WaitlistRow {
+    fn from(entry: WaitlistEntry) -> Self {
+        WaitlistRow {
+            email: entry.email.as_str().to_string(),
+            joined: timestamp(&entry.joined),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "waitlist.html")]
+pub struct WaitlistTemplate {
+    open: bool,
+    waiting: Vec<WaitlistRow>,
+    notice: Option<String>,
+    invites: Vec<(String, String)>,
+    expiry: Option<String>,
+}
+
+impl WaitlistTemplate {
+    pub fn from_entries(
+        open: bool,
+        waiting: Vec<WaitlistEntry>,
+        notice: Option<String>,
+        invites: Vec<(Email, String)>,
+        expires: Option<&UtcDateTime>,
+        valid_for: Duration,
+    ) -> Self {
+        WaitlistTemplate {
+            open,
+            waiting: waiting.into_iter().map(WaitlistRow::from).collect(),
+            notice,
+            invites: invites
+                .into_iter()
+                .map(|(email, link)| (email.as_str().to_string(), link))
+                .collect(),
+            expiry: expires.map(|expires| expiry_note(expires, valid_for)),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "maintenance.html")]
+pub struct MaintenanceTemplate {
+    on: bool,
+    notice: Option<&'static str>,
+}
+
+impl MaintenanceTemplate {
+    pub fn new(on: bool, notice: Option<&'static str>) -> Self {
+        MaintenanceTemplate { on, notice }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "merge.html")]
+pub struct MergeTemplate<'a> {
+    users: &'a [User],
+    error: Option<&'a str>,
+    // Both set once a pair has been picked.
+    primary: Option<&'a User>,
+    duplicate: Option<&'a User>,
+    // The signed choice, as hidden fields for the confirmation form.
+    fields: Vec<(String, String)>,
+    moved_events: Option<usize>,
+    dry_run: bool,
+}
+
+impl<'a> MergeTemplate<'a> {
+    pub fn form(users: &'a [User], error: Option<&'a str>) -> Self {
+        MergeTemplate {
+            users,
+            error,
+            primary: None,
+            duplicate: None,
+            fields: Vec::new(),
+            moved_events: None,
+            dry_run: false,
+        }
+    }
+
+    pub fn confirm(
+        primary: &'a User,
+        duplicate: &'a User,
+        params: &impl Serialize,
+    ) -> Result<Self, UrlError> {
+        let query = encode_query(params)?;
+        Ok(MergeTemplate {
+            primary: Some(primary),
+            duplicate: Some(duplicate),
+            fields: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            ..MergeTemplate::form(&[], None)
+        })
+    }
+
+    pub fn merged(primary: &'a User, duplicate: &'a User, moved_events: usize) -> Self {
+        MergeTemplate {
+            primary: Some(primary),
+            duplicate: Some(duplicate),
+            moved_events: Some(moved_events),
+            ..MergeTemplate::form(&[], None)
+        }
+    }
+
+    pub fn preview(
+        primary: &'a User,
+        duplicate: &'a User,
+        params: &impl Serialize,
+        moved_events: usize,
+    ) -> Result<Self, UrlError> {
+        Ok(MergeTemplate {
+            moved_events: Some(moved_events),
+            dry_run: true,
+            ..MergeTemplate::confirm(primary, duplicate, params)?
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+pub struct StatsTemplate {
+    stats: Stats,
+}
+
+impl StatsTemplate {
+    pub fn from_stats(stats: Stats) -> Self {
+        StatsTemplate { stats }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorTemplate<'a> {
+    message: &'a str,
+    details: Vec<(&'static str, String)>,
+}
+
+impl<'a> ErrorTemplate<'a> {
+    pub fn from_message(message: &'a str) -> Self {
+        ErrorTemplate {
+            message,
+            details: Vec::new(),
         }
     }
+
+    // Only built when `debug_errors` is on; the details can include request
+    // paths and internal error text.
+    pub fn with_details(message: &'a str, details: Vec<(&'static str, String)>) -> Self {
+        ErrorTemplate { message, details }
+    }
 }
@@ -0,0 +1,131 @@
+use unicode_normalization::UnicodeNormalization;
+
+// Zero-width characters, fillers that render as nothing, and the bidi
+// controls that can reorder how the rest of a name is displayed.
+const HIDDEN: &[(char, char)] = &[
+    ('\u{00AD}', '\u{00AD}'),
+    ('\u{034F}', '\u{034F}'),
+    ('\u{061C}', '\u{061C}'),
+    ('\u{115F}', '\u{1160}'),
+    ('\u{17B4}', '\u{17B5}'),
+    ('\u{180E}', '\u{180E}'),
+    ('\u{200B}', '\u{200F}'),
+    ('\u{202A}', '\u{202E}'),
+    ('\u{2060}', '\u{2064}'),
+    ('\u{2066}', '\u{2069}'),
+    ('\u{3164}', '\u{3164}'),
+    ('\u{FEFF}', '\u{FEFF}'),
+    ('\u{FFA0}', '\u{FFA0}'),
+];
+
+// Letters from other scripts that are drawn the same as a Latin one. This is
+// a short list of the common offenders, not the full Unicode confusables set.
+const LOOKALIKES: &[(char, char)] = &[
+    ('а', 'a'),
+    ('е', 'e'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('ԁ', 'd'),
+    ('һ', 'h'),
+    ('ӏ', 'l'),
+    ('А', 'A'),
+    ('В', 'B'),
+    ('Е', 'E'),
+    ('К', 'K'),
+    ('М', 'M'),
+    ('Н', 'H'),
+    ('О', 'O'),
+    ('Р', 'P'),
+    ('С', 'C'),
+    ('Т', 'T'),
+    ('Х', 'X'),
+    ('І', 'I'),
+    ('Ј', 'J'),
+    ('Ѕ', 'S'),
+    ('ο', 'o'),
+    ('ν', 'v'),
+    ('ρ', 'p'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+    ('Α', 'A'),
+    ('Β', 'B'),
+    ('Ε', 'E'),
+    ('Ζ', 'Z'),
+    ('Η', 'H'),
+    ('Ι', 'I'),
+    ('Κ', 'K'),
+    ('Μ', 'M'),
+    ('Ν', 'N'),
+    ('Ο', 'O'),
+    ('Ρ', 'P'),
+    ('Τ', 'T'),
+    ('Υ', 'Y'),
+    ('Χ', 'X'),
+    ('0', 'o'),
+    ('1', 'l'),
+    ('I', 'l'),
+    ('|', 'l'),
+];
+
+pub fn normalize(value: &str) -> String {
+    value.nfc().collect()
+}
+
+pub fn has_hidden_characters(value: &str) -> bool {
+    value
+        .chars()
+        .any(|c| c.is_control() || HIDDEN.iter().any(|&(first, last)| first <= c && c <= last))
+}
+
+fn unconfuse(c: char) -> char {
+    LOOKALIKES
+        .iter()
+        .find(|&&(lookalike, _)| lookalike == c)
+        .map_or(c, |&(_, latin)| latin)
+}
+
+// Two values with the same skeleton read the same on screen.
+pub fn skeleton(value: &str) -> String {
+    value
+        .nfkc()
+        .map(unconfuse)
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn is_lookalike(candidate: &str, existing: &str) -> bool {
+    normalize(candidate).to_lowercase() != normalize(existing).to_lowercase()
+        && skeleton(candidate) == skeleton(existing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_and_screens() {
+        assert_eq!(normalize("Re\u{301}my"), "R\u{e9}my");
+        for hidden in &["Ne\u{200B}o", "\u{202E}oeN", "Neo\u{FEFF}", "Ne\u{2066}o"] {
+            assert!(has_hidden_characters(hidden), "{:?}", hidden);
+        }
+        assert!(!has_hidden_characters("Zoë O'Brien-Smith"));
+    }
+
+    #[test]
+    fn flags_only_lookalikes() {
+        assert!(is_lookalike("N\u{435}o", "Neo"));
+        assert!(is_lookalike("\u{39D}eo", "Neo"));
+        assert!(is_lookalike("ne0", "neo"));
+        assert!(!is_lookalike("NEO", "neo"));
+        assert!(!is_lookalike("Noe", "Neo"));
+    }
+}
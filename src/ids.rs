@@ -0,0 +1,161 @@
+use crate::user::UserId;
+use rand::Rng;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 2020-01-01T00:00:00Z. Snowflake timestamps count from here, which leaves
+// their 41 bits about 69 years of room.
+const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const SEQUENCE_MASK: u64 = (1 << SEQUENCE_BITS) - 1;
+pub const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
+
+// Hands out ids for new users. Demo users keep their fixed, random ids.
+pub trait IdGenerator: fmt::Debug + Send + Sync {
+    fn next_id(&self) -> UserId;
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[derive(Debug)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn next_id(&self) -> UserId {
+        rand::thread_rng().gen()
+    }
+}
+
+// The first 64 bits of a UUIDv7: a 48-bit Unix millisecond timestamp, the
+// version nibble and 12 random bits. Ids sort by creation time, but only
+// 4096 values tell apart the ones made in the same millisecond, so several
+// instances should use snowflake ids instead.
+#[derive(Debug)]
+pub struct UuidV7Ids;
+
+impl IdGenerator for UuidV7Ids {
+    fn next_id(&self) -> UserId {
+        let millis = now_ms() & ((1 << 48) - 1);
+        let random = u64::from(rand::thread_rng().gen::<u16>()) & 0xfff;
+        (millis << 16) | (0x7 << 12) | random
+    }
+}
+
+// Milliseconds since `SNOWFLAKE_EPOCH_MS`, then the node id, then a sequence
+// number within the millisecond. Unique across instances as long as each has
+// its own node id, and time-ordered across them up to clock skew. When the
+// clock steps back, or a millisecond's sequence runs out, ids carry on from
+// the last timestamp handed out rather than repeat one.
+#[derive(Debug)]
+pub struct SnowflakeIds {
+    node: u64,
+    // Timestamp and sequence of the last id handed out.
+    last: Mutex<(u64, u64)>,
+}
+
+impl SnowflakeIds {
+    pub fn new(node: u16) -> Self {
+        assert!(node <= MAX_NODE_ID, "node id out of range");
+        SnowflakeIds {
+            node: u64::from(node),
+            last: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl IdGenerator for SnowflakeIds {
+    fn next_id(&self) -> UserId {
+        let mut last = self.last.lock().unwrap();
+        let now = now_ms().saturating_sub(SNOWFLAKE_EPOCH_MS);
+        let (millis, sequence) = if now > last.0 {
+            (now, 0)
+        } else if last.1 < SEQUENCE_MASK {
+            (last.0, last.1 + 1)
+        } else {
+            (last.0 + 1, 0)
+        };
+        *last = (millis, sequence);
+        (millis << (NODE_BITS + SEQUENCE_BITS)) | (self.node << SEQUENCE_BITS) | sequence
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    Random,
+    UuidV7,
+    Snowflake { node: u16 },
+}
+
+impl IdStrategy {
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("APP_ID_STRATEGY").as_deref() {
+            Ok("random") | Err(_) => Ok(IdStrategy::Random),
+            Ok("uuidv7") => Ok(IdStrategy::UuidV7),
+            Ok("snowflake") => {
+                let node = env::var("APP_NODE_ID")
+                    .map_err(|_| "APP_ID_STRATEGY=snowflake needs APP_NODE_ID".to_string())?
+                    .parse()
+                    .ok()
+                    .filter(|node| *node <= MAX_NODE_ID)
+                    .ok_or_else(|| {
+                        format!("APP_NODE_ID must be a number from 0 to {}", MAX_NODE_ID)
+                    })?;
+                Ok(IdStrategy::Snowflake { node })
+            }
+            Ok(other) => Err(format!(
+                "APP_ID_STRATEGY must be random, uuidv7 or snowflake, not {}",
+                other
+            )),
+        }
+    }
+
+    pub fn generator(self) -> Arc<dyn IdGenerator> {
+        match self {
+            IdStrategy::Random => Arc::new(RandomIds),
+            IdStrategy::UuidV7 => Arc::new(UuidV7Ids),
+            IdStrategy::Snowflake { node } => Arc::new(SnowflakeIds::new(node)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snowflake_ids_increase_and_carry_the_node() {
+        let ids = SnowflakeIds::new(MAX_NODE_ID);
+        let mut previous = ids.next_id();
+        for _ in 0..10_000 {
+            let id = ids.next_id();
+            assert!(id > previous);
+            assert_eq!((id >> SEQUENCE_BITS) & u64::from(MAX_NODE_ID), 1023);
+            previous = id;
+        }
+    }
+
+    #[test]
+    fn snowflake_ids_survive_the_clock_stepping_back() {
+        let ids = SnowflakeIds::new(3);
+        let ahead = now_ms() - SNOWFLAKE_EPOCH_MS + 60_000;
+        *ids.last.lock().unwrap() = (ahead, 0);
+        let id = ids.next_id();
+        assert_eq!(id >> (NODE_BITS + SEQUENCE_BITS), ahead);
+        assert_eq!(id & SEQUENCE_MASK, 1);
+    }
+
+    #[test]
+    fn uuid_v7_ids_lead_with_the_time_and_version() {
+        let before = now_ms();
+        let id = UuidV7Ids.next_id();
+        assert_eq!((id >> 12) & 0xf, 7);
+        assert!(id >> 16 >= before);
+    }
+}
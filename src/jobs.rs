@@ -0,0 +1,38 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+type BoxedJob = Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+pub struct JobRunner {
+    jobs: Vec<(Duration, BoxedJob)>,
+}
+
+impl JobRunner {
+    pub fn new() -> Self {
+        JobRunner { jobs: Vec::new() }
+    }
+
+    pub fn every<F, Fut>(&mut self, period: Duration, job: F) -> &mut Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let boxed: BoxedJob = Box::new(move || Box::pin(job()));
+        self.jobs.push((period, boxed));
+        self
+    }
+
+    pub fn start(self) {
+        for (period, job) in self.jobs {
+            tokio::spawn(async move {
+                let start = tokio::time::Instant::now() + period;
+                let mut interval = tokio::time::interval_at(start, period);
+                loop {
+                    interval.tick().await;
+                    job().await;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// Without the server, the user and metrics modules are only partly used by
+// the token code that needs them.
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+
+#[cfg(feature = "server")]
+pub mod access_log;
+#[cfg(feature = "server")]
+pub mod api_keys;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
+mod avatars;
+#[cfg(feature = "server")]
+mod bulk;
+#[cfg(feature = "server")]
+pub mod config;
+pub mod core;
+#[cfg(feature = "server")]
+pub mod domains;
+mod env_vars;
+#[cfg(feature = "server")]
+pub mod features;
+#[cfg(feature = "server")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hashing;
+#[cfg(feature = "server")]
+mod html;
+mod identity;
+pub mod ids;
+#[cfg(feature = "server")]
+mod jobs;
+#[cfg(feature = "server")]
+pub mod limits;
+#[cfg(feature = "server")]
+mod links;
+#[cfg(feature = "server")]
+mod maintenance;
+mod metrics;
+#[cfg(feature = "server")]
+mod names;
+#[cfg(feature = "server")]
+mod notify;
+#[cfg(feature = "server")]
+mod panics;
+#[cfg(feature = "server")]
+mod paths;
+mod pii;
+#[cfg(feature = "server")]
+mod preflight;
+#[cfg(feature = "server")]
+mod reporting;
+#[cfg(feature = "server")]
+mod resilience;
+#[cfg(feature = "server")]
+pub mod rotation;
+#[cfg(feature = "server")]
+mod sanitize;
+#[cfg(feature = "server")]
+mod secrets;
+#[cfg(feature = "server")]
+mod self_test;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+mod service;
+mod shadow;
+#[cfg(feature = "server")]
+mod stats;
+mod terms;
+#[cfg(feature = "server")]
+mod throttle;
+#[cfg(feature = "server")]
+mod timing;
+#[cfg(feature = "server")]
+mod tokens;
+mod user;
+pub mod verify;
+#[cfg(feature = "server")]
+mod waitlist;
+pub mod webhooks;
+#[cfg(feature = "server")]
+mod well_known;
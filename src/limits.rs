@@ -0,0 +1,189 @@
+use crate::env_vars::{env_list, env_secs};
+use crate::html::{self, HtmlStringReply};
+use crate::timing;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::Filter;
+
+const DEFAULT_MAX_CONCURRENT_POSTS: usize = 64;
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_AFTER_SECS: &str = "1";
+
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl warp::reject::Reject for Overloaded {}
+
+// Bounds how much work piles up behind bcrypt and the user database mutex.
+// POSTs past `max_concurrent` are turned away with a 503 rather than queued,
+// and any request still running after its route's timeout is abandoned.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    in_flight: Arc<AtomicUsize>,
+    max_concurrent: usize,
+    default_timeout: Duration,
+    route_timeouts: Arc<HashMap<String, Duration>>,
+}
+
+// Holds one of the `max_concurrent` slots until dropped.
+#[derive(Debug)]
+pub struct Permit {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn parse_route_timeout(entry: &str) -> Result<(String, Duration), String> {
+    entry
+        .split_once('=')
+        .and_then(|(route, secs)| {
+            let secs = secs.trim().parse().ok()?;
+            Some((route.trim().to_string(), Duration::from_secs(secs)))
+        })
+        .ok_or_else(|| {
+            format!(
+                "APP_ROUTE_TIMEOUTS entries look like /route=seconds, not {}",
+                entry
+            )
+        })
+}
+
+impl Limits {
+    pub fn new(max_concurrent: usize, default_timeout: Duration) -> Self {
+        Limits {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_concurrent,
+            default_timeout,
+            route_timeouts: Arc::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let max_concurrent = match env::var("APP_MAX_CONCURRENT_POSTS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("APP_MAX_CONCURRENT_POSTS must be a number, not {}", value))?,
+            Err(_) => DEFAULT_MAX_CONCURRENT_POSTS,
+        };
+        let default_timeout =
+            env_secs("APP_REQUEST_TIMEOUT_SECS")?.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        let route_timeouts = env_list("APP_ROUTE_TIMEOUTS")
+            .unwrap_or_default()
+            .iter()
+            .map(|entry| parse_route_timeout(entry))
+            .collect::<Result<_, _>>()?;
+        Ok(Limits {
+            route_timeouts: Arc::new(route_timeouts),
+            ..Limits::new(max_concurrent, default_timeout)
+        })
+    }
+
+    pub fn permit(
+        &self,
+    ) -> impl Filter<Extract = (Permit,), Error = warp::reject::Rejection> + Clone {
+        let limits = self.clone();
+        warp::any().and_then(move || {
+            let limits = limits.clone();
+            async move {
+                limits
+                    .try_acquire()
+                    .ok_or_else(|| warp::reject::custom(Overloaded))
+            }
+        })
+    }
+
+    fn try_acquire(&self) -> Option<Permit> {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                if in_flight < self.max_concurrent {
+                    Some(in_flight + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(Permit {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    pub fn timeout_for(&self, path: &str) -> Duration {
+        self.route_timeouts
+            .get(timing::route_of(path))
+            .copied()
+            .unwrap_or(self.default_timeout)
+    }
+
+    pub fn with_timeouts<S>(&self, inner: S) -> TimeoutService<S> {
+        TimeoutService {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+pub fn unavailable_page(message: &str) -> Response<Body> {
+    let body = html::ErrorTemplate::from_message(message)
+        .as_html()
+        .unwrap_or_else(|_| "Service Unavailable".to_string());
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    let headers = response.headers_mut();
+    headers.insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    headers.insert(
+        warp::http::header::RETRY_AFTER,
+        warp::http::HeaderValue::from_static(RETRY_AFTER_SECS),
+    );
+    response
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeoutService<S> {
+    inner: S,
+    limits: Limits,
+}
+
+impl<S> Service<Request<Body>> for TimeoutService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response<Body>, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let timeout = self.limits.timeout_for(request.uri().path());
+        let handling = self.inner.call(request);
+        async move {
+            match tokio::time::timeout(timeout, handling).await {
+                Ok(response) => response,
+                Err(_) => Ok(unavailable_page(
+                    "Sorry, that took too long. Please try again in a moment.",
+                )),
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,205 @@
+use crate::env_vars::env_secs;
+use crate::html::{self, UrlError};
+use crate::verify::MALFORMED_BASE64;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use warp::Filter;
+
+const DEFAULT_OPAQUE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    Stateless,
+    Opaque,
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    Missing(String),
+    MalformedToken,
+    BadStructure(String),
+    Unknown,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Missing(field) => write!(
+                f,
+                "This link is missing its {} parameter. Try copying the whole link again.",
+                field
+            ),
+            LinkError::MalformedToken => write!(
+                f,
+                "This link's token is damaged. Try copying the whole link again."
+            ),
+            LinkError::BadStructure(message) => {
+                write!(f, "This link couldn't be read: {}.", message)
+            }
+            LinkError::Unknown => write!(f, "This link has expired or was already used."),
+        }
+    }
+}
+
+impl warp::reject::Reject for LinkError {}
+
+impl From<serde_urlencoded::de::Error> for LinkError {
+    fn from(err: serde_urlencoded::de::Error) -> Self {
+        let message = err.to_string();
+        if let Some(field) = message.strip_prefix("missing field ") {
+            LinkError::Missing(field.trim_matches('`').to_string())
+        } else if message.starts_with(MALFORMED_BASE64) {
+            LinkError::MalformedToken
+        } else {
+            LinkError::BadStructure(message)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpaqueRef {
+    #[serde(rename = "ref")]
+    id: String,
+}
+
+#[derive(Debug)]
+struct StoredLink {
+    query: String,
+    expires: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct Links {
+    mode: LinkMode,
+    ttl: Duration,
+    stored: Arc<Mutex<HashMap<String, StoredLink>>>,
+}
+
+fn query_of(link: &str) -> &str {
+    link.splitn(2, '?').last().unwrap_or_default()
+}
+
+impl Links {
+    pub fn new(mode: LinkMode, ttl: Duration) -> Self {
+        Links {
+            mode,
+            ttl,
+            stored: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let mode = match env::var("APP_LINK_MODE").as_deref() {
+            Ok("opaque") => LinkMode::Opaque,
+            Ok("stateless") | Err(_) => LinkMode::Stateless,
+            Ok(other) => {
+                return Err(format!(
+                    "APP_LINK_MODE must be stateless or opaque, not {}",
+                    other
+                ))
+            }
+        };
+        let ttl = env_secs("APP_OPAQUE_LINK_TTL_SECS")?.unwrap_or(DEFAULT_OPAQUE_TTL);
+        Ok(Links::new(mode, ttl))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.mode == LinkMode::Opaque
+    }
+
+    pub async fn url(&self, pathname: &str, params: &impl Serialize) -> Result<String, UrlError> {
+        match self.mode {
+            LinkMode::Stateless => html::create_url(pathname, Some(params)),
+            LinkMode::Opaque => {
+                let query = html::encode_query(params)?;
+                let id = base64::encode_config(
+                    rand::thread_rng().gen::<[u8; 16]>(),
+                    base64::URL_SAFE_NO_PAD,
+                );
+                let stored = StoredLink {
+                    query,
+                    expires: Instant::now() + self.ttl,
+                };
+                self.stored.lock().await.insert(id.clone(), stored);
+                html::create_url(pathname, Some(&OpaqueRef { id }))
+            }
+        }
+    }
+
+    pub async fn resolve<T: DeserializeOwned>(&self, link: &str) -> Result<T, LinkError> {
+        let query = query_of(link);
+        match self.mode {
+            LinkMode::Stateless => Ok(serde_urlencoded::from_str(query)?),
+            LinkMode::Opaque => {
+                let opaque: OpaqueRef = serde_urlencoded::from_str(query)?;
+                let stored = self.stored.lock().await;
+                let link = stored
+                    .get(&opaque.id)
+                    .filter(|link| link.expires > Instant::now())
+                    .ok_or(LinkError::Unknown)?;
+                Ok(serde_urlencoded::from_str(&link.query)?)
+            }
+        }
+    }
+
+    pub fn params<T: DeserializeOwned + Send>(
+        &self,
+    ) -> impl Filter<Extract = (T,), Error = warp::reject::Rejection> + Clone {
+        let links = self.clone();
+        warp::query::raw()
+            .or(warp::any().map(String::new))
+            .unify()
+            .and_then(move |query: String| {
+                let links = links.clone();
+                async move {
+                    links
+                        .resolve::<T>(&query)
+                        .await
+                        .map_err(warp::reject::custom)
+                }
+            })
+    }
+
+    pub async fn revoke(&self, link: &str) -> bool {
+        match serde_urlencoded::from_str::<OpaqueRef>(query_of(link)) {
+            Ok(opaque) => self.stored.lock().await.remove(&opaque.id).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn spend(&self, params: &impl Serialize) {
+        if self.mode == LinkMode::Opaque {
+            // Anything that fails to encode here could never have been linked.
+            let query = match html::encode_query(params) {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+            self.stored
+                .lock()
+                .await
+                .retain(|_, link| link.query != query);
+        }
+    }
+
+    pub async fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut stored = self.stored.lock().await;
+        let before = stored.len();
+        stored.retain(|_, link| link.expires > now);
+        before - stored.len()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::env_vars::env_bool;
+use crate::timing;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use warp::Filter;
+
+// Routes that keep working during maintenance: health checks for the load
+// balancer and the switch to turn it off again.
+const EXEMPT_ROUTES: &[&str] = &["/readyz", "/metrics", "/admin/maintenance"];
+
+#[derive(Debug)]
+pub struct UnderMaintenance;
+
+impl warp::reject::Reject for UnderMaintenance {}
+
+// While on, every other route answers with a 503 maintenance page. Flipped
+// at runtime from `/admin/maintenance`; `APP_MAINTENANCE` starts the server
+// with it on.
+#[derive(Debug, Clone)]
+pub struct Maintenance {
+    on: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn new(on: bool) -> Self {
+        Maintenance {
+            on: Arc::new(AtomicBool::new(on)),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Maintenance::new(env_bool("APP_MAINTENANCE").unwrap_or(false))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, on: bool) {
+        self.on.store(on, Ordering::SeqCst);
+    }
+
+    pub fn guard(&self) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+        let maintenance = self.clone();
+        warp::path::full()
+            .and_then(move |path: warp::path::FullPath| {
+                let on = maintenance.is_on();
+                async move {
+                    if on && !EXEMPT_ROUTES.contains(&timing::route_of(path.as_str())) {
+                        Err(warp::reject::custom(UnderMaintenance))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .untuple_one()
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "server")]
+use warp::Filter;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Metric name, label name, label value.
+type HistogramKey = (&'static str, &'static str, &'static str);
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    counters: Arc<Mutex<BTreeMap<&'static str, u64>>>,
+    gauges: Arc<Mutex<BTreeMap<&'static str, i64>>>,
+    histograms: Arc<Mutex<BTreeMap<HistogramKey, Histogram>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            counters: Arc::new(Mutex::new(BTreeMap::new())),
+            gauges: Arc::new(Mutex::new(BTreeMap::new())),
+            histograms: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn incr_by(&self, name: &'static str, amount: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(name).or_insert(0) += amount;
+    }
+
+    pub fn add_to_gauge(&self, name: &'static str, delta: i64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        *gauges.entry(name).or_insert(0) += delta;
+    }
+
+    pub fn observe_duration(&self, name: &'static str, route: &'static str, duration: Duration) {
+        self.observe_labeled(name, "route", route, duration);
+    }
+
+    pub fn observe_labeled(
+        &self,
+        name: &'static str,
+        label: &'static str,
+        value: &'static str,
+        duration: Duration,
+    ) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry((name, label, value))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut rendered: String = counters
+            .iter()
+            .map(|(name, value)| format!("# TYPE {} counter\n{} {}\n", name, name, value))
+            .collect();
+        let gauges = self.gauges.lock().unwrap();
+        for (name, value) in gauges.iter() {
+            rendered.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, value));
+        }
+        let histograms = self.histograms.lock().unwrap();
+        let mut last_name = None;
+        for ((name, label, value), histogram) in histograms.iter() {
+            if last_name != Some(name) {
+                rendered.push_str(&format!("# TYPE {} histogram\n", name));
+                last_name = Some(name);
+            }
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                rendered.push_str(&format!(
+                    "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}\n",
+                    name, label, value, bound, bucket
+                ));
+            }
+            let labels = format!("{}=\"{}\"", label, value);
+            rendered.push_str(&format!(
+                "{}_bucket{{{},le=\"+Inf\"}} {}\n{}_sum{{{}}} {}\n{}_count{{{}}} {}\n",
+                name,
+                labels,
+                histogram.count,
+                name,
+                labels,
+                histogram.sum,
+                name,
+                labels,
+                histogram.count
+            ));
+        }
+        rendered
+    }
+}
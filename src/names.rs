@@ -0,0 +1,100 @@
+use crate::env_vars::env_list;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use warp::Filter;
+
+const RESERVED_NAMES: &[&str] = &[
+    "abuse",
+    "admin",
+    "administrator",
+    "api",
+    "help",
+    "hostmaster",
+    "moderator",
+    "no-reply",
+    "official",
+    "postmaster",
+    "root",
+    "security",
+    "staff",
+    "support",
+    "system",
+    "webmaster",
+];
+
+// Entries are compared as whole words with case and punctuation dropped, so
+// `Ad.Min` and `the_admin` are caught but `Badminton` is not. Longer lists,
+// such as slurs, belong in the file named by `APP_BLOCKED_NAMES_FILE`.
+#[derive(Debug, Clone)]
+pub struct NameBlocklist {
+    blocked: Arc<HashSet<String>>,
+}
+
+fn normalize(word: &str) -> String {
+    word.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == '.' || c == '_' || c == '-'
+}
+
+impl NameBlocklist {
+    pub fn new<I: IntoIterator<Item = String>>(extra: I) -> Self {
+        let blocked = RESERVED_NAMES
+            .iter()
+            .map(|name| normalize(name))
+            .chain(extra.into_iter().map(|name| normalize(&name)))
+            .filter(|name| !name.is_empty())
+            .collect();
+        NameBlocklist {
+            blocked: Arc::new(blocked),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let mut extra = env_list("APP_BLOCKED_NAMES").unwrap_or_default();
+        if let Ok(path) = env::var("APP_BLOCKED_NAMES_FILE") {
+            let contents = fs::read_to_string(&path).map_err(|err| {
+                format!("could not read APP_BLOCKED_NAMES_FILE {}: {}", path, err)
+            })?;
+            extra.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+        Ok(NameBlocklist::new(extra))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn blocks(&self, value: &str) -> bool {
+        self.blocked.contains(&normalize(value))
+            || value
+                .split(is_separator)
+                .any(|word| self.blocked.contains(&normalize(word)))
+    }
+
+    pub fn errors(&self, name: &str, username: &str) -> Vec<&'static str> {
+        let mut errors = Vec::new();
+        if self.blocks(name) {
+            errors.push("That name is reserved or not allowed.");
+        }
+        if self.blocks(username) {
+            errors.push("That username is reserved or not allowed.");
+        }
+        errors
+    }
+}
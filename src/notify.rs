@@ -0,0 +1,19 @@
+use crate::pii::Email;
+
+// Stands in for a mailer until there is one: each message goes to stdout as
+// one tab-separated line, `notify<TAB>address<TAB>subject<TAB>link`, for
+// whatever runs alongside to forward. Messages carrying a reset link add a
+// fifth column with the link for reporting it.
+pub fn send(to: &Email, subject: &str, link: &str) {
+    println!("notify\t{}\t{}\t{}", to.as_str(), subject, link);
+}
+
+pub fn send_with_report(to: &Email, subject: &str, link: &str, report_link: &str) {
+    println!(
+        "notify\t{}\t{}\t{}\t{}",
+        to.as_str(),
+        subject,
+        link,
+        report_link
+    );
+}
@@ -0,0 +1,103 @@
+use crate::access_log::ClientAddr;
+use crate::html::{self, HtmlStringReply};
+use crate::reporting::{self, ErrorEvent};
+use futures::FutureExt;
+use hyper::server::conn::AddrStream;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use warp::http::StatusCode;
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn is_catching() -> bool {
+    CATCHING.with(Cell::get)
+}
+
+pub fn stash(location: String) {
+    LAST_PANIC.with(|last| *last.borrow_mut() = Some(location));
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panic".to_string())
+}
+
+fn error_page() -> Response<Body> {
+    let body = html::ErrorTemplate::from_message("Sorry, that request failed on our end.")
+        .as_html()
+        .unwrap_or_else(|_| "Internal Server Error".to_string());
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
+
+async fn guarded<F>(method: String, path: String, handling: F) -> Result<Response<Body>, Infallible>
+where
+    F: Future<Output = Result<Response<Body>, Infallible>>,
+{
+    let mut handling = Box::pin(handling);
+    let caught = futures::future::poll_fn(|cx| {
+        CATCHING.with(|catching| catching.set(true));
+        let polled = std::panic::catch_unwind(AssertUnwindSafe(|| handling.as_mut().poll(cx)));
+        CATCHING.with(|catching| catching.set(false));
+        match polled {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => std::task::Poll::Ready(Err(panic_message(payload.as_ref()))),
+        }
+    })
+    .await;
+    match caught {
+        Ok(response) => response,
+        Err(message) => {
+            let mut event = ErrorEvent::new("panic", message)
+                .with("method", method)
+                .with("path", path);
+            if let Some(location) = LAST_PANIC.with(|last| last.borrow_mut().take()) {
+                event = event.with("location", location);
+            }
+            reporting::report(event);
+            Ok(error_page())
+        }
+    }
+}
+
+pub async fn serve<S>(addr: SocketAddr, service: S) -> Result<(), hyper::Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let make_service = hyper::service::make_service_fn(move |conn: &AddrStream| {
+        let service = service.clone();
+        let client = ClientAddr(conn.remote_addr());
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(
+                move |mut request: Request<Body>| {
+                    request.extensions_mut().insert(client);
+                    let method = request.method().to_string();
+                    let path = request.uri().path().to_string();
+                    let mut service = service.clone();
+                    guarded(method, path, service.call(request)).boxed()
+                },
+            ))
+        }
+    });
+    hyper::Server::bind(&addr).serve(make_service).await
+}
@@ -0,0 +1,88 @@
+use crate::timing;
+use warp::http::{header, HeaderValue, Response, StatusCode};
+use warp::hyper::Body;
+use warp::Filter;
+
+// The one spelling of `path` that routes answer to: no empty segments, no
+// trailing slash, and the route's fixed segments in lower case. Segments
+// that carry a value, like ids and usernames, keep their case. `None` when
+// the path is already canonical or no route would take it either way.
+pub fn canonical(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    let lowered = format!("/{}", segments.join("/").to_lowercase());
+    let route = timing::route_of(&lowered);
+    if route == "other" {
+        return None;
+    }
+    let pattern: Vec<&str> = route.split('/').filter(|part| !part.is_empty()).collect();
+    if pattern.len() != segments.len() {
+        return None;
+    }
+    let canonical = segments
+        .iter()
+        .zip(&pattern)
+        .map(|(segment, fixed)| if fixed.contains(':') { segment } else { fixed })
+        .fold(String::new(), |path, segment| path + "/" + segment);
+    let canonical = if canonical.is_empty() {
+        "/".to_string()
+    } else {
+        canonical
+    };
+    if canonical == path {
+        None
+    } else {
+        Some(canonical)
+    }
+}
+
+// Sends `/LIST`, `/list/` and `//list` to `/list` with a 308, which keeps
+// the method and body, so there is one URL per page for links, caches and
+// metrics. Paths that are canonical already, or unknown, fall through.
+pub fn redirect(
+) -> impl Filter<Extract = (Response<Body>,), Error = warp::reject::Rejection> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(|path: warp::path::FullPath, query: String| async move {
+            let mut location = canonical(path.as_str()).ok_or_else(warp::reject::not_found)?;
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(&query);
+            }
+            let location =
+                HeaderValue::from_str(&location).map_err(|_| warp::reject::not_found())?;
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            response.headers_mut().insert(header::LOCATION, location);
+            Ok::<_, warp::reject::Rejection>(response)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_resolve_to_one_path() {
+        assert_eq!(canonical("/LIST").as_deref(), Some("/list"));
+        assert_eq!(canonical("/list/").as_deref(), Some("/list"));
+        assert_eq!(canonical("//list//").as_deref(), Some("/list"));
+        assert_eq!(
+            canonical("/Admin/Merge/Confirm/").as_deref(),
+            Some("/admin/merge/confirm")
+        );
+        assert_eq!(canonical("/USER/@Neo").as_deref(), Some("/user/@Neo"));
+        assert_eq!(
+            canonical("/Users/42/Avatar").as_deref(),
+            Some("/users/42/avatar")
+        );
+    }
+
+    #[test]
+    fn canonical_and_unknown_paths_are_left_alone() {
+        assert_eq!(canonical("/list"), None);
+        assert_eq!(canonical("/"), None);
+        assert_eq!(canonical("/user/@Neo"), None);
+        assert_eq!(canonical("/nowhere/"), None);
+        assert_eq!(canonical("/users/1/avatar/extra"), None);
+    }
+}
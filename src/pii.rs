@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Debug and Display never show the value, so these can go into logs, error
+// reports and derived Debug output. Serialization is deliberate and keeps it.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Email(String);
+
+impl Email {
+    pub fn new(email: impl Into<String>) -> Self {
+        Email(email.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Email(<redacted>)")
+    }
+}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted email>")
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    pub fn new(hash: String) -> Self {
+        PasswordHash(hash)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(<redacted>)")
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted password hash>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_never_shows_the_value() {
+        let email = Email::new("neo@example.com");
+        let hash = PasswordHash::new("$2b$04$abcdefghijklmnopqrstuv".to_string());
+        for shown in &[
+            format!("{} {:?}", email, email),
+            format!("{} {:?}", hash, hash),
+            format!("{:?}", Some((&email, &hash))),
+        ] {
+            assert!(
+                !shown.contains("neo") && !shown.contains("$2b$"),
+                "{}",
+                shown
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&email).unwrap(),
+            "\"neo@example.com\""
+        );
+    }
+}
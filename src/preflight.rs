@@ -0,0 +1,279 @@
+use crate::avatars::Avatars;
+use crate::config::{Config, Profile};
+use crate::secrets::DEV_SECRET_KEY;
+use secrecy::{ExposeSecret, SecretVec};
+use std::env;
+use std::fmt;
+use std::time::Duration;
+use url::Url;
+
+const MIN_SECRET_BYTES: usize = 32;
+const MIN_SECRET_BITS: f64 = 128.0;
+const BCRYPT_COSTS: std::ops::RangeInclusive<u32> = 4..=31;
+const PRODUCTION_MIN_BCRYPT_COST: u32 = 10;
+// Backends the server sends secrets or error details to.
+const BACKEND_URL_VARS: &[&str] = &["APP_VAULT_ADDR", "APP_ERROR_WEBHOOK_URL", "APP_SENTRY_DSN"];
+const BACKEND_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Everything wrong with the configuration, gathered in one pass so it can be
+// fixed in one go rather than one restart per mistake. Errors keep the server
+// from starting. Development defaults and weak settings are errors under the
+// production profile and warnings everywhere else.
+#[derive(Debug, Default)]
+pub struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    production: bool,
+}
+
+impl Report {
+    // For a configuration that could not be read at all.
+    pub fn from_errors(errors: Vec<String>) -> Self {
+        Report {
+            errors,
+            ..Report::default()
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn unsafe_default(&mut self, message: String) {
+        if self.production {
+            self.errors.push(message);
+        } else {
+            self.warnings.push(message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.warnings.is_empty()
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        !self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "error: {}", error)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+// Shannon entropy of the byte frequencies, scaled by length. It can't tell a
+// random key from a long passphrase, but it does catch short words and
+// repeated characters.
+fn estimated_entropy_bits(secret: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in secret {
+        counts[usize::from(*byte)] += 1;
+    }
+    let len = secret.len() as f64;
+    let per_byte: f64 = counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    per_byte * len
+}
+
+fn check_secret(report: &mut Report, secret: &[u8]) {
+    if secret == DEV_SECRET_KEY {
+        report.unsafe_default(
+            "the secret key is the built-in development key; set APP_SECRET_SOURCE".to_string(),
+        );
+    } else if secret.len() < MIN_SECRET_BYTES {
+        report.unsafe_default(format!(
+            "the secret key is {} bytes; use at least {}",
+            secret.len(),
+            MIN_SECRET_BYTES
+        ));
+    } else if estimated_entropy_bits(secret) < MIN_SECRET_BITS {
+        report.unsafe_default(format!(
+            "the secret key looks guessable (about {:.0} bits of entropy); generate a random one",
+            estimated_entropy_bits(secret)
+        ));
+    }
+}
+
+// Returns the URL when it is worth trying to reach.
+fn check_backend_url(report: &mut Report, var: &str, value: &str) -> Option<Url> {
+    match Url::parse(value) {
+        Ok(url) if url.scheme() == "https" => Some(url),
+        Ok(url) if url.scheme() == "http" => {
+            report.unsafe_default(format!(
+                "{} uses plain http; use https so nothing is sent in the clear",
+                var
+            ));
+            Some(url)
+        }
+        Ok(url) => {
+            report.error(format!(
+                "{} must be an http or https URL, not {}",
+                var,
+                url.scheme()
+            ));
+            None
+        }
+        Err(err) => {
+            report.error(format!("{} is not a valid URL: {}", var, err));
+            None
+        }
+    }
+}
+
+// Sends one HEAD request to the backend's origin, so a wrong host or a
+// closed port shows up at startup rather than on the first secret refresh or
+// error report. Any HTTP answer at all counts as reachable.
+async fn probe_backend(url: &Url) -> Result<(), String> {
+    let client =
+        hyper::Client::builder().build::<_, hyper::Body>(hyper_rustls::HttpsConnector::new());
+    let request = hyper::Request::head(format!("{}/", url.origin().ascii_serialization()))
+        .body(hyper::Body::empty())
+        .map_err(|err| err.to_string())?;
+    match tokio::time::timeout(BACKEND_PROBE_TIMEOUT, client.request(request)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!(
+            "no answer within {}s",
+            BACKEND_PROBE_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+pub async fn check(
+    config: &Config,
+    secret: &SecretVec<u8>,
+    secret_ttl: Duration,
+    avatars: &Avatars,
+) -> Report {
+    let mut report = Report {
+        production: config.profile == Some(Profile::Production),
+        ..Report::default()
+    };
+    check_secret(&mut report, secret.expose_secret());
+    if secret_ttl == Duration::from_secs(0) {
+        report.error("APP_SECRET_TTL_SECS must be more than zero".to_string());
+    }
+
+    if !BCRYPT_COSTS.contains(&config.bcrypt_cost) {
+        report.error(format!(
+            "APP_BCRYPT_COST must be from {} to {}, not {}",
+            BCRYPT_COSTS.start(),
+            BCRYPT_COSTS.end(),
+            config.bcrypt_cost
+        ));
+    } else if config.bcrypt_cost < PRODUCTION_MIN_BCRYPT_COST {
+        report.unsafe_default(format!(
+            "APP_BCRYPT_COST is {}; use at least {} outside development",
+            config.bcrypt_cost, PRODUCTION_MIN_BCRYPT_COST
+        ));
+    }
+    for problem in config.token_policy.problems() {
+        report.error(problem);
+    }
+
+    for var in BACKEND_URL_VARS {
+        let url = env::var(var)
+            .ok()
+            .and_then(|value| check_backend_url(&mut report, var, &value));
+        if let Some(url) = url {
+            if let Err(err) = probe_backend(&url).await {
+                report.error(format!("{} could not be reached: {}", var, err));
+            }
+        }
+    }
+    if let Some(url) = config.terms.url() {
+        if !url.starts_with('/') && Url::parse(url).is_err() {
+            report.error(format!(
+                "APP_TOS_URL must be a path or an absolute URL, not {}",
+                url
+            ));
+        }
+    }
+
+    if let Err(err) = avatars.probe().await {
+        report.error(format!("the avatar store is not writable: {}", err));
+    }
+
+    if config.demo {
+        report.unsafe_default("demo mode is on; it seeds and reseeds sample users".to_string());
+    }
+    if config.debug_errors {
+        report
+            .unsafe_default("APP_DEBUG_ERRORS is on; error pages show request details".to_string());
+    }
+    if !config.require_tls {
+        report
+            .unsafe_default("APP_REQUIRE_TLS is off; cookies are sent without Secure".to_string());
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_secrets_are_flagged() {
+        let mut report = Report::default();
+        check_secret(&mut report, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        check_secret(&mut report, b"short");
+        check_secret(&mut report, DEV_SECRET_KEY);
+        assert_eq!(report.warnings.len(), 3);
+        assert!(!report.is_fatal());
+
+        let mut report = Report::default();
+        check_secret(
+            &mut report,
+            b"7f3a9c1e5b2d8f4a6c0e9b3d7f1a5c8e2b6d0f4a8c3e7b1d",
+        );
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn production_refuses_unsafe_defaults() {
+        let mut report = Report {
+            production: true,
+            ..Report::default()
+        };
+        check_secret(&mut report, DEV_SECRET_KEY);
+        check_backend_url(&mut report, "APP_VAULT_ADDR", "http://vault:8200");
+        check_backend_url(
+            &mut report,
+            "APP_ERROR_WEBHOOK_URL",
+            "https://hooks.example",
+        );
+        assert_eq!(report.errors.len(), 2);
+        assert!(report
+            .to_string()
+            .contains("error: APP_VAULT_ADDR uses plain http"));
+    }
+
+    #[test]
+    fn every_unreadable_variable_is_listed() {
+        let report = Report::from_errors(vec![
+            "APP_ENV must be one of development, staging or production, not prd".to_string(),
+            "APP_LINK_MODE must be stateless or opaque, not opaq".to_string(),
+        ]);
+        assert!(report.is_fatal());
+        assert_eq!(report.to_string().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn closed_ports_are_unreachable() {
+        let url = Url::parse("http://127.0.0.1:9/hook").unwrap();
+        assert!(probe_backend(&url).await.is_err());
+    }
+}
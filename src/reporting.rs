@@ -0,0 +1,272 @@
+use crate::panics;
+use crate::webhooks;
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::RwLock;
+
+static REPORTER: RwLock<Option<Box<dyn ErrorReporter>>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEvent {
+    pub kind: &'static str,
+    pub message: String,
+    pub context: BTreeMap<&'static str, String>,
+}
+
+impl ErrorEvent {
+    pub fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        ErrorEvent {
+            kind,
+            message: message.into(),
+            context: BTreeMap::new(),
+        }
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.context.insert(key, value.to_string());
+        self
+    }
+}
+
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, event: &ErrorEvent);
+}
+
+pub struct LogReporter;
+
+impl ErrorReporter for LogReporter {
+    fn report(&self, event: &ErrorEvent) {
+        let context: String = event
+            .context
+            .iter()
+            .map(|(key, value)| format!(" {}={:?}", key, value))
+            .collect();
+        eprintln!(
+            "level=error kind={} message={:?}{}",
+            event.kind, event.message, context
+        );
+    }
+}
+
+type HttpsClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+fn https_client() -> HttpsClient {
+    hyper::Client::builder().build(hyper_rustls::HttpsConnector::new())
+}
+
+fn post_json(client: &HttpsClient, request: hyper::http::request::Builder, body: String) {
+    let request = match request
+        .method(hyper::Method::POST)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body))
+    {
+        Ok(request) => request,
+        Err(err) => return eprintln!("could not build error report: {}", err),
+    };
+    let sending = client.request(request);
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn(async move {
+                match sending.await {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => eprintln!("error report rejected: {}", response.status()),
+                    Err(err) => eprintln!("could not send error report: {}", err),
+                }
+            });
+        }
+        Err(_) => eprintln!("no runtime to send error report on"),
+    }
+}
+
+pub struct WebhookReporter {
+    client: HttpsClient,
+    url: String,
+    secret: Option<SecretVec<u8>>,
+}
+
+impl WebhookReporter {
+    pub fn new(url: &str, secret: Option<SecretVec<u8>>) -> Self {
+        WebhookReporter {
+            client: https_client(),
+            url: url.to_string(),
+            secret,
+        }
+    }
+}
+
+impl ErrorReporter for WebhookReporter {
+    fn report(&self, event: &ErrorEvent) {
+        let body = serde_json::to_string(event).unwrap();
+        let mut request = hyper::Request::builder().uri(&self.url);
+        if let Some(secret) = &self.secret {
+            let signature = webhooks::sign(
+                secret.expose_secret(),
+                chrono::Utc::now().timestamp(),
+                body.as_bytes(),
+            );
+            request = request.header(webhooks::SIGNATURE_HEADER, signature);
+        }
+        post_json(&self.client, request, body);
+    }
+}
+
+pub struct SentryReporter {
+    client: HttpsClient,
+    store_url: String,
+    auth: String,
+}
+
+impl SentryReporter {
+    pub fn from_dsn(dsn: &str) -> Option<Self> {
+        let (store_url, auth) = sentry_endpoint(dsn)?;
+        Some(SentryReporter {
+            client: https_client(),
+            store_url,
+            auth,
+        })
+    }
+}
+
+// The store URL and auth header a DSN points at.
+fn sentry_endpoint(dsn: &str) -> Option<(String, String)> {
+    let (scheme, rest) = dsn.split_once("://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let key = key.split(':').next()?;
+    let (host, project) = rest.rsplit_once('/')?;
+    Some((
+        format!("{}://{}/api/{}/store/", scheme, host, project),
+        format!(
+            "Sentry sentry_version=7, sentry_client=no-db-verify/{}, sentry_key={}",
+            env!("CARGO_PKG_VERSION"),
+            key
+        ),
+    ))
+}
+
+impl ErrorReporter for SentryReporter {
+    fn report(&self, event: &ErrorEvent) {
+        let event_id: String = rand::thread_rng()
+            .gen::<[u8; 16]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let body = serde_json::json!({
+            "event_id": event_id,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": "error",
+            "platform": "other",
+            "logger": "no-db-verify",
+            "message": { "formatted": event.message },
+            "tags": { "kind": event.kind },
+            "extra": event.context,
+        });
+        let request = hyper::Request::builder()
+            .uri(&self.store_url)
+            .header("X-Sentry-Auth", &self.auth);
+        post_json(&self.client, request, body.to_string());
+    }
+}
+
+// Which reporter to install, read with the rest of the configuration so a
+// bad choice is reported at startup instead of panicking.
+#[derive(Debug, Clone)]
+pub enum ReporterChoice {
+    None,
+    Log,
+    Webhook {
+        url: String,
+        secret: Option<SecretString>,
+    },
+    Sentry {
+        dsn: String,
+    },
+}
+
+impl ReporterChoice {
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("APP_ERROR_REPORTER").as_deref() {
+            Ok("none") => Ok(ReporterChoice::None),
+            Ok("log") | Err(_) => Ok(ReporterChoice::Log),
+            Ok("webhook") => {
+                let url = env::var("APP_ERROR_WEBHOOK_URL").map_err(|_| {
+                    "APP_ERROR_WEBHOOK_URL is required for the webhook reporter".to_string()
+                })?;
+                let secret = env::var("APP_ERROR_WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|secret| !secret.is_empty())
+                    .map(SecretString::new);
+                Ok(ReporterChoice::Webhook { url, secret })
+            }
+            Ok("sentry") => {
+                let dsn = env::var("APP_SENTRY_DSN").map_err(|_| {
+                    "APP_SENTRY_DSN is required for the sentry reporter".to_string()
+                })?;
+                if sentry_endpoint(&dsn).is_none() {
+                    return Err("APP_SENTRY_DSN is not a valid DSN".to_string());
+                }
+                Ok(ReporterChoice::Sentry { dsn })
+            }
+            Ok(other) => Err(format!(
+                "APP_ERROR_REPORTER must be none, log, webhook or sentry, not {}",
+                other
+            )),
+        }
+    }
+
+    pub fn build(&self) -> Option<Box<dyn ErrorReporter>> {
+        match self {
+            ReporterChoice::None => None,
+            ReporterChoice::Log => Some(Box::new(LogReporter)),
+            ReporterChoice::Webhook { url, secret } => {
+                let secret = secret
+                    .as_ref()
+                    .map(|secret| SecretVec::new(secret.expose_secret().as_bytes().to_vec()));
+                Some(Box::new(WebhookReporter::new(url, secret)))
+            }
+            ReporterChoice::Sentry { dsn } => {
+                SentryReporter::from_dsn(dsn).map(|reporter| Box::new(reporter) as Box<_>)
+            }
+        }
+    }
+}
+
+pub fn install(reporter: Option<Box<dyn ErrorReporter>>) {
+    *REPORTER.write().unwrap() = reporter;
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|location| location.to_string());
+        if panics::is_catching() {
+            // The request guard reports this one with the request attached.
+            if let Some(location) = location {
+                panics::stash(location);
+            }
+        } else {
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "panic".to_string());
+            let mut event = ErrorEvent::new("panic", message);
+            if let Some(location) = location {
+                event = event.with("location", location);
+            }
+            if let Some(name) = std::thread::current().name() {
+                event = event.with("thread", name);
+            }
+            report(event);
+        }
+        default_hook(info);
+    }));
+}
+
+pub fn report(event: ErrorEvent) {
+    if let Ok(reporter) = REPORTER.read() {
+        if let Some(reporter) = reporter.as_ref() {
+            reporter.report(&event);
+        }
+    }
+}
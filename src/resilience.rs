@@ -0,0 +1,306 @@
+use crate::env_vars::env_secs;
+use serde::Serialize;
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+// Every breaker ever built, so `/readyz` can report on them.
+static BREAKERS: RwLock<Vec<CircuitBreaker>> = RwLock::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    pub name: &'static str,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+// Opens after `failure_threshold` failures in a row and turns calls away
+// until `cooldown` has passed. After that calls are let through again
+// (half-open); one success closes it and one failure re-opens it.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    cooldown: Duration,
+    breaker: Arc<Mutex<Breaker>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            name,
+            failure_threshold,
+            cooldown,
+            breaker: Arc::new(Mutex::new(Breaker {
+                consecutive_failures: 0,
+                opened_at: None,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.breaker.lock().unwrap().opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    pub fn status(&self) -> BackendStatus {
+        BackendStatus {
+            name: self.name,
+            state: self.state(),
+            consecutive_failures: self.breaker.lock().unwrap().consecutive_failures,
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures = 0;
+        breaker.opened_at = None;
+    }
+
+    fn failed(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= self.failure_threshold {
+            breaker.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << attempt.min(16))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+    }
+}
+
+// The retry and breaker settings every backend is built with.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendPolicy {
+    pub retry: RetryPolicy,
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for BackendPolicy {
+    fn default() -> Self {
+        BackendPolicy {
+            retry: RetryPolicy {
+                attempts: DEFAULT_ATTEMPTS,
+                base_delay: DEFAULT_BASE_DELAY,
+            },
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+fn env_number(name: &str) -> Result<Option<u32>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} must be a number, not {}", name, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+impl BackendPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = BackendPolicy::default();
+        let base_delay = match env::var("APP_BACKEND_BACKOFF_MS") {
+            Ok(millis) => millis.parse().map(Duration::from_millis).map_err(|_| {
+                format!(
+                    "APP_BACKEND_BACKOFF_MS must be a number of milliseconds, not {}",
+                    millis
+                )
+            })?,
+            Err(_) => defaults.retry.base_delay,
+        };
+        Ok(BackendPolicy {
+            retry: RetryPolicy {
+                attempts: env_number("APP_BACKEND_ATTEMPTS")?
+                    .unwrap_or(defaults.retry.attempts)
+                    .max(1),
+                base_delay,
+            },
+            failure_threshold: env_number("APP_BREAKER_THRESHOLD")?
+                .unwrap_or(defaults.failure_threshold)
+                .max(1),
+            cooldown: env_secs("APP_BREAKER_COOLDOWN_SECS")?.unwrap_or(defaults.cooldown),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Failure<E> {
+    CircuitOpen,
+    Failed(E),
+}
+
+// Bounded retries with exponential backoff, behind a circuit breaker, for
+// calls to a backend on the other side of a network.
+#[derive(Debug, Clone)]
+pub struct Resilience {
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl Resilience {
+    pub fn new(retry: RetryPolicy, breaker: CircuitBreaker) -> Self {
+        BREAKERS.write().unwrap().push(breaker.clone());
+        Resilience { retry, breaker }
+    }
+
+    pub fn from_policy(name: &'static str, policy: BackendPolicy) -> Self {
+        Resilience::new(
+            policy.retry,
+            CircuitBreaker::new(name, policy.failure_threshold, policy.cooldown),
+        )
+    }
+
+    pub async fn call<T, E, F, Fut>(
+        &self,
+        retryable: impl Fn(&E) -> bool,
+        mut operation: F,
+    ) -> Result<T, Failure<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            if self.breaker.state() == CircuitState::Open {
+                return Err(Failure::CircuitOpen);
+            }
+            match operation().await {
+                Ok(value) => {
+                    self.breaker.succeeded();
+                    return Ok(value);
+                }
+                Err(err) => {
+                    self.breaker.failed();
+                    attempt += 1;
+                    if attempt >= self.retry.attempts || !retryable(&err) {
+                        return Err(Failure::Failed(err));
+                    }
+                    tokio::time::delay_for(self.retry.delay(attempt - 1)).await;
+                }
+            }
+        }
+    }
+}
+
+pub fn statuses() -> Vec<BackendStatus> {
+    BREAKERS
+        .read()
+        .unwrap()
+        .iter()
+        .map(CircuitBreaker::status)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn resilience(attempts: u32, threshold: u32, cooldown: Duration) -> Resilience {
+        Resilience::new(
+            RetryPolicy {
+                attempts,
+                base_delay: Duration::from_millis(0),
+            },
+            CircuitBreaker::new("test", threshold, cooldown),
+        )
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_until_one_succeeds() {
+        let resilience = resilience(3, 10, DEFAULT_COOLDOWN);
+        let calls = Cell::new(0);
+        let result = resilience
+            .call(
+                |_: &&str| true,
+                || {
+                    calls.set(calls.get() + 1);
+                    let outcome = if calls.get() < 3 { Err("down") } else { Ok(7) };
+                    async move { outcome }
+                },
+            )
+            .await;
+        assert!(matches!(result, Ok(7)));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(resilience.breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_failures() {
+        let resilience = resilience(3, 10, DEFAULT_COOLDOWN);
+        let calls = Cell::new(0);
+        let result: Result<(), _> = resilience
+            .call(
+                |_: &&str| false,
+                || {
+                    calls.set(calls.get() + 1);
+                    async { Err("malformed") }
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(Failure::Failed("malformed"))));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn opens_after_repeated_failures_then_half_opens() {
+        let resilience = resilience(1, 2, Duration::from_millis(20));
+        for _ in 0..2 {
+            let _ = resilience
+                .call(|_: &&str| true, || async { Err::<(), _>("down") })
+                .await;
+        }
+        assert_eq!(resilience.breaker.state(), CircuitState::Open);
+        let refused = resilience
+            .call(|_: &&str| true, || async { Ok::<_, &str>(()) })
+            .await;
+        assert!(matches!(refused, Err(Failure::CircuitOpen)));
+
+        tokio::time::delay_for(Duration::from_millis(30)).await;
+        assert_eq!(resilience.breaker.state(), CircuitState::HalfOpen);
+        let recovered = resilience
+            .call(|_: &&str| true, || async { Ok::<_, &str>(()) })
+            .await;
+        assert!(recovered.is_ok());
+        assert_eq!(resilience.breaker.state(), CircuitState::Closed);
+    }
+}
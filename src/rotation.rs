@@ -0,0 +1,71 @@
+use crate::env_vars::env_secs;
+use crate::user::User;
+use crate::verify::UtcDateTime;
+use serde::Serialize;
+use std::time::Duration;
+use warp::Filter;
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordAge {
+    Current,
+    // Past the maximum age; a reset link is due but the password still works.
+    Expired,
+    // Past the grace period as well; the password is refused until reset.
+    Locked,
+}
+
+// Optional forced rotation. Off unless `APP_PASSWORD_MAX_AGE_SECS` is set.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordRotation {
+    max_age: Option<Duration>,
+    grace: Duration,
+}
+
+impl PasswordRotation {
+    pub fn new(max_age: Option<Duration>, grace: Duration) -> Self {
+        PasswordRotation { max_age, grace }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        Ok(PasswordRotation::new(
+            env_secs("APP_PASSWORD_MAX_AGE_SECS")?.filter(|max_age| !max_age.is_zero()),
+            env_secs("APP_PASSWORD_GRACE_SECS")?.unwrap_or(DEFAULT_GRACE),
+        ))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = *self;
+        warp::any().map(move || hanging_copy)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some()
+    }
+
+    pub fn age(&self, user: &User, now: UtcDateTime) -> PasswordAge {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return PasswordAge::Current,
+        };
+        let age = (now - user.password_changed_at)
+            .to_std()
+            .unwrap_or_default();
+        if age < max_age {
+            PasswordAge::Current
+        } else if age < max_age + self.grace {
+            PasswordAge::Expired
+        } else {
+            PasswordAge::Locked
+        }
+    }
+
+    // Expired or locked, with no reset link sent yet for this password.
+    pub fn link_due(&self, user: &User, now: UtcDateTime) -> bool {
+        self.age(user, now) != PasswordAge::Current && user.rotation_requested_at.is_none()
+    }
+}
@@ -0,0 +1,57 @@
+// Every value typed into a form passes through here before it reaches
+// `UserBuilder` or a template. Text fields lose their control characters and
+// surrounding whitespace; passwords are only ever measured, never altered.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    max: usize,
+    too_long: &'static str,
+}
+
+impl Limit {
+    pub fn check(&self, value: &str) -> Option<&'static str> {
+        if value.chars().count() > self.max {
+            Some(self.too_long)
+        } else {
+            None
+        }
+    }
+}
+
+pub const NAME: Limit = Limit {
+    max: 100,
+    too_long: "Name must be at most 100 characters.",
+};
+
+pub const EMAIL: Limit = Limit {
+    max: 254,
+    too_long: "Email must be at most 254 characters.",
+};
+
+pub const KEY_NAME: Limit = Limit {
+    max: 64,
+    too_long: "Key name must be at most 64 characters.",
+};
+
+// bcrypt silently ignores everything past its first 72 bytes, so a longer
+// password would look accepted while only part of it counts.
+pub const MAX_PASSWORD_BYTES: usize = 72;
+
+pub fn text(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+pub fn password_error(password: &str) -> Option<&'static str> {
+    if password.is_empty() {
+        Some("Password is required.")
+    } else if password.len() > MAX_PASSWORD_BYTES {
+        Some("Password must be at most 72 bytes.")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,326 @@
+use crate::env_vars::env_secs;
+use crate::reporting::{self, ErrorEvent};
+use crate::resilience::{BackendPolicy, Failure, Resilience};
+use async_trait::async_trait;
+use hyper::body::Buf;
+use secrecy::{ExposeSecret, SecretVec};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub const DEV_SECRET_KEY: &[u8] = b"my super secret key";
+const DEFAULT_VAULT_FIELD: &str = "signing_key";
+const DEFAULT_SECRET_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub enum SecretError {
+    Missing(String),
+    Io(std::io::Error),
+    Http(String),
+    Malformed(String),
+    UnknownSource(String),
+    CircuitOpen(&'static str),
+    Invalid(String),
+}
+
+impl SecretError {
+    fn is_transient(&self) -> bool {
+        matches!(self, SecretError::Io(_) | SecretError::Http(_))
+    }
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Missing(what) => write!(f, "secret {} is not set", what),
+            SecretError::Io(err) => write!(f, "could not read secret: {}", err),
+            SecretError::Http(err) => write!(f, "secret backend request failed: {}", err),
+            SecretError::Malformed(err) => {
+                write!(f, "secret backend response was malformed: {}", err)
+            }
+            SecretError::UnknownSource(source) => write!(
+                f,
+                "unknown secret source {} (expected dev, env, file or vault)",
+                source
+            ),
+            SecretError::CircuitOpen(provider) => write!(
+                f,
+                "not asking {} for the secret while its circuit breaker is open",
+                provider
+            ),
+            SecretError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError>;
+}
+
+pub struct DevSecret;
+
+#[async_trait]
+impl SecretProvider for DevSecret {
+    fn name(&self) -> &'static str {
+        "dev"
+    }
+
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError> {
+        Ok(SecretVec::new(DEV_SECRET_KEY.to_vec()))
+    }
+}
+
+pub struct EnvSecret {
+    var: String,
+}
+
+impl EnvSecret {
+    pub fn new(var: &str) -> Self {
+        EnvSecret {
+            var: var.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for EnvSecret {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError> {
+        env::var(&self.var)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| SecretVec::new(value.into_bytes()))
+            .ok_or_else(|| SecretError::Missing(self.var.clone()))
+    }
+}
+
+pub struct FileSecret {
+    path: PathBuf,
+}
+
+impl FileSecret {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileSecret { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for FileSecret {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError> {
+        let mut contents = tokio::fs::read(&self.path).await.map_err(SecretError::Io)?;
+        while matches!(contents.last(), Some(byte) if byte.is_ascii_whitespace()) {
+            contents.pop();
+        }
+        if contents.is_empty() {
+            return Err(SecretError::Missing(self.path.display().to_string()));
+        }
+        Ok(SecretVec::new(contents))
+    }
+}
+
+type HttpsClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+pub struct VaultSecret {
+    client: HttpsClient,
+    url: String,
+    token: String,
+    field: String,
+}
+
+impl VaultSecret {
+    pub fn new(addr: &str, token: &str, path: &str, field: &str) -> Self {
+        VaultSecret {
+            client: hyper::Client::builder().build(hyper_rustls::HttpsConnector::new()),
+            url: format!(
+                "{}/v1/{}",
+                addr.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            ),
+            token: token.to_string(),
+            field: field.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for VaultSecret {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError> {
+        let request = hyper::Request::get(&self.url)
+            .header("X-Vault-Token", &self.token)
+            .body(hyper::Body::empty())
+            .map_err(|err| SecretError::Http(err.to_string()))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| SecretError::Http(err.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SecretError::Http(format!(
+                "{} returned {}",
+                self.url, status
+            )));
+        }
+        let body = hyper::body::aggregate(response.into_body())
+            .await
+            .map_err(|err| SecretError::Http(err.to_string()))?;
+        let json: serde_json::Value = serde_json::from_slice(body.bytes())
+            .map_err(|err| SecretError::Malformed(err.to_string()))?;
+        // KV v2 nests the secret under data.data, KV v1 directly under data.
+        let data = &json["data"];
+        let data = if data["data"].is_object() {
+            &data["data"]
+        } else {
+            data
+        };
+        data[self.field.as_str()]
+            .as_str()
+            .filter(|value| !value.is_empty())
+            .map(|value| SecretVec::new(value.as_bytes().to_vec()))
+            .ok_or_else(|| SecretError::Malformed(format!("no string field {}", self.field)))
+    }
+}
+
+pub struct ResilientSecret {
+    inner: Box<dyn SecretProvider>,
+    resilience: Resilience,
+}
+
+impl ResilientSecret {
+    pub fn new(inner: Box<dyn SecretProvider>, policy: BackendPolicy) -> Self {
+        let resilience = Resilience::from_policy(inner.name(), policy);
+        ResilientSecret { inner, resilience }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for ResilientSecret {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn fetch(&self) -> Result<SecretVec<u8>, SecretError> {
+        self.resilience
+            .call(SecretError::is_transient, || self.inner.fetch())
+            .await
+            .map_err(|failure| match failure {
+                Failure::CircuitOpen => SecretError::CircuitOpen(self.inner.name()),
+                Failure::Failed(err) => err,
+            })
+    }
+}
+
+fn copy_secret(secret: &SecretVec<u8>) -> SecretVec<u8> {
+    SecretVec::new(secret.expose_secret().clone())
+}
+
+struct Cached {
+    value: SecretVec<u8>,
+    fetched_at: Instant,
+}
+
+pub struct CachedSecret {
+    provider: Box<dyn SecretProvider>,
+    ttl: Duration,
+    cached: Mutex<Option<Cached>>,
+}
+
+impl CachedSecret {
+    pub fn new(provider: Box<dyn SecretProvider>, ttl: Duration) -> Self {
+        CachedSecret {
+            provider,
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn from_env(backends: BackendPolicy) -> Result<Self, SecretError> {
+        let ttl = env_secs("APP_SECRET_TTL_SECS")
+            .map_err(SecretError::Invalid)?
+            .unwrap_or(DEFAULT_SECRET_TTL);
+        Ok(CachedSecret::new(provider_from_env(backends)?, ttl))
+    }
+
+    pub fn provider_name(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub async fn get(&self) -> Result<SecretVec<u8>, SecretError> {
+        self.load(false).await
+    }
+
+    pub async fn refresh(&self) -> Result<SecretVec<u8>, SecretError> {
+        self.load(true).await
+    }
+
+    async fn load(&self, force: bool) -> Result<SecretVec<u8>, SecretError> {
+        let mut cached = self.cached.lock().await;
+        if let Some(entry) = cached.as_ref() {
+            if !force && entry.fetched_at.elapsed() < self.ttl {
+                return Ok(copy_secret(&entry.value));
+            }
+        }
+        match self.provider.fetch().await {
+            Ok(value) => {
+                *cached = Some(Cached {
+                    value: copy_secret(&value),
+                    fetched_at: Instant::now(),
+                });
+                Ok(value)
+            }
+            Err(err) => match cached.as_ref() {
+                Some(entry) => {
+                    reporting::report(
+                        ErrorEvent::new("secret_refresh_failed", err.to_string())
+                            .with("provider", self.provider.name()),
+                    );
+                    Ok(copy_secret(&entry.value))
+                }
+                None => Err(err),
+            },
+        }
+    }
+}
+
+fn required_env(name: &str) -> Result<String, SecretError> {
+    env::var(name).map_err(|_| SecretError::Missing(name.to_string()))
+}
+
+fn provider_from_env(backends: BackendPolicy) -> Result<Box<dyn SecretProvider>, SecretError> {
+    let source = env::var("APP_SECRET_SOURCE").unwrap_or_else(|_| "dev".to_string());
+    match source.as_str() {
+        "dev" => Ok(Box::new(DevSecret)),
+        "env" => Ok(Box::new(EnvSecret::new("APP_SECRET_KEY"))),
+        "file" => Ok(Box::new(FileSecret::new(required_env("APP_SECRET_FILE")?))),
+        "vault" => Ok(Box::new(ResilientSecret::new(
+            Box::new(VaultSecret::new(
+                &required_env("APP_VAULT_ADDR")?,
+                &required_env("APP_VAULT_TOKEN")?,
+                &required_env("APP_VAULT_SECRET_PATH")?,
+                &env::var("APP_VAULT_SECRET_FIELD")
+                    .unwrap_or_else(|_| DEFAULT_VAULT_FIELD.to_string()),
+            )),
+            backends,
+        ))),
+        other => Err(SecretError::UnknownSource(other.to_string())),
+    }
+}
@@ -0,0 +1,235 @@
+use crate::bulk::BulkOutcome;
+use crate::config::Config;
+use crate::hashing;
+use crate::html::{self, HtmlStringReply, RenderError, UserListing};
+use crate::server::{REPORT_PATHNAME, RESET_PASSWORD_PATHNAME};
+use crate::stats::Stats;
+use crate::user::{NotificationTopic, User, UserDatabase, UserStore};
+use crate::verify::{self, ResetParams};
+use std::fmt;
+
+const SAMPLE_PASSWORD: &str = "self-test password";
+
+// One step of `no-db-verify self-test` and what went wrong with it, if
+// anything.
+#[derive(Debug)]
+pub struct Check {
+    name: String,
+    problem: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<(), String>) -> Self {
+        Check {
+            name: name.into(),
+            problem: result.err(),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            None => write!(f, "ok    {}", self.name),
+            Some(problem) => write!(f, "FAIL  {}: {}", self.name, problem),
+        }
+    }
+}
+
+fn reset_token_round_trip(user: &User) -> Result<(), String> {
+    let query = html::encode_query(&ResetParams::from(user)).map_err(|err| err.to_string())?;
+    let parsed: ResetParams = serde_urlencoded::from_str(&query).map_err(|err| err.to_string())?;
+    if ResetParams::verify(user, &parsed) {
+        Ok(())
+    } else {
+        Err("a freshly minted token did not verify".to_string())
+    }
+}
+
+fn tampered_reset_token(user: &User) -> Result<(), String> {
+    let query = html::encode_query(&ResetParams::from(user)).map_err(|err| err.to_string())?;
+    let tampered = query
+        .split('&')
+        .map(|pair| match pair.strip_prefix("token=") {
+            Some(token) => {
+                let changed = if token.starts_with('A') { 'B' } else { 'A' };
+                format!("token={}{}", changed, token.get(1..).unwrap_or_default())
+            }
+            None => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let parsed: ResetParams =
+        serde_urlencoded::from_str(&tampered).map_err(|err| err.to_string())?;
+    if ResetParams::verify(user, &parsed) {
+        Err("a token with a changed byte still verified".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn password_hash() -> Result<(), String> {
+    let hash = hashing::hash(SAMPLE_PASSWORD).map_err(|err| err.to_string())?;
+    if !hashing::verify(SAMPLE_PASSWORD, &hash) {
+        Err("the password did not match its own hash".to_string())
+    } else if hashing::verify("not the password", &hash) {
+        Err("a wrong password matched the hash".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn rendered(page: Result<String, RenderError>) -> Result<(), String> {
+    page.map(|_| ()).map_err(|err| err.to_string())
+}
+
+// Renders every template once with sample data, so a template that only
+// fails at runtime shows up before real traffic does.
+fn templates(config: &Config, users: &[User], store: &UserStore) -> Vec<Check> {
+    let user = &users[0];
+    let gravatar = config.gravatar;
+    let listing =
+        || UserListing::from_table(store, gravatar, &config.terms, &config.password_rotation);
+    let expires = chrono::Utc::now() + chrono::Duration::hours(1);
+    let link = "/self-test";
+    let page = html::PageContext::from_config(config);
+    let pages: Vec<(&str, Result<String, RenderError>)> = page.render(|| {
+        vec![
+            (
+                "generate_reset.html",
+                html::GeneratePasswordResetTemplate::from_user_reset_link(
+                    user,
+                    RESET_PASSWORD_PATHNAME,
+                    REPORT_PATHNAME,
+                    &expires,
+                    verify::reset_link_ttl(),
+                )
+                .as_html(),
+            ),
+            (
+                "list.html",
+                html::ListUsersTemplate::from_listing(listing()).as_html(),
+            ),
+            (
+                "fragments/user_rows.html",
+                html::UserRowsTemplate::from_listing(listing()).as_html(),
+            ),
+            (
+                "share_link.html",
+                html::ShareLinkTemplate::from_link(link, None, &expires, verify::share_link_ttl())
+                    .as_html(),
+            ),
+            (
+                "shared_list.html",
+                html::SharedListTemplate::from_listing(listing(), None, &expires).as_html(),
+            ),
+            (
+                "reset_password.html",
+                html::ResetPasswordTemplate::from_user(user, &expires).as_html(),
+            ),
+            (
+                "new_user.html",
+                html::NewUserTemplate::form(true, true).as_html(),
+            ),
+            (
+                "fragments/email_available.html",
+                html::EmailAvailableTemplate::from_email(user.email.as_str(), true).as_html(),
+            ),
+            (
+                "create_user.html",
+                html::CreateUserTemplate::form(&config.terms).as_html(),
+            ),
+            (
+                "fragments/create_user_form.html",
+                html::CreateUserFormTemplate::from(html::CreateUserTemplate::form(&config.terms))
+                    .as_html(),
+            ),
+            (
+                "user_detail.html",
+                html::UserDetailTemplate::from_user(user, gravatar).as_html(),
+            ),
+            ("locked.html", html::AccountLockedTemplate.as_html()),
+            (
+                "report.html",
+                html::ReportTemplate::from_user(user, false).as_html(),
+            ),
+            (
+                "revert.html",
+                html::RevertTemplate::from_user(user, false).as_html(),
+            ),
+            (
+                "unsubscribed.html",
+                html::UnsubscribedTemplate::from_topic(NotificationTopic::Email, true).as_html(),
+            ),
+            (
+                "bulk_result.html",
+                html::BulkResultTemplate::applied(
+                    "Reset Links",
+                    vec![BulkOutcome::link(user, link.to_string())],
+                )
+                .as_html(),
+            ),
+            (
+                "api_keys.html",
+                html::ApiKeysTemplate::from_keys(Vec::new()).as_html(),
+            ),
+            (
+                "waitlist.html",
+                html::WaitlistTemplate::from_entries(
+                    true,
+                    Vec::new(),
+                    None,
+                    Vec::new(),
+                    None,
+                    verify::invite_link_ttl(),
+                )
+                .as_html(),
+            ),
+            (
+                "maintenance.html",
+                html::MaintenanceTemplate::new(false, None).as_html(),
+            ),
+            (
+                "merge.html",
+                html::MergeTemplate::form(users, None).as_html(),
+            ),
+            (
+                "stats.html",
+                html::StatsTemplate::from_stats(Stats::collect(store, &[], chrono::Utc::now()))
+                    .as_html(),
+            ),
+            (
+                "error.html",
+                html::ErrorTemplate::from_message("Self-test error page.").as_html(),
+            ),
+        ]
+    });
+    pages
+        .into_iter()
+        .map(|(template, page)| Check::new(format!("render {}", template), rendered(page)))
+        .collect()
+}
+
+// Exercises the signing, hashing and rendering paths once with sample data,
+// using the configured key and bcrypt cost. Nothing is stored or sent.
+pub async fn run(config: &Config) -> Vec<Check> {
+    let db = UserDatabase::create_test_db(config.id_strategy.generator());
+    let store = db.lock().await;
+    let mut users: Vec<User> = store.values().cloned().collect();
+    users.sort_unstable_by_key(|user| user.id);
+    let user = &users[0];
+    let mut checks = vec![
+        Check::new("reset token round trip", reset_token_round_trip(user)),
+        Check::new(
+            "tampered reset token is refused",
+            tampered_reset_token(user),
+        ),
+        Check::new("password hash and verify", password_hash()),
+    ];
+    checks.extend(templates(config, &users, &store));
+    checks
+}
@@ -0,0 +1,2761 @@
+use crate::features::Feature;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::html::HtmlStringReply;
+use crate::pii::Email;
+use crate::user::UserId;
+use crate::{
+    access_log, api_keys, audit, avatars, bulk, config, domains, features, graphql, hashing, html,
+    jobs, limits, links, maintenance, metrics, names, panics, paths, preflight, reporting,
+    resilience, rotation, sanitize, secrets, self_test, service, shadow, terms, throttle, timing,
+    tokens, user, verify, waitlist, well_known,
+};
+use futures::{future, stream, StreamExt};
+use secrecy::{ExposeSecret, SecretString};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::time::Duration;
+use warp::http::Method;
+use warp::{Filter, Reply};
+
+pub const RESET_PASSWORD_PATHNAME: &str = "/reset-password";
+pub const CREATE_USER_PATHNAME: &str = "/create-user";
+pub const SHARED_LIST_PATHNAME: &str = "/shared/list";
+pub const VERIFY_EMAIL_PATHNAME: &str = "/verify-email";
+pub const REVERT_PATHNAME: &str = "/revert";
+pub const REPORT_PATHNAME: &str = "/report";
+pub const CONFIRM_EMAIL_CHANGE_PATHNAME: &str = "/confirm-email-change";
+const CLEANUP_PERIOD: Duration = Duration::from_secs(10 * 60);
+const RESEED_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+const ROTATION_CHECK_PERIOD: Duration = Duration::from_secs(60 * 60);
+const PAGE_METHODS: &[Method] = &[Method::GET, Method::HEAD, Method::OPTIONS];
+const FORM_METHODS: &[Method] = &[Method::GET, Method::HEAD, Method::POST, Method::OPTIONS];
+const MAX_JSON_BODY_BYTES: u64 = 64 * 1024;
+const MAX_FORM_BODY_BYTES: u64 = 16 * 1024;
+// A bulk form repeats `user_id` once per selected row, so it gets more room
+// than other forms.
+const MAX_BULK_FORM_BODY_BYTES: u64 = 64 * 1024;
+const ACTION_METHODS: &[Method] = &[Method::POST, Method::OPTIONS];
+
+#[derive(Debug)]
+enum ServerError {
+    RenderError {
+        message: String,
+        template: Option<&'static str>,
+    },
+    HashError(String),
+    StorageError(String),
+    BadRequest,
+    BadForm(String),
+    MethodNotAllowed(&'static [Method]),
+    PreconditionFailed,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareListParams {
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResetFormParams {
+    requested_password: SecretString,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewUserParams {
+    requested_email: Email,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MergeRequest {
+    primary: UserId,
+    duplicate: UserId,
+}
+
+// `?dry_run=true` on a destructive admin action reports what it would change
+// and changes nothing.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DryRunParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmailAddressParams {
+    email: String,
+}
+
+// Unticked checkboxes are left out of the form entirely.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PreferencesParams {
+    email: Option<String>,
+    sms: Option<String>,
+    marketing: Option<String>,
+}
+
+impl From<PreferencesParams> for user::NotificationPreferences {
+    fn from(params: PreferencesParams) -> Self {
+        user::NotificationPreferences {
+            email: params.email.is_some(),
+            sms: params.sms.is_some(),
+            marketing: params.marketing.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LockParams {
+    locked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistrationParams {
+    open: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaintenanceParams {
+    on: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WaitlistInviteParams {
+    count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailAvailableParams {
+    #[serde(alias = "requested_email")]
+    email: Email,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResetLinksRequest {
+    users: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InvitesRequest {
+    emails: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifyPasswordRequest {
+    user_id: user::UserId,
+    password: SecretString,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyPasswordReply {
+    valid: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateUserParams {
+    requested_name: String,
+    requested_username: String,
+    requested_password: SecretString,
+    #[serde(default)]
+    accept_tos: Option<String>,
+}
+
+impl warp::reject::Reject for ServerError {}
+
+fn render_error(err: html::RenderError) -> warp::reject::Rejection {
+    warp::reject::custom(ServerError::RenderError {
+        message: err.to_string(),
+        template: Some(err.template),
+    })
+}
+
+fn form_bytes(
+) -> impl Filter<Extract = (hyper::body::Bytes,), Error = warp::reject::Rejection> + Clone {
+    form_bytes_up_to(MAX_FORM_BODY_BYTES)
+}
+
+fn form_bytes_up_to(
+    limit: u64,
+) -> impl Filter<Extract = (hyper::body::Bytes,), Error = warp::reject::Rejection> + Clone {
+    warp::body::content_length_limit(limit).and(warp::body::bytes())
+}
+
+fn strict_form<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::reject::Rejection> + Clone {
+    form_bytes().and_then(|body: hyper::body::Bytes| async move {
+        serde_urlencoded::from_bytes::<T>(&body)
+            .map_err(|err| warp::reject::custom(ServerError::BadForm(err.to_string())))
+    })
+}
+
+fn bulk_form() -> impl Filter<Extract = (bulk::BulkParams,), Error = warp::reject::Rejection> + Clone
+{
+    form_bytes_up_to(MAX_BULK_FORM_BODY_BYTES).and_then(|body: hyper::body::Bytes| async move {
+        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+            .map_err(|err| err.to_string())
+            .and_then(bulk::BulkParams::from_pairs)
+            .map_err(|message| warp::reject::custom(ServerError::BadForm(message)))
+    })
+}
+
+fn api_key_form(
+) -> impl Filter<Extract = (api_keys::NewKeyParams,), Error = warp::reject::Rejection> + Clone {
+    form_bytes().and_then(|body: hyper::body::Bytes| async move {
+        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+            .map_err(|err| err.to_string())
+            .and_then(api_keys::NewKeyParams::from_pairs)
+            .map_err(|message| warp::reject::custom(ServerError::BadForm(message)))
+    })
+}
+
+fn json_body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::reject::Rejection> + Clone {
+    warp::body::content_length_limit(MAX_JSON_BODY_BYTES)
+        .and(warp::body::bytes())
+        .and_then(|body: hyper::body::Bytes| async move {
+            serde_json::from_slice::<T>(&body)
+                .map_err(|err| warp::reject::custom(ServerError::BadForm(err.to_string())))
+        })
+}
+
+fn reset_intent() -> impl Filter<Extract = (service::ResetIntent,), Error = Infallible> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and(warp::cookie::optional(verify::RESET_INTENT_COOKIE))
+        .map(|query, cookie| service::ResetIntent { query, cookie })
+}
+
+fn service_error(err: service::ServiceError) -> warp::reject::Rejection {
+    match err {
+        service::ServiceError::NotFound => warp::reject::not_found(),
+        service::ServiceError::BadRequest => warp::reject::custom(ServerError::BadRequest),
+        service::ServiceError::Hash(message) => {
+            warp::reject::custom(ServerError::HashError(message))
+        }
+        service::ServiceError::Storage(message) => {
+            warp::reject::custom(ServerError::StorageError(message))
+        }
+        service::ServiceError::Url(err) => warp::reject::custom(ServerError::RenderError {
+            message: err.to_string(),
+            template: None,
+        }),
+        service::ServiceError::PreconditionFailed => {
+            warp::reject::custom(ServerError::PreconditionFailed)
+        }
+    }
+}
+
+fn html_page(
+    page: Result<String, html::RenderError>,
+    status: warp::http::StatusCode,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    page.map(|page| warp::reply::with_status(warp::reply::html(page), status).into_response())
+        .map_err(render_error)
+}
+
+fn create_user_page(
+    page: html::CreateUserTemplate,
+    htmx: bool,
+) -> Result<String, html::RenderError> {
+    if htmx {
+        html::CreateUserFormTemplate::from(page).as_html()
+    } else {
+        page.as_html()
+    }
+}
+
+fn respond(
+    page: &html::PageContext,
+    outcome: service::PageOutcome,
+    htmx: bool,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    page.render(|| render_outcome(outcome, htmx))
+}
+
+fn render_outcome(
+    outcome: service::PageOutcome,
+    htmx: bool,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    use service::PageOutcome;
+    let ok = warp::http::StatusCode::OK;
+    match outcome {
+        PageOutcome::ConfirmReset {
+            user,
+            continue_link,
+            intent,
+            refused,
+            expires,
+        } => {
+            let status = if refused {
+                warp::http::StatusCode::FORBIDDEN
+            } else {
+                ok
+            };
+            let page = html_page(
+                html::ResetPasswordTemplate::confirm(&user, continue_link, &expires).as_html(),
+                status,
+            )?;
+            let cookie = format!(
+                "{}={}; Max-Age={}; Path={}; HttpOnly; SameSite=Strict",
+                verify::RESET_INTENT_COOKIE,
+                intent,
+                verify::RESET_INTENT_LIFETIME.as_secs(),
+                RESET_PASSWORD_PATHNAME
+            );
+            Ok(
+                warp::reply::with_header(page, warp::http::header::SET_COOKIE, cookie)
+                    .into_response(),
+            )
+        }
+        PageOutcome::ResetForm {
+            user,
+            error: None,
+            expires,
+        } => html_page(
+            html::ResetPasswordTemplate::from_user(&user, &expires).as_html(),
+            ok,
+        ),
+        PageOutcome::ResetForm {
+            user,
+            error: Some(error),
+            expires,
+        } => html_page(
+            html::ResetPasswordTemplate::with_error(&user, error, &expires).as_html(),
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+        PageOutcome::PasswordReset { user, success } => html_page(
+            html::ResetPasswordTemplate::from_user_with_warning(&user, success).as_html(),
+            ok,
+        ),
+        PageOutcome::ResetLink {
+            user,
+            link,
+            report_link,
+            expires,
+        } => html_page(
+            html::GeneratePasswordResetTemplate::from_user_reset_link(
+                &user,
+                &link,
+                &report_link,
+                &expires,
+                verify::reset_link_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::UserDetail { user, gravatar } => html_page(
+            html::UserDetailTemplate::from_user(&user, gravatar).as_html(),
+            ok,
+        ),
+        PageOutcome::AvatarNotice {
+            user,
+            gravatar,
+            saved,
+            message,
+        } => {
+            let status = if saved {
+                ok
+            } else {
+                warp::http::StatusCode::BAD_REQUEST
+            };
+            html_page(
+                html::UserDetailTemplate::with_notice(&user, gravatar, saved, message).as_html(),
+                status,
+            )
+        }
+        PageOutcome::PreferencesSaved { user, gravatar } => html_page(
+            html::UserDetailTemplate::with_notice(
+                &user,
+                gravatar,
+                true,
+                "Notification preferences saved.".to_string(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::VerificationLink {
+            user,
+            gravatar,
+            link: Ok((link, expires)),
+        } => html_page(
+            html::UserDetailTemplate::with_link(
+                &user,
+                gravatar,
+                link,
+                &expires,
+                verify::verify_email_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::VerificationLink {
+            user,
+            gravatar,
+            link: Err(error),
+        } => html_page(
+            html::UserDetailTemplate::with_notice(&user, gravatar, false, error.to_string())
+                .as_html(),
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+        PageOutcome::LockChanged { user, gravatar } => {
+            let message = if user.is_locked() {
+                "Account locked."
+            } else {
+                "Account unlocked."
+            };
+            html_page(
+                html::UserDetailTemplate::with_notice(&user, gravatar, true, message.to_string())
+                    .as_html(),
+                ok,
+            )
+        }
+        PageOutcome::ResetLinkThrottled { throttled } => {
+            let mut page = html_page(
+                html::ErrorTemplate::from_message(&throttled.to_string()).as_html(),
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+            )?;
+            page.headers_mut().insert(
+                warp::http::header::RETRY_AFTER,
+                warp::http::HeaderValue::from(throttled.retry_after.as_secs().max(1)),
+            );
+            Ok(page)
+        }
+        PageOutcome::AccountLocked => html_page(
+            html::AccountLockedTemplate.as_html(),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        PageOutcome::Report {
+            user: Some(user),
+            reported,
+        } => html_page(
+            html::ReportTemplate::from_user(&user, reported).as_html(),
+            ok,
+        ),
+        PageOutcome::Report { user: None, .. } => html_page(
+            html::ErrorTemplate::from_message("That link is invalid or has already expired.")
+                .as_html(),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        PageOutcome::Revert {
+            user: Some(user),
+            reverted,
+        } => html_page(
+            html::RevertTemplate::from_user(&user, reverted).as_html(),
+            ok,
+        ),
+        PageOutcome::Revert { user: None, .. } => html_page(
+            html::ErrorTemplate::from_message(
+                "That link is invalid, has expired or was already used.",
+            )
+            .as_html(),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        PageOutcome::EmailChangeNotice {
+            user,
+            gravatar,
+            success,
+            message,
+        } => html_page(
+            html::UserDetailTemplate::with_notice(&user, gravatar, success, message.to_string())
+                .as_html(),
+            if success {
+                ok
+            } else {
+                warp::http::StatusCode::BAD_REQUEST
+            },
+        ),
+        PageOutcome::EmailChangeLinksSent {
+            user,
+            gravatar,
+            expires,
+        } => html_page(
+            html::UserDetailTemplate::with_expiring_notice(
+                &user,
+                gravatar,
+                "Confirmation links sent to both addresses. The change applies once both are followed."
+                    .to_string(),
+                &expires,
+                verify::email_change_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::EmailChangeRejected => html_page(
+            html::ErrorTemplate::from_message(
+                "That confirmation link is invalid, has expired or belongs to a change that was replaced or cancelled.",
+            )
+            .as_html(),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        PageOutcome::EmailVerified {
+            user: Some(user),
+            gravatar,
+        } => html_page(
+            html::UserDetailTemplate::with_notice(
+                &user,
+                gravatar,
+                true,
+                "Email address verified.".to_string(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::EmailVerified { user: None, .. } => html_page(
+            html::ErrorTemplate::from_message(
+                "That verification link is invalid or has expired. Ask for a new one.",
+            )
+            .as_html(),
+            warp::http::StatusCode::FORBIDDEN,
+        ),
+        PageOutcome::Unsubscribed { topic, success } => html_page(
+            html::UnsubscribedTemplate::from_topic(topic, success).as_html(),
+            if success {
+                ok
+            } else {
+                warp::http::StatusCode::FORBIDDEN
+            },
+        ),
+        PageOutcome::Invite {
+            email,
+            link,
+            expires,
+        } => html_page(
+            html::NewUserTemplate::from_email(
+                Some((&link, email.as_str())),
+                &expires,
+                verify::invite_link_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::Waitlisted { email } => html_page(
+            html::NewUserTemplate::waitlisted(email.as_str()).as_html(),
+            ok,
+        ),
+        PageOutcome::SignupRejected { message, open } => html_page(
+            html::NewUserTemplate::rejected(&message, open).as_html(),
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+        PageOutcome::Maintenance { on, notice } => {
+            html_page(html::MaintenanceTemplate::new(on, notice).as_html(), ok)
+        }
+        PageOutcome::Waitlist {
+            open,
+            waiting,
+            notice,
+            invites,
+            expires,
+        } => html_page(
+            html::WaitlistTemplate::from_entries(
+                open,
+                waiting,
+                notice,
+                invites,
+                expires.as_ref(),
+                verify::invite_link_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::MergeForm { users, error } => html_page(
+            html::MergeTemplate::form(&users, error).as_html(),
+            if error.is_some() {
+                warp::http::StatusCode::BAD_REQUEST
+            } else {
+                ok
+            },
+        ),
+        PageOutcome::MergeConfirm {
+            primary,
+            duplicate,
+            params,
+        } => html_page(
+            html::MergeTemplate::confirm(&primary, &duplicate, &params)
+                .map_err(|err| service_error(service::ServiceError::Url(err)))?
+                .as_html(),
+            ok,
+        ),
+        PageOutcome::Merged {
+            primary,
+            duplicate,
+            moved_events,
+        } => html_page(
+            html::MergeTemplate::merged(&primary, &duplicate, moved_events).as_html(),
+            ok,
+        ),
+        PageOutcome::MergePreview {
+            primary,
+            duplicate,
+            params,
+            moved_events,
+        } => html_page(
+            html::MergeTemplate::preview(&primary, &duplicate, &params, moved_events)
+                .map_err(|err| service_error(service::ServiceError::Url(err)))?
+                .as_html(),
+            ok,
+        ),
+        PageOutcome::Stats { stats } => {
+            html_page(html::StatsTemplate::from_stats(stats).as_html(), ok)
+        }
+        PageOutcome::ShareLink {
+            link,
+            filter,
+            expires,
+        } => html_page(
+            html::ShareLinkTemplate::from_link(
+                &link,
+                filter.as_deref(),
+                &expires,
+                verify::share_link_ttl(),
+            )
+            .as_html(),
+            ok,
+        ),
+        PageOutcome::CreateUserForm { errors, terms } => html_page(
+            create_user_page(
+                html::CreateUserTemplate::form_with_errors(errors, &terms),
+                htmx,
+            ),
+            ok,
+        ),
+        PageOutcome::UserCreated { success } => html_page(
+            create_user_page(html::CreateUserTemplate::report_success(success), htmx),
+            ok,
+        ),
+        PageOutcome::BulkRejected { action, outcomes } => html_page(
+            html::BulkResultTemplate::rejected(action.title(), outcomes).as_html(),
+            warp::http::StatusCode::CONFLICT,
+        ),
+        PageOutcome::BulkApplied { action, outcomes } => html_page(
+            html::BulkResultTemplate::applied(action.title(), outcomes).as_html(),
+            ok,
+        ),
+        PageOutcome::BulkPreview {
+            action,
+            selected,
+            outcomes,
+        } => html_page(
+            html::BulkResultTemplate::preview(action, &selected, outcomes).as_html(),
+            ok,
+        ),
+        PageOutcome::Export { csv, filename } => {
+            let reply = warp::reply::with_header(
+                csv,
+                warp::http::header::CONTENT_TYPE,
+                "text/csv; charset=utf-8",
+            );
+            Ok(warp::reply::with_header(
+                reply,
+                warp::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            )
+            .into_response())
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn reset_password_post_handler(
+    db: user::UserDatabase,
+    used_tokens: tokens::UsedTokenStore,
+    audit: audit::AuditLog,
+    links: links::Links,
+    url_params: verify::ResetParams,
+    intent: service::ResetIntent,
+    form_params: ResetFormParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::reset_password(
+        &db,
+        &used_tokens,
+        &audit,
+        &links,
+        &url_params,
+        &intent,
+        form_params.requested_password.expose_secret(),
+    )
+    .await
+    .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn reset_password_get_handler(
+    db: user::UserDatabase,
+    params: verify::ResetParams,
+    intent: service::ResetIntent,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::reset_form(&db, &params, &intent)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn generate_reset_password_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    links: links::Links,
+    throttle: throttle::ResetThrottle,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::generate_reset_link(&db, &audit, &links, &throttle, id)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn user_detail_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    gravatar: avatars::Gravatar,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::user_detail(&db, gravatar, id)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn username_detail_handler(
+    handle: String,
+    db: user::UserDatabase,
+    gravatar: avatars::Gravatar,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let username = handle
+        .strip_prefix('@')
+        .ok_or_else(warp::reject::not_found)?;
+    let outcome = service::user_detail_by_username(&db, gravatar, username)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn avatar_get_handler(
+    id: user::UserId,
+    avatars: avatars::Avatars,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (content_type, image) = avatars
+        .load(id)
+        .await
+        .map_err(|err| warp::reject::custom(ServerError::StorageError(err.to_string())))?
+        .ok_or_else(warp::reject::not_found)?;
+    let reply = warp::reply::with_header(image, warp::http::header::CONTENT_TYPE, content_type);
+    Ok(warp::reply::with_header(
+        reply,
+        warp::http::header::CACHE_CONTROL,
+        "no-cache",
+    ))
+}
+
+async fn avatar_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    avatars: avatars::Avatars,
+    gravatar: avatars::Gravatar,
+    form: warp::multipart::FormData,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    service::require_user(&db, id)
+        .await
+        .map_err(service_error)?;
+    let upload = avatars::read_upload(form).await;
+    let outcome = service::save_avatar(&db, &avatars, gravatar, id, upload)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn preferences_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: PreferencesParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::save_preferences(&db, &audit, gravatar, id, params.into())
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn alias_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    links: links::Links,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: EmailAddressParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::add_email_alias(&db, &links, &audit, gravatar, id, &params.email)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn email_change_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    links: links::Links,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: EmailAddressParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::request_email_change(&db, &links, &audit, gravatar, id, &params.email)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn email_change_cancel_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::cancel_email_change(&db, &audit, gravatar, id)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn verification_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    links: links::Links,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: EmailAddressParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::resend_verification(&db, &links, &audit, gravatar, id, &params.email)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn lock_post_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: LockParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::set_locked(&db, &audit, gravatar, id, params.locked)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn lock_api_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    terms: terms::Terms,
+    if_match: Option<String>,
+    request: LockParams,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let reply = service::set_locked_reply(&db, &audit, &terms, id, request.locked, |version| {
+        if_match
+            .as_deref()
+            .is_none_or(|if_match| etag_matches_strong(if_match, &user_etag(version)))
+    })
+    .await
+    .map_err(service_error)?;
+    Ok(with_etag(
+        warp::reply::json(&reply).into_response(),
+        &user_etag(reply.version),
+    ))
+}
+
+async fn link_report_get_handler(
+    db: user::UserDatabase,
+    params: verify::ResetParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::report_page(&db, &params).await, false)
+}
+
+async fn link_report_post_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    used_tokens: tokens::UsedTokenStore,
+    links: links::Links,
+    params: verify::ResetParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::report_link(&db, &audit, &used_tokens, &links, &params).await,
+        false,
+    )
+}
+
+async fn revert_get_handler(
+    db: user::UserDatabase,
+    params: verify::RevertParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::revert_page(&db, &params).await, false)
+}
+
+async fn revert_post_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    params: verify::RevertParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::revert_reset(&db, &audit, &params).await,
+        false,
+    )
+}
+
+async fn verify_email_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: verify::VerifyEmailParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::verify_email(&db, &audit, gravatar, &params).await,
+        false,
+    )
+}
+
+async fn confirm_email_change_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    gravatar: avatars::Gravatar,
+    params: verify::EmailChangeParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::confirm_email_change(&db, &audit, gravatar, &params).await,
+        false,
+    )
+}
+
+async fn unsubscribe_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    params: verify::UnsubscribeParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::unsubscribe(&db, &audit, &params)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn merge_get_handler(
+    db: user::UserDatabase,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::merge_page(&db).await, false)
+}
+
+async fn merge_post_handler(
+    db: user::UserDatabase,
+    request: MergeRequest,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::request_merge(&db, request.primary, request.duplicate)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn merge_confirm_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    dry_run: DryRunParams,
+    params: verify::MergeParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::merge(&db, &audit, &params, dry_run.dry_run)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn new_user_get_handler(
+    waitlist: waitlist::Waitlist,
+    page: html::PageContext,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let open = waitlist.is_open().await;
+    page.render(|| html::NewUserTemplate::form(page.api_enabled, open).as_html())
+        .map(warp::reply::html)
+        .map_err(render_error)
+}
+
+async fn new_user_post_handler(
+    links: links::Links,
+    waitlist: waitlist::Waitlist,
+    domains: domains::AllowedDomains,
+    form_params: NewUserParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let email = form_params.requested_email.as_str();
+    let outcome = service::invite(&links, &waitlist, &domains, email)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn waitlist_handler(
+    waitlist: waitlist::Waitlist,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::waitlist_page(&waitlist, None).await, false)
+}
+
+async fn stats_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::stats(&db, &audit).await, false)
+}
+
+async fn report_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::report(&db, &audit).await, false)
+}
+
+async fn share_list_handler(
+    audit: audit::AuditLog,
+    links: links::Links,
+    params: ShareListParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::share_list(&audit, &links, params.filter.as_deref())
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn shared_list_handler(
+    db: user::UserDatabase,
+    gravatar: avatars::Gravatar,
+    terms: terms::Terms,
+    rotation: rotation::PasswordRotation,
+    params: verify::ShareParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    if !verify::ShareParams::verify(&params) {
+        return html_page(
+            page.render(|| {
+                html::ErrorTemplate::from_message("This share link is invalid or has expired.")
+                    .as_html()
+            }),
+            warp::http::StatusCode::FORBIDDEN,
+        );
+    }
+    let users = db.lock().await;
+    let mut listing = html::UserListing::from_table(&users, gravatar, &terms, &rotation);
+    if let Some(filter) = params.filter() {
+        listing = listing.matching(filter);
+    }
+    html_page(
+        page.render(|| {
+            html::SharedListTemplate::from_listing(listing, params.filter(), &params.expires())
+                .as_html()
+        }),
+        warp::http::StatusCode::OK,
+    )
+}
+
+async fn registration_post_handler(
+    waitlist: waitlist::Waitlist,
+    params: RegistrationParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::set_registration(&waitlist, params.open).await,
+        false,
+    )
+}
+
+async fn maintenance_get_handler(
+    maintenance: maintenance::Maintenance,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(&page, service::maintenance_page(&maintenance), false)
+}
+
+async fn maintenance_post_handler(
+    maintenance: maintenance::Maintenance,
+    params: MaintenanceParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    respond(
+        &page,
+        service::set_maintenance(&maintenance, params.on),
+        false,
+    )
+}
+
+async fn waitlist_invite_handler(
+    links: links::Links,
+    waitlist: waitlist::Waitlist,
+    params: WaitlistInviteParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::invite_waitlisted(&links, &waitlist, params.count)
+        .await
+        .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn create_user_get_handler(
+    terms: terms::Terms,
+    page: html::PageContext,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    page.render(|| html::CreateUserTemplate::form(&terms).as_html())
+        .map(warp::reply::html)
+        .map_err(render_error)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_user_post_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    links: links::Links,
+    features: features::Features,
+    terms: terms::Terms,
+    domains: domains::AllowedDomains,
+    names: names::NameBlocklist,
+    url_params: verify::CreateParams,
+    htmx: bool,
+    form_params: CreateUserParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::create_user(
+        &db,
+        &audit,
+        &links,
+        service::SignupPolicy {
+            terms: &terms,
+            domains: &domains,
+            names: &names,
+            screen_lookalikes: features.is_enabled(Feature::LookalikeScreening),
+        },
+        &url_params,
+        service::SignupForm {
+            name: &form_params.requested_name,
+            username: &form_params.requested_username,
+            password: form_params.requested_password.expose_secret(),
+            accepted_tos: form_params.accept_tos.as_deref() == Some("on"),
+        },
+    )
+    .await
+    .map_err(service_error)?;
+    respond(&page, outcome, htmx)
+}
+
+async fn list_handler(
+    db: user::UserDatabase,
+    gravatar: avatars::Gravatar,
+    terms: terms::Terms,
+    rotation: rotation::PasswordRotation,
+    params: ListParams,
+    htmx: bool,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let users = db.lock().await;
+    let listing = html::UserListing::from_table(&users, gravatar, &terms, &rotation);
+    let rendered = match params.format.as_deref() {
+        Some("json") => return Ok(warp::reply::json(&listing).into_response()),
+        Some("html") | None if htmx => html::UserRowsTemplate::from_listing(listing).as_html(),
+        Some("html") | None => {
+            page.render(|| html::ListUsersTemplate::from_listing(listing).as_html())
+        }
+        Some(_) => return Err(warp::reject::custom(ServerError::BadRequest)),
+    };
+    rendered
+        .map(|rendered| warp::reply::html(rendered).into_response())
+        .map_err(render_error)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn bulk_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    links: links::Links,
+    throttle: throttle::ResetThrottle,
+    avatars: avatars::Avatars,
+    dry_run: DryRunParams,
+    params: bulk::BulkParams,
+    page: html::PageContext,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let outcome = service::bulk(
+        &db,
+        &audit,
+        &links,
+        &throttle,
+        &avatars,
+        params,
+        dry_run.dry_run,
+    )
+    .await
+    .map_err(service_error)?;
+    respond(&page, outcome, false)
+}
+
+async fn reset_links_handler(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    links: links::Links,
+    throttle: throttle::ResetThrottle,
+    request: ResetLinksRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    service::reset_links(&db, &audit, &links, &throttle, request.users)
+        .await
+        .map(|reply| warp::reply::json(&reply))
+        .map_err(service_error)
+}
+
+async fn invites_handler(
+    links: links::Links,
+    request: InvitesRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    service::invite_links(&links, request.emails)
+        .await
+        .map(|reply| warp::reply::json(&reply))
+        .map_err(service_error)
+}
+
+fn user_etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn with_etag(mut response: warp::reply::Response, etag: &str) -> warp::reply::Response {
+    if let Ok(etag) = warp::http::HeaderValue::from_str(etag) {
+        response
+            .headers_mut()
+            .insert(warp::http::header::ETAG, etag);
+    }
+    response
+}
+
+// If-Match uses the strong comparison (RFC 7232 §3.1): a weak validator
+// never matches, since it can't vouch for the exact representation.
+fn etag_matches_strong(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+// If-None-Match uses the weak comparison (RFC 7232 §3.2).
+fn etag_matches_weak(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+async fn user_api_handler(
+    id: user::UserId,
+    db: user::UserDatabase,
+    terms: terms::Terms,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<warp::reply::Response, warp::reject::Rejection> {
+    let reply = service::user_reply(&db, &terms, id)
+        .await
+        .map_err(service_error)?;
+    let etag = user_etag(reply.version);
+    let status = match (if_match, if_none_match) {
+        (Some(if_match), _) if !etag_matches_strong(&if_match, &etag) => {
+            warp::http::StatusCode::PRECONDITION_FAILED
+        }
+        (_, Some(if_none_match)) if etag_matches_weak(&if_none_match, &etag) => {
+            warp::http::StatusCode::NOT_MODIFIED
+        }
+        _ => warp::http::StatusCode::OK,
+    };
+    let response = if status == warp::http::StatusCode::OK {
+        warp::reply::json(&reply).into_response()
+    } else {
+        warp::reply::with_status(warp::reply(), status).into_response()
+    };
+    Ok(with_etag(response, &etag))
+}
+
+async fn api_keys_handler(
+    keys: api_keys::ApiKeys,
+    page: html::PageContext,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let keys = keys.list().await;
+    page.render(|| html::ApiKeysTemplate::from_keys(keys).as_html())
+        .map(warp::reply::html)
+        .map_err(render_error)
+}
+
+async fn api_key_create_handler(
+    keys: api_keys::ApiKeys,
+    params: api_keys::NewKeyParams,
+    page: html::PageContext,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (created, key) = keys.create(params).await;
+    let keys = keys.list().await;
+    page.render(|| html::ApiKeysTemplate::created(keys, &created.name, key).as_html())
+        .map(warp::reply::html)
+        .map_err(render_error)
+}
+
+async fn api_key_revoke_handler(
+    id: String,
+    keys: api_keys::ApiKeys,
+    page: html::PageContext,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let revoked = keys.revoke(&id).await.ok_or_else(warp::reject::not_found)?;
+    let keys = keys.list().await;
+    page.render(|| html::ApiKeysTemplate::revoked(keys, &revoked.name).as_html())
+        .map(warp::reply::html)
+        .map_err(render_error)
+}
+
+async fn verify_password_handler(
+    db: user::UserDatabase,
+    rotation: rotation::PasswordRotation,
+    request: VerifyPasswordRequest,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let valid = service::verify_password(
+        &db,
+        &rotation,
+        request.user_id,
+        request.password.expose_secret(),
+    )
+    .await;
+    Ok(warp::reply::json(&VerifyPasswordReply { valid }))
+}
+
+async fn email_available_handler(
+    db: user::UserDatabase,
+    params: EmailAvailableParams,
+    htmx: bool,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let email = &sanitize::text(params.email.as_str());
+    let available = service::email_available(&db, email).await;
+    let availability = html::EmailAvailableTemplate::from_email(email, available);
+    if htmx {
+        availability
+            .as_html()
+            .map(|page| warp::reply::html(page).into_response())
+            .map_err(render_error)
+    } else {
+        Ok(warp::reply::json(&availability).into_response())
+    }
+}
+
+async fn events_handler(
+    audit: audit::AuditLog,
+    last_event_id: Option<u64>,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    let (backlog, live) = audit.subscribe(last_event_id);
+    let live = live.filter_map(|event| future::ready(event.ok()));
+    let events = stream::iter(backlog).chain(live).map(|event| {
+        Ok::<_, Infallible>((
+            warp::sse::id(event.id),
+            warp::sse::event(event.kind.name()),
+            warp::sse::json(event),
+        ))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+async fn graphql_handler(
+    (schema, request): (graphql::GraphQLSchema, async_graphql::Request),
+) -> Result<impl warp::Reply, Infallible> {
+    Ok(async_graphql_warp::Response::from(
+        schema.execute(request).await,
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct ReadyReply {
+    ready: bool,
+    backends: Vec<resilience::BackendStatus>,
+}
+
+async fn readyz_handler() -> Result<impl warp::Reply, Infallible> {
+    let backends = resilience::statuses();
+    let ready = backends
+        .iter()
+        .all(|backend| backend.state != resilience::CircuitState::Open);
+    let status = if ready {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ReadyReply { ready, backends }),
+        status,
+    ))
+}
+
+async fn metrics_handler(
+    metrics: metrics::Metrics,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    Ok(metrics.render())
+}
+
+async fn rotation_job(
+    db: user::UserDatabase,
+    audit: audit::AuditLog,
+    links: links::Links,
+    metrics: metrics::Metrics,
+    rotation: rotation::PasswordRotation,
+) {
+    match service::request_rotations(&db, &audit, &links, &rotation).await {
+        Ok(requested) => metrics.incr_by("password_rotations_requested_total", requested as u64),
+        Err(err) => eprintln!("could not request password rotations: {:?}", err),
+    }
+}
+
+async fn cleanup_job(
+    used_tokens: tokens::UsedTokenStore,
+    links: links::Links,
+    metrics: metrics::Metrics,
+) {
+    let purged = used_tokens.purge_expired().await;
+    metrics.incr_by("purged_used_tokens_total", purged as u64);
+    let purged = links.purge_expired().await;
+    metrics.incr_by("purged_opaque_links_total", purged as u64);
+}
+
+fn allow_methods(
+    allowed: &'static [Method],
+) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+    warp::method()
+        .and_then(move |method: Method| async move {
+            if allowed.contains(&method) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(ServerError::MethodNotAllowed(allowed)))
+            }
+        })
+        .untuple_one()
+}
+
+async fn security_txt_handler(
+    well_known: well_known::WellKnown,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    well_known
+        .security_txt()
+        .ok_or_else(warp::reject::not_found)
+}
+
+async fn jwks_handler() -> Result<impl warp::Reply, warp::reject::Rejection> {
+    verify::public_keys()
+        .map(|keys| warp::reply::json(&well_known::jwks(&keys.purposes)))
+        .ok_or_else(warp::reject::not_found)
+}
+
+const COUNTDOWN_JS: &str = include_str!("../static/countdown.js");
+
+async fn countdown_js_handler() -> Result<impl warp::Reply, warp::reject::Rejection> {
+    Ok(warp::reply::with_header(
+        COUNTDOWN_JS,
+        warp::http::header::CONTENT_TYPE,
+        "application/javascript; charset=utf-8",
+    ))
+}
+
+async fn robots_txt_handler(
+    well_known: well_known::WellKnown,
+) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    Ok(well_known.robots_txt())
+}
+
+fn is_htmx() -> impl Filter<Extract = (bool,), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional::<String>("hx-request").map(|header: Option<String>| header.is_some())
+}
+
+fn get_or_head() -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+    warp::get().or(warp::head()).unify()
+}
+
+fn allow_header(allowed: &[Method]) -> warp::http::HeaderValue {
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    warp::http::HeaderValue::from_str(&allow).unwrap()
+}
+
+fn options_reply(
+    allowed: &'static [Method],
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::reject::Rejection> + Clone {
+    warp::options().map(move || {
+        let reply = warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT);
+        warp::reply::with_header(reply, warp::http::header::ALLOW, allow_header(allowed))
+    })
+}
+
+// Error details gathered by `rejection_handler` when `debug_errors` is on. The
+// request line is only known outside `recover`, so `routes` renders the page.
+#[derive(Debug, Clone)]
+struct DebugError {
+    message: String,
+    details: Vec<(&'static str, String)>,
+}
+
+fn debug_error(err: &warp::reject::Rejection, message: Option<&str>) -> DebugError {
+    let mut details = vec![];
+    match err.find::<ServerError>() {
+        Some(ServerError::RenderError { message, template }) => {
+            if let Some(template) = template {
+                details.push(("Template", template.to_string()));
+            }
+            details.push(("Error", message.clone()));
+        }
+        Some(ServerError::HashError(message)) | Some(ServerError::StorageError(message)) => {
+            details.push(("Error", message.clone()))
+        }
+        _ => {}
+    }
+    details.push(("Rejection", format!("{:#?}", err)));
+    DebugError {
+        message: message.unwrap_or("Something went wrong.").to_string(),
+        details,
+    }
+}
+
+const HSTS: &str = "max-age=31536000";
+
+fn require_secure_transport(response: &mut warp::reply::Response) {
+    let headers = response.headers_mut();
+    let cookies: Vec<_> = headers
+        .get_all(warp::http::header::SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .map(|cookie| format!("{}; Secure", cookie))
+        .collect();
+    headers.remove(warp::http::header::SET_COOKIE);
+    for cookie in cookies {
+        if let Ok(cookie) = warp::http::HeaderValue::from_str(&cookie) {
+            headers.append(warp::http::header::SET_COOKIE, cookie);
+        }
+    }
+    headers.insert(
+        warp::http::header::STRICT_TRANSPORT_SECURITY,
+        warp::http::HeaderValue::from_static(HSTS),
+    );
+}
+
+fn request_line() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(
+            |method: Method, path: warp::path::FullPath, query: String| {
+                if query.is_empty() {
+                    format!("{} {}", method, path.as_str())
+                } else {
+                    format!("{} {}?{}", method, path.as_str(), query)
+                }
+            },
+        )
+}
+
+fn render_debug_error(response: &mut warp::reply::Response, request: String) {
+    let debug = match response.extensions_mut().remove::<DebugError>() {
+        Some(debug) => debug,
+        None => return,
+    };
+    let mut details = vec![("Request", request)];
+    details.extend(debug.details);
+    if let Ok(page) = html::ErrorTemplate::with_details(&debug.message, details).as_html() {
+        response.headers_mut().insert(
+            warp::http::header::CONTENT_TYPE,
+            warp::http::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        *response.body_mut() = page.into();
+    }
+}
+
+async fn rejection_handler(
+    err: warp::reject::Rejection,
+    debug_errors: bool,
+    page_context: html::PageContext,
+) -> Result<impl warp::Reply, Infallible> {
+    let status = match err.find::<ServerError>() {
+        Some(ServerError::BadRequest) | Some(ServerError::BadForm(_)) => {
+            warp::http::StatusCode::BAD_REQUEST
+        }
+        Some(ServerError::RenderError { .. })
+        | Some(ServerError::HashError(_))
+        | Some(ServerError::StorageError(_)) => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        Some(ServerError::MethodNotAllowed(_)) => warp::http::StatusCode::METHOD_NOT_ALLOWED,
+        Some(ServerError::PreconditionFailed) => warp::http::StatusCode::PRECONDITION_FAILED,
+        None => match err.find::<links::LinkError>() {
+            Some(links::LinkError::Unknown) => warp::http::StatusCode::NOT_FOUND,
+            Some(_) => warp::http::StatusCode::BAD_REQUEST,
+            None if err.find::<api_keys::Unauthorized>().is_some() => {
+                warp::http::StatusCode::UNAUTHORIZED
+            }
+            None if err.find::<api_keys::MissingScope>().is_some() => {
+                warp::http::StatusCode::FORBIDDEN
+            }
+            None if err.find::<warp::reject::PayloadTooLarge>().is_some() => {
+                warp::http::StatusCode::PAYLOAD_TOO_LARGE
+            }
+            None if err.find::<maintenance::UnderMaintenance>().is_some() => {
+                return Ok(page_context.render(|| {
+                    limits::unavailable_page(
+                        "We're down for maintenance. Please check back shortly.",
+                    )
+                }));
+            }
+            None if err.find::<limits::Overloaded>().is_some() => {
+                return Ok(page_context.render(|| {
+                    limits::unavailable_page(
+                        "Sorry, we're busy right now. Please try again in a moment.",
+                    )
+                }));
+            }
+            None => warp::http::StatusCode::NOT_FOUND,
+        },
+    };
+    let message = match (err.find::<ServerError>(), err.find::<links::LinkError>()) {
+        (Some(ServerError::BadForm(message)), _) => {
+            Some(format!("That form couldn't be read: {}.", message))
+        }
+        (None, Some(link_error)) => Some(link_error.to_string()),
+        _ => None,
+    };
+    let debug = if debug_errors {
+        Some(debug_error(&err, message.as_deref()))
+    } else {
+        None
+    };
+    let page = message.and_then(|message| {
+        page_context.render(|| html::ErrorTemplate::from_message(&message).as_html().ok())
+    });
+    let mut response = match page {
+        Some(body) => warp::reply::with_status(warp::reply::html(body), status).into_response(),
+        None => warp::reply::with_status(warp::reply(), status).into_response(),
+    };
+    if let Some(ServerError::MethodNotAllowed(allowed)) = err.find::<ServerError>() {
+        response
+            .headers_mut()
+            .insert(warp::http::header::ALLOW, allow_header(allowed));
+    }
+    if err.find::<api_keys::Unauthorized>().is_some() {
+        response.headers_mut().insert(
+            warp::http::header::WWW_AUTHENTICATE,
+            warp::http::HeaderValue::from_static("Bearer"),
+        );
+    }
+    if let Some(api_keys::MissingScope(scope)) = err.find::<api_keys::MissingScope>() {
+        let challenge = format!(
+            "Bearer error=\"insufficient_scope\", scope=\"{}\"",
+            scope.name()
+        );
+        response.headers_mut().insert(
+            warp::http::header::WWW_AUTHENTICATE,
+            warp::http::HeaderValue::from_str(&challenge).unwrap(),
+        );
+    }
+    let error_event = match err.find::<ServerError>() {
+        Some(ServerError::RenderError { message, .. }) => {
+            Some(reporting::ErrorEvent::new("render_error", message.clone()))
+        }
+        Some(ServerError::HashError(message)) => {
+            Some(reporting::ErrorEvent::new("hash_error", message.clone()))
+        }
+        Some(ServerError::StorageError(message)) => {
+            Some(reporting::ErrorEvent::new("storage_error", message.clone()))
+        }
+        _ => None,
+    };
+    if let Some(event) = error_event {
+        response.extensions_mut().insert(event);
+    }
+    if let Some(debug) = debug {
+        response.extensions_mut().insert(debug);
+    }
+    Ok(response)
+}
+
+#[derive(Debug, Clone)]
+pub struct App {
+    pub config: config::Config,
+    pub users: user::UserDatabase,
+    pub used_tokens: tokens::UsedTokenStore,
+    pub metrics: metrics::Metrics,
+    pub audit: audit::AuditLog,
+    pub avatars: avatars::Avatars,
+    pub waitlist: waitlist::Waitlist,
+    pub limits: limits::Limits,
+    pub maintenance: maintenance::Maintenance,
+}
+
+impl App {
+    pub fn from_config(config: config::Config) -> Self {
+        let ids = config.id_strategy.generator();
+        let avatars = config.avatars.clone();
+        let limits = config.limits.clone();
+        let users = if config.demo {
+            user::UserDatabase::create_test_db(ids)
+        } else {
+            user::UserDatabase::new(ids)
+        };
+        App {
+            config,
+            users,
+            used_tokens: tokens::UsedTokenStore::new(),
+            metrics: metrics::Metrics::new(),
+            audit: audit::AuditLog::new(),
+            avatars,
+            waitlist: waitlist::Waitlist::from_env(),
+            limits,
+            maintenance: maintenance::Maintenance::from_env(),
+        }
+    }
+}
+
+pub fn routes(
+    app: &App,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone {
+    let App {
+        config,
+        users: user_db,
+        used_tokens,
+        metrics,
+        audit,
+        avatars,
+        waitlist,
+        limits,
+        maintenance,
+    } = app.clone();
+    let links = config.links.clone();
+    let reset_throttle = config.reset_throttle.clone();
+    let debug_errors = config.debug_errors;
+    let require_tls = config.require_tls;
+    let page_context = html::PageContext::from_config(&config);
+    let error_page_context = page_context.clone();
+    let debug_page_context = page_context.clone();
+
+    let list = warp::path("list")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(config.gravatar.inject())
+        .and(config.terms.inject())
+        .and(config.password_rotation.inject())
+        .and(
+            warp::query::<ListParams>()
+                .or(warp::any().map(|| ListParams { format: None }))
+                .unify(),
+        )
+        .and(is_htmx())
+        .and(page_context.inject())
+        .and_then(list_handler);
+    let reset_password_generate = warp::path("reset-password-generate")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(reset_throttle.inject())
+        .and(page_context.inject())
+        .and_then(generate_reset_password_handler);
+    let user_detail = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(config.gravatar.inject())
+        .and(page_context.inject())
+        .and_then(user_detail_handler);
+    let username_detail = warp::path("user")
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(config.gravatar.inject())
+        .and(page_context.inject())
+        .and_then(username_detail_handler);
+    let avatar_get = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("avatar"))
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(avatars.inject())
+        .and_then(avatar_get_handler);
+    let reset_password_get = warp::path(&RESET_PASSWORD_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(links.params::<verify::ResetParams>())
+        .and(reset_intent())
+        .and(page_context.inject())
+        .and_then(reset_password_get_handler);
+    let new_user_get = warp::path("new-user")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(waitlist.inject())
+        .and(page_context.inject())
+        .and_then(new_user_get_handler);
+    let waitlist_get = warp::path("waitlist")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(waitlist.inject())
+        .and(page_context.inject())
+        .and_then(waitlist_handler);
+    let create_user_get = warp::path(&CREATE_USER_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(config.terms.inject())
+        .and(page_context.inject())
+        .and_then(create_user_get_handler);
+    let stats_get = warp::path!("admin" / "stats")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(page_context.inject())
+        .and_then(stats_handler);
+    let share_list_get = warp::path!("list" / "share")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(
+            warp::query::<ShareListParams>()
+                .or(warp::any().map(|| ShareListParams { filter: None }))
+                .unify(),
+        )
+        .and(page_context.inject())
+        .and_then(share_list_handler);
+    let shared_list_get = warp::path!("shared" / "list")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(config.gravatar.inject())
+        .and(config.terms.inject())
+        .and(config.password_rotation.inject())
+        .and(links.params::<verify::ShareParams>())
+        .and(page_context.inject())
+        .and_then(shared_list_handler);
+    let unsubscribe_get = warp::path("unsubscribe")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.params::<verify::UnsubscribeParams>())
+        .and(page_context.inject())
+        .and_then(unsubscribe_handler);
+    let verify_email_get = warp::path(&VERIFY_EMAIL_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(links.params::<verify::VerifyEmailParams>())
+        .and(page_context.inject())
+        .and_then(verify_email_handler);
+    let confirm_email_change_get = warp::path(&CONFIRM_EMAIL_CHANGE_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(links.params::<verify::EmailChangeParams>())
+        .and(page_context.inject())
+        .and_then(confirm_email_change_handler);
+    let revert_get = warp::path(&REVERT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(links.params::<verify::RevertParams>())
+        .and(page_context.inject())
+        .and_then(revert_get_handler);
+    let link_report_get = warp::path(&REPORT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(links.params::<verify::ResetParams>())
+        .and(page_context.inject())
+        .and_then(link_report_get_handler);
+    let merge_get = warp::path!("admin" / "merge")
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(page_context.inject())
+        .and_then(merge_get_handler);
+    let maintenance_get = warp::path!("admin" / "maintenance")
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(maintenance.inject())
+        .and(page_context.inject())
+        .and_then(maintenance_get_handler);
+    let report_get = warp::path!("admin" / "report.csv")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(page_context.inject())
+        .and_then(report_handler);
+    let readyz_get = warp::path("readyz")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and_then(readyz_handler);
+    let metrics_get = warp::path("metrics")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(metrics.inject())
+        .and_then(metrics_handler);
+    let events_get = warp::path("events")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(audit.inject())
+        .and(warp::sse::last_event_id::<u64>())
+        .and_then(events_handler);
+    let email_available_get = warp::path!("api" / "email-available")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(user_db.inject())
+        .and(warp::query::<EmailAvailableParams>())
+        .and(is_htmx())
+        .and_then(email_available_handler);
+    let user_api_get = warp::path!("api" / "users" / UserId)
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(config.api_keys.scoped(api_keys::Scope::ReadUsers))
+        .and(user_db.inject())
+        .and(config.terms.inject())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(user_api_handler);
+    let api_keys_get = warp::path("api-keys")
+        .and(warp::path::end())
+        .and(config.api_keys.enabled())
+        .and(allow_methods(FORM_METHODS))
+        .and(get_or_head())
+        .and(config.api_keys.require())
+        .and(config.api_keys.inject())
+        .and(page_context.inject())
+        .and_then(api_keys_handler);
+    let graphql_schema = graphql::schema(
+        user_db.clone(),
+        used_tokens.clone(),
+        audit.clone(),
+        config.features.clone(),
+        config.blocked_names.clone(),
+        links.clone(),
+        waitlist.clone(),
+        reset_throttle.clone(),
+    );
+    let graphql_route = warp::path("graphql")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(FORM_METHODS))
+        .and(async_graphql_warp::graphql(graphql_schema))
+        .and_then(graphql_handler);
+    let security_txt_get = warp::path!(".well-known" / "security.txt")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(config.well_known.inject())
+        .and_then(security_txt_handler);
+    let jwks_get = warp::path!(".well-known" / "jwks.json")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and_then(jwks_handler);
+    let countdown_js_get = warp::path!("static" / "countdown.js")
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and_then(countdown_js_handler);
+    let robots_txt_get = warp::path("robots.txt")
+        .and(warp::path::end())
+        .and(allow_methods(PAGE_METHODS))
+        .and(get_or_head())
+        .and(config.well_known.inject())
+        .and_then(robots_txt_handler);
+
+    let page_gets = list
+        .or(reset_password_generate)
+        .or(user_detail)
+        .or(username_detail)
+        .or(avatar_get)
+        .or(reset_password_get)
+        .or(new_user_get)
+        .or(waitlist_get)
+        .or(create_user_get)
+        .or(stats_get)
+        .or(report_get)
+        .or(share_list_get)
+        .or(shared_list_get)
+        .or(unsubscribe_get)
+        .or(merge_get)
+        .or(maintenance_get)
+        .or(verify_email_get)
+        .or(confirm_email_change_get)
+        .or(revert_get)
+        .or(link_report_get)
+        .map(warp::Reply::into_response)
+        .boxed();
+    let api_gets = readyz_get
+        .or(metrics_get)
+        .or(events_get)
+        .or(email_available_get)
+        .or(user_api_get)
+        .or(api_keys_get)
+        .or(security_txt_get)
+        .or(jwks_get)
+        .or(robots_txt_get)
+        .or(countdown_js_get)
+        .map(warp::Reply::into_response)
+        .boxed();
+    let get_routes = page_gets.or(api_gets).unify();
+
+    let reset_password_post = warp::path(&RESET_PASSWORD_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(used_tokens.inject())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(links.params::<verify::ResetParams>())
+        .and(reset_intent())
+        .and(strict_form::<ResetFormParams>())
+        .and(page_context.inject())
+        .and_then(reset_password_post_handler);
+    let new_user_post = warp::path("new-user")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(links.inject())
+        .and(waitlist.inject())
+        .and(config.allowed_domains.inject())
+        .and(strict_form::<NewUserParams>())
+        .and(page_context.inject())
+        .and_then(new_user_post_handler);
+    let registration_post = warp::path!("waitlist" / "registration")
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(waitlist.inject())
+        .and(strict_form::<RegistrationParams>())
+        .and(page_context.inject())
+        .and_then(registration_post_handler);
+    let waitlist_invite_post = warp::path!("waitlist" / "invite")
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(links.inject())
+        .and(waitlist.inject())
+        .and(strict_form::<WaitlistInviteParams>())
+        .and(page_context.inject())
+        .and_then(waitlist_invite_handler);
+    let create_user_post = warp::path(&CREATE_USER_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(config.features.inject())
+        .and(config.terms.inject())
+        .and(config.allowed_domains.inject())
+        .and(config.blocked_names.inject())
+        .and(links.params::<verify::CreateParams>())
+        .and(is_htmx())
+        .and(strict_form::<CreateUserParams>())
+        .and(page_context.inject())
+        .and_then(create_user_post_handler);
+
+    let avatar_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("avatar"))
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(avatars.inject())
+        .and(config.gravatar.inject())
+        .and(warp::multipart::form().max_length(avatars::MAX_UPLOAD_BYTES))
+        .and(page_context.inject())
+        .and_then(avatar_post_handler);
+    let merge_post = warp::path!("admin" / "merge")
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(strict_form::<MergeRequest>())
+        .and(page_context.inject())
+        .and_then(merge_post_handler);
+    let maintenance_post = warp::path!("admin" / "maintenance")
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(maintenance.inject())
+        .and(strict_form::<MaintenanceParams>())
+        .and(page_context.inject())
+        .and_then(maintenance_post_handler);
+    let merge_confirm_post = warp::path!("admin" / "merge" / "confirm")
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(warp::query::<DryRunParams>())
+        .and(strict_form::<verify::MergeParams>())
+        .and(page_context.inject())
+        .and_then(merge_confirm_handler);
+    let preferences_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(strict_form::<PreferencesParams>())
+        .and(page_context.inject())
+        .and_then(preferences_post_handler);
+    let lock_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("lock"))
+        .and(warp::path::end())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(strict_form::<LockParams>())
+        .and(page_context.inject())
+        .and_then(lock_post_handler);
+    let lock_api_post = warp::path!("api" / "users" / UserId / "lock")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.scoped(api_keys::Scope::ManageUsers))
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.terms.inject())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(json_body::<LockParams>())
+        .and_then(lock_api_handler);
+    let revert_post = warp::path(&REVERT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.params::<verify::RevertParams>())
+        .and(page_context.inject())
+        .and_then(revert_post_handler);
+    let link_report_post = warp::path(&REPORT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(used_tokens.inject())
+        .and(links.inject())
+        .and(links.params::<verify::ResetParams>())
+        .and(page_context.inject())
+        .and_then(link_report_post_handler);
+    let alias_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("aliases"))
+        .and(warp::path::end())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(links.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(strict_form::<EmailAddressParams>())
+        .and(page_context.inject())
+        .and_then(alias_post_handler);
+    let email_change_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("email-change"))
+        .and(warp::path::end())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(links.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(strict_form::<EmailAddressParams>())
+        .and(page_context.inject())
+        .and_then(email_change_post_handler);
+    let email_change_cancel_post = warp::path!("users" / UserId / "email-change" / "cancel")
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(page_context.inject())
+        .and_then(email_change_cancel_handler);
+    let verification_post = warp::path("users")
+        .and(warp::path::param())
+        .and(warp::path("verification"))
+        .and(warp::path::end())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(links.inject())
+        .and(audit.inject())
+        .and(config.gravatar.inject())
+        .and(strict_form::<EmailAddressParams>())
+        .and(page_context.inject())
+        .and_then(verification_post_handler);
+
+    let bulk_post = warp::path!("list" / "bulk")
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(reset_throttle.inject())
+        .and(avatars.inject())
+        .and(warp::query::<DryRunParams>())
+        .and(bulk_form())
+        .and(page_context.inject())
+        .and_then(bulk_handler);
+
+    let reset_links_post = warp::path!("api" / "reset-links")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.scoped(api_keys::Scope::MintTokens))
+        .and(user_db.inject())
+        .and(audit.inject())
+        .and(links.inject())
+        .and(reset_throttle.inject())
+        .and(json_body::<ResetLinksRequest>())
+        .and_then(reset_links_handler);
+    let invites_post = warp::path!("api" / "invites")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.scoped(api_keys::Scope::MintTokens))
+        .and(links.inject())
+        .and(json_body::<InvitesRequest>())
+        .and_then(invites_handler);
+
+    // Minting and revoking client keys takes a service key, so these pages
+    // are for operators with one, not for a browser session.
+    let api_key_create_post = warp::path("api-keys")
+        .and(warp::path::end())
+        .and(config.api_keys.enabled())
+        .and(allow_methods(FORM_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.require())
+        .and(config.api_keys.inject())
+        .and(api_key_form())
+        .and(page_context.inject())
+        .and_then(api_key_create_handler);
+    let api_key_revoke_post = warp::path!("api-keys" / String / "revoke")
+        .and(config.api_keys.enabled())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.require())
+        .and(config.api_keys.inject())
+        .and(page_context.inject())
+        .and_then(api_key_revoke_handler);
+
+    let verify_password_post = warp::path!("api" / "internal" / "verify-password")
+        .and(config.api_keys.enabled())
+        .and(allow_methods(ACTION_METHODS))
+        .and(warp::post())
+        .and(config.api_keys.require())
+        .and(user_db.inject())
+        .and(config.password_rotation.inject())
+        .and(json_body::<VerifyPasswordRequest>())
+        .and_then(verify_password_handler);
+
+    // Only POSTs take a permit, so GETs that miss every route still 404.
+    let post_routes = warp::post()
+        .and(limits.permit())
+        .and(
+            reset_password_post
+                .or(new_user_post)
+                .or(registration_post)
+                .or(waitlist_invite_post)
+                .or(create_user_post)
+                .or(avatar_post)
+                .or(preferences_post)
+                .or(alias_post)
+                .or(revert_post)
+                .or(link_report_post)
+                .or(lock_post)
+                .or(lock_api_post)
+                .or(verification_post)
+                .or(email_change_post)
+                .or(email_change_cancel_post)
+                .or(merge_post)
+                .or(merge_confirm_post)
+                .or(maintenance_post)
+                .or(bulk_post)
+                .or(reset_links_post)
+                .or(invites_post)
+                .or(api_key_create_post)
+                .or(api_key_revoke_post)
+                .or(verify_password_post),
+        )
+        .map(|_permit: limits::Permit, reply| warp::Reply::into_response(reply))
+        .boxed();
+
+    let list_options = warp::path("list")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let bulk_options = warp::path!("list" / "bulk").and(options_reply(ACTION_METHODS));
+    let reset_password_generate_options = warp::path("reset-password-generate")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS))
+        .map(|_, reply| reply);
+    let user_detail_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS))
+        .map(|_, reply| reply);
+    let username_detail_options = warp::path("user")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS))
+        .map(|_, reply| reply);
+    let avatar_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path("avatar"))
+        .and(warp::path::end())
+        .and(options_reply(FORM_METHODS))
+        .map(|_, reply| reply);
+    let preferences_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let alias_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path("aliases"))
+        .and(warp::path::end())
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let verification_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path("verification"))
+        .and(warp::path::end())
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let lock_options = warp::path("users")
+        .and(warp::path::param::<user::UserId>())
+        .and(warp::path("lock"))
+        .and(warp::path::end())
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let lock_api_options = warp::path!("api" / "users" / UserId / "lock")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let revert_options = warp::path(&REVERT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(FORM_METHODS));
+    let link_report_options = warp::path(&REPORT_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(FORM_METHODS));
+    let verify_email_options = warp::path(&VERIFY_EMAIL_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let confirm_email_change_options = warp::path(&CONFIRM_EMAIL_CHANGE_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let email_change_options = warp::path!("users" / UserId / "email-change")
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let email_change_cancel_options = warp::path!("users" / UserId / "email-change" / "cancel")
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let reset_password_options = warp::path(&RESET_PASSWORD_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(FORM_METHODS));
+    let new_user_options = warp::path("new-user")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(options_reply(FORM_METHODS));
+    let waitlist_options = warp::path("waitlist")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(options_reply(PAGE_METHODS));
+    let registration_options = warp::path!("waitlist" / "registration")
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(options_reply(ACTION_METHODS));
+    let waitlist_invite_options = warp::path!("waitlist" / "invite")
+        .and(config.features.require(Feature::OpenRegistration))
+        .and(options_reply(ACTION_METHODS));
+    let create_user_options = warp::path(&CREATE_USER_PATHNAME[1..])
+        .and(warp::path::end())
+        .and(options_reply(FORM_METHODS));
+    let stats_options = warp::path!("admin" / "stats").and(options_reply(PAGE_METHODS));
+    let report_options = warp::path!("admin" / "report.csv").and(options_reply(PAGE_METHODS));
+    let share_list_options = warp::path!("list" / "share").and(options_reply(PAGE_METHODS));
+    let shared_list_options = warp::path!("shared" / "list").and(options_reply(PAGE_METHODS));
+    let merge_options = warp::path!("admin" / "merge").and(options_reply(FORM_METHODS));
+    let maintenance_options = warp::path!("admin" / "maintenance").and(options_reply(FORM_METHODS));
+    let merge_confirm_options =
+        warp::path!("admin" / "merge" / "confirm").and(options_reply(ACTION_METHODS));
+    let unsubscribe_options = warp::path("unsubscribe")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let readyz_options = warp::path("readyz")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let metrics_options = warp::path("metrics")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let events_options = warp::path("events")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+    let email_available_options = warp::path!("api" / "email-available")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(PAGE_METHODS));
+    let reset_links_options = warp::path!("api" / "reset-links")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(ACTION_METHODS));
+    let invites_options = warp::path!("api" / "invites")
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(ACTION_METHODS));
+    let user_api_options = warp::path!("api" / "users" / UserId)
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(PAGE_METHODS))
+        .map(|_, reply| reply);
+    let api_keys_options = warp::path("api-keys")
+        .and(warp::path::end())
+        .and(config.api_keys.enabled())
+        .and(options_reply(FORM_METHODS));
+    let api_key_revoke_options = warp::path!("api-keys" / String / "revoke")
+        .and(config.api_keys.enabled())
+        .and(options_reply(ACTION_METHODS))
+        .map(|_, reply| reply);
+    let verify_password_options = warp::path!("api" / "internal" / "verify-password")
+        .and(config.api_keys.enabled())
+        .and(options_reply(ACTION_METHODS));
+    let graphql_options = warp::path("graphql")
+        .and(warp::path::end())
+        .and(config.features.require(Feature::ApiEnabled))
+        .and(options_reply(FORM_METHODS));
+    let security_txt_options =
+        warp::path!(".well-known" / "security.txt").and(options_reply(PAGE_METHODS));
+    let jwks_options = warp::path!(".well-known" / "jwks.json").and(options_reply(PAGE_METHODS));
+    let countdown_js_options =
+        warp::path!("static" / "countdown.js").and(options_reply(PAGE_METHODS));
+    let robots_txt_options = warp::path("robots.txt")
+        .and(warp::path::end())
+        .and(options_reply(PAGE_METHODS));
+
+    let page_options = list_options
+        .or(bulk_options)
+        .or(reset_password_generate_options)
+        .or(user_detail_options)
+        .or(username_detail_options)
+        .or(avatar_options)
+        .or(preferences_options)
+        .or(alias_options)
+        .or(verification_options)
+        .or(verify_email_options)
+        .or(confirm_email_change_options)
+        .or(email_change_options)
+        .or(email_change_cancel_options)
+        .or(revert_options)
+        .or(link_report_options)
+        .or(lock_options)
+        .or(lock_api_options)
+        .or(reset_password_options)
+        .or(new_user_options)
+        .or(waitlist_options)
+        .or(registration_options)
+        .or(waitlist_invite_options)
+        .or(create_user_options)
+        .or(stats_options)
+        .or(report_options)
+        .or(share_list_options)
+        .or(shared_list_options)
+        .or(unsubscribe_options)
+        .or(merge_options)
+        .or(merge_confirm_options)
+        .or(maintenance_options)
+        .map(warp::Reply::into_response)
+        .boxed();
+    let api_options = readyz_options
+        .or(metrics_options)
+        .or(events_options)
+        .or(email_available_options)
+        .or(reset_links_options)
+        .or(invites_options)
+        .or(user_api_options)
+        .or(api_keys_options)
+        .or(api_key_revoke_options)
+        .or(verify_password_options)
+        .or(graphql_options)
+        .or(security_txt_options)
+        .or(jwks_options)
+        .or(robots_txt_options)
+        .or(countdown_js_options)
+        .map(warp::Reply::into_response)
+        .boxed();
+    let options_routes = page_options.or(api_options);
+
+    // Each group is boxed; otherwise the combined filter type grows past what
+    // rustc will lay out without raising the recursion limit.
+    let routes = maintenance
+        .guard()
+        .and(
+            paths::redirect()
+                .or(get_routes)
+                .unify()
+                .or(post_routes)
+                .or(graphql_route)
+                .or(options_routes),
+        )
+        .recover(move |err| rejection_handler(err, debug_errors, error_page_context.clone()));
+    let slow_threshold = config.slow_request_threshold;
+    let timed_metrics = metrics.clone();
+    let access_log = config.access_log.clone();
+    timing::start()
+        .and(access_log::start())
+        .and(request_line())
+        .and(routes)
+        .map(
+            move |timing: timing::RequestTiming,
+                  logged: access_log::Request,
+                  request: String,
+                  reply| {
+                let mut response = warp::Reply::into_response(reply);
+                if debug_errors {
+                    debug_page_context.render(|| render_debug_error(&mut response, request));
+                }
+                if require_tls {
+                    require_secure_transport(&mut response);
+                }
+                if let Some(id) = logged.id_header() {
+                    response
+                        .headers_mut()
+                        .insert(access_log::REQUEST_ID_HEADER, id);
+                }
+                access_log.record(
+                    &logged,
+                    timing.method(),
+                    timing.route(),
+                    response.status().as_u16(),
+                    timing.elapsed(),
+                );
+                timing.finish(&timed_metrics, slow_threshold, &response);
+                response
+            },
+        )
+}
+
+// Opaque links only live in the memory of the process that minted them, so
+// the CLI can only hand out stateless ones.
+fn print_invites(config: &config::Config, emails: &[String]) {
+    if emails.is_empty() {
+        eprintln!("usage: no-db-verify invite <email>...");
+        std::process::exit(2);
+    }
+    if config.links.is_opaque() {
+        eprintln!("invite links cannot be minted from the CLI when APP_LINK_MODE=opaque");
+        std::process::exit(1);
+    }
+    for email in emails {
+        let params = verify::CreateParams::invite(email);
+        match html::create_url(CREATE_USER_PATHNAME, Some(&params)) {
+            Ok(link) => println!("{}\t{}", email, link),
+            Err(err) => {
+                eprintln!("could not create an invite link for {}: {:?}", email, err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn refuse_to_start() -> ! {
+    eprintln!("refusing to start until the errors above are fixed");
+    std::process::exit(1);
+}
+
+pub async fn run() {
+    let config = config::Config::from_env().unwrap_or_else(|errors| {
+        eprint!("{}", preflight::Report::from_errors(errors));
+        refuse_to_start()
+    });
+    reporting::install(config.reporter.build());
+    verify::set_token_policy(config.token_policy);
+    html::set_display_zone(config.display_zone);
+    let secret = secrets::CachedSecret::from_env(config.backends).unwrap_or_else(|err| {
+        eprintln!("invalid secret configuration: {}", err);
+        std::process::exit(1);
+    });
+    let key = secret.get().await.unwrap_or_else(|err| {
+        eprintln!(
+            "could not load secret key from {}: {}",
+            secret.provider_name(),
+            err
+        );
+        std::process::exit(1);
+    });
+    let report = preflight::check(&config, &key, secret.ttl(), &config.avatars).await;
+    if !report.is_empty() {
+        eprint!("{}", report);
+    }
+    if report.is_fatal() {
+        refuse_to_start();
+    }
+    verify::set_secret_key(key);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("invite") {
+        print_invites(&config, &args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("self-test") {
+        hashing::install(metrics::Metrics::new(), config.bcrypt_cost);
+        let checks = self_test::run(&config).await;
+        for check in &checks {
+            println!("{}", check);
+        }
+        if !checks.iter().all(self_test::Check::passed) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let app = App::from_config(config);
+    shadow::install(shadow::ShadowVerifier::from_env(app.metrics.clone()));
+    hashing::install(app.metrics.clone(), app.config.bcrypt_cost);
+
+    let mut jobs = jobs::JobRunner::new();
+    let cleanup_tokens = app.used_tokens.clone();
+    let cleanup_links = app.config.links.clone();
+    let cleanup_metrics = app.metrics.clone();
+    jobs.every(CLEANUP_PERIOD, move || {
+        cleanup_job(
+            cleanup_tokens.clone(),
+            cleanup_links.clone(),
+            cleanup_metrics.clone(),
+        )
+    });
+    if app.config.password_rotation.is_enabled() {
+        let rotation_db = app.users.clone();
+        let rotation_audit = app.audit.clone();
+        let rotation_links = app.config.links.clone();
+        let rotation_metrics = app.metrics.clone();
+        let rotation = app.config.password_rotation;
+        jobs.every(ROTATION_CHECK_PERIOD, move || {
+            rotation_job(
+                rotation_db.clone(),
+                rotation_audit.clone(),
+                rotation_links.clone(),
+                rotation_metrics.clone(),
+                rotation,
+            )
+        });
+    }
+    if app.config.demo {
+        let seeded_db = app.users.clone();
+        jobs.every(RESEED_PERIOD, move || {
+            let seeded_db = seeded_db.clone();
+            async move { seeded_db.reseed().await }
+        });
+    }
+    let secret = std::sync::Arc::new(secret);
+    jobs.every(secret.ttl(), move || {
+        let secret = secret.clone();
+        async move {
+            if let Ok(key) = secret.refresh().await {
+                verify::set_secret_key(key);
+            }
+        }
+    });
+    jobs.start();
+
+    #[cfg(feature = "grpc")]
+    {
+        let service = grpc::VerifyService::new(
+            app.users.clone(),
+            app.used_tokens.clone(),
+            app.audit.clone(),
+            app.config.links.clone(),
+            app.config.reset_throttle.clone(),
+            app.config.blocked_names.clone(),
+            app.config.features.clone(),
+        );
+        let addr = app.config.grpc_addr;
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(addr, service).await {
+                eprintln!("gRPC server stopped: {}", err);
+            }
+        });
+    }
+
+    let addr = ([127, 0, 0, 1], 3232).into();
+    let service = app.limits.with_timeouts(warp::service(routes(&app)));
+    if let Err(err) = panics::serve(addr, service).await {
+        eprintln!("server stopped: {}", err);
+    }
+}
@@ -0,0 +1,1482 @@
+use crate::audit::{AuditKind, AuditLog};
+use crate::avatars::{AvatarError, Avatars, Gravatar};
+use crate::bulk::{self, BulkAction, BulkOutcome, BulkParams};
+use crate::domains::AllowedDomains;
+use crate::html::UrlError;
+use crate::identity;
+use crate::links::Links;
+use crate::maintenance::Maintenance;
+use crate::names::NameBlocklist;
+use crate::notify;
+use crate::pii::Email;
+use crate::reporting::{self, ErrorEvent};
+use crate::rotation::{PasswordAge, PasswordRotation};
+use crate::sanitize;
+use crate::server::{
+    CONFIRM_EMAIL_CHANGE_PATHNAME, CREATE_USER_PATHNAME, REPORT_PATHNAME, RESET_PASSWORD_PATHNAME,
+    REVERT_PATHNAME, SHARED_LIST_PATHNAME, VERIFY_EMAIL_PATHNAME,
+};
+use crate::stats::{self, Stats};
+use crate::terms::Terms;
+use crate::throttle::{ResetThrottle, Throttled};
+use crate::tokens::UsedTokenStore;
+use crate::user::{
+    self, EmailChangeSide, NotificationPreferences, NotificationTopic, PendingEmailChange, User,
+    UserBuilder, UserDatabase, UserError, UserId,
+};
+use crate::verify::{
+    CreateParams, EmailChangeParams, MergeParams, ResetParams, RevertParams, ShareParams,
+    UnsubscribeParams, UtcDateTime, VerifyEmailParams,
+};
+use crate::waitlist::{Waitlist, WaitlistEntry};
+use serde::Serialize;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    NotFound,
+    BadRequest,
+    Hash(String),
+    Storage(String),
+    Url(UrlError),
+    // The caller's copy of the record is out of date.
+    PreconditionFailed,
+}
+
+impl From<UrlError> for ServiceError {
+    fn from(err: UrlError) -> Self {
+        ServiceError::Url(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum PageOutcome {
+    ConfirmReset {
+        user: User,
+        continue_link: String,
+        intent: String,
+        refused: bool,
+        expires: UtcDateTime,
+    },
+    ResetForm {
+        user: User,
+        error: Option<&'static str>,
+        expires: UtcDateTime,
+    },
+    PasswordReset {
+        user: User,
+        success: bool,
+    },
+    ResetLink {
+        user: User,
+        link: String,
+        report_link: String,
+        expires: UtcDateTime,
+    },
+    ResetLinkThrottled {
+        throttled: Throttled,
+    },
+    UserDetail {
+        user: User,
+        gravatar: Gravatar,
+    },
+    AvatarNotice {
+        user: User,
+        gravatar: Gravatar,
+        saved: bool,
+        message: String,
+    },
+    PreferencesSaved {
+        user: User,
+        gravatar: Gravatar,
+    },
+    Unsubscribed {
+        topic: NotificationTopic,
+        success: bool,
+    },
+    // A link to confirm one of the user's addresses, or why it wasn't made.
+    VerificationLink {
+        user: User,
+        gravatar: Gravatar,
+        link: Result<(String, UtcDateTime), &'static str>,
+    },
+    LockChanged {
+        user: User,
+        gravatar: Gravatar,
+    },
+    // A link or password for a locked account was refused.
+    AccountLocked,
+    // `None` when the reported link was bad or has expired.
+    Report {
+        user: Option<User>,
+        reported: bool,
+    },
+    // `None` when the revert link was bad, has expired or was already used.
+    Revert {
+        user: Option<User>,
+        reverted: bool,
+    },
+    // Where a change of primary address stands after a request, a
+    // cancellation or one of its links being followed.
+    EmailChangeNotice {
+        user: User,
+        gravatar: Gravatar,
+        success: bool,
+        message: &'static str,
+    },
+    // Both confirmation links of a new change went out; they stop working at
+    // `expires`.
+    EmailChangeLinksSent {
+        user: User,
+        gravatar: Gravatar,
+        expires: UtcDateTime,
+    },
+    // The confirmation link was bad, has expired or belongs to a change that
+    // was since replaced or cancelled.
+    EmailChangeRejected,
+    // `None` when the verification link was bad or has expired.
+    EmailVerified {
+        user: Option<User>,
+        gravatar: Gravatar,
+    },
+    MergeForm {
+        users: Vec<User>,
+        error: Option<&'static str>,
+    },
+    MergeConfirm {
+        primary: User,
+        duplicate: User,
+        params: MergeParams,
+    },
+    Merged {
+        primary: User,
+        duplicate: User,
+        moved_events: usize,
+    },
+    MergePreview {
+        primary: User,
+        duplicate: User,
+        params: MergeParams,
+        moved_events: usize,
+    },
+    Invite {
+        email: Email,
+        link: String,
+        expires: UtcDateTime,
+    },
+    Waitlisted {
+        email: Email,
+    },
+    SignupRejected {
+        message: String,
+        open: bool,
+    },
+    Maintenance {
+        on: bool,
+        notice: Option<&'static str>,
+    },
+    Waitlist {
+        open: bool,
+        waiting: Vec<WaitlistEntry>,
+        notice: Option<String>,
+        invites: Vec<(Email, String)>,
+        expires: Option<UtcDateTime>,
+    },
+    CreateUserForm {
+        errors: Vec<&'static str>,
+        terms: Terms,
+    },
+    UserCreated {
+        success: bool,
+    },
+    BulkRejected {
+        action: BulkAction,
+        outcomes: Vec<BulkOutcome>,
+    },
+    BulkApplied {
+        action: BulkAction,
+        outcomes: Vec<BulkOutcome>,
+    },
+    BulkPreview {
+        action: BulkAction,
+        selected: Vec<UserId>,
+        outcomes: Vec<BulkOutcome>,
+    },
+    Export {
+        csv: String,
+        filename: String,
+    },
+    Stats {
+        stats: Stats,
+    },
+    ShareLink {
+        link: String,
+        filter: Option<String>,
+        expires: UtcDateTime,
+    },
+}
+
+#[derive(Debug)]
+pub struct ResetIntent {
+    pub query: String,
+    pub cookie: Option<String>,
+}
+
+impl ResetIntent {
+    fn is_confirmed(&self, params: &ResetParams) -> bool {
+        self.cookie
+            .as_deref()
+            .is_some_and(|cookie| params.verify_intent(cookie))
+    }
+
+    fn continue_link(&self) -> String {
+        let query: Vec<&str> = self
+            .query
+            .split('&')
+            .filter(|pair| !pair.is_empty() && *pair != "confirm=1")
+            .collect();
+        format!("{}?{}&confirm=1", RESET_PASSWORD_PATHNAME, query.join("&"))
+    }
+
+    fn wants_form(&self) -> bool {
+        self.query.split('&').any(|pair| pair == "confirm=1")
+    }
+
+    fn confirmation(&self, user: &User, params: &ResetParams, refused: bool) -> PageOutcome {
+        PageOutcome::ConfirmReset {
+            user: user.clone(),
+            continue_link: self.continue_link(),
+            intent: params.intent(),
+            refused,
+            expires: params.expires(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResetLinkReply {
+    user: String,
+    user_id: UserId,
+    link: String,
+    report_link: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResetLinksReply {
+    links: Vec<ResetLinkReply>,
+    not_found: Vec<String>,
+    throttled: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteLinkReply {
+    email: Email,
+    link: String,
+    expires: UtcDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteLinksReply {
+    links: Vec<InviteLinkReply>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserReply {
+    id: UserId,
+    pub version: u64,
+    name: String,
+    username: String,
+    email: Email,
+    has_avatar: bool,
+    lookalike_of: Option<UserId>,
+    accepted_tos_version: Option<String>,
+    needs_tos_acceptance: bool,
+    locked: bool,
+}
+
+impl UserReply {
+    fn from_user(user: &User, terms: &Terms) -> Self {
+        UserReply {
+            id: user.id,
+            version: user.version,
+            name: user.name.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            has_avatar: user.has_avatar,
+            lookalike_of: user.lookalike_of,
+            accepted_tos_version: user
+                .tos_accepted
+                .as_ref()
+                .map(|accepted| accepted.version.clone()),
+            needs_tos_acceptance: terms.needs_acceptance(user),
+            locked: user.is_locked(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SignupPolicy<'a> {
+    pub terms: &'a Terms,
+    pub domains: &'a AllowedDomains,
+    pub names: &'a NameBlocklist,
+    pub screen_lookalikes: bool,
+}
+
+#[derive(Debug)]
+pub struct SignupForm<'a> {
+    pub name: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub accepted_tos: bool,
+}
+
+// `name` and `username` are expected to have been through `sanitize::text`.
+pub fn create_user_errors(name: &str, username: &str, password: &str) -> Vec<&'static str> {
+    let mut errors = Vec::new();
+    if name.is_empty() {
+        errors.push("Name is required.");
+    } else if let Some(error) = sanitize::NAME.check(name) {
+        errors.push(error);
+    } else if identity::has_hidden_characters(name) {
+        errors.push("Name can't contain invisible or text-direction characters.");
+    }
+    if username.is_empty() {
+        errors.push("Username is required.");
+    } else if let Some(error) = user::username_error(username) {
+        errors.push(error);
+    }
+    if let Some(error) = sanitize::password_error(password) {
+        errors.push(error);
+    }
+    errors
+}
+
+pub async fn reset_form(
+    db: &UserDatabase,
+    params: &ResetParams,
+    intent: &ResetIntent,
+) -> Result<PageOutcome, ServiceError> {
+    let users = db.lock().await;
+    let user = users.get(&params.user_id()).ok_or(ServiceError::NotFound)?;
+    if user.is_locked() {
+        return Ok(PageOutcome::AccountLocked);
+    }
+    if !(intent.wants_form() && intent.is_confirmed(params)) {
+        return Ok(intent.confirmation(user, params, false));
+    }
+    Ok(PageOutcome::ResetForm {
+        user: user.clone(),
+        error: None,
+        expires: params.expires(),
+    })
+}
+
+pub async fn reset_password(
+    db: &UserDatabase,
+    used_tokens: &UsedTokenStore,
+    audit: &AuditLog,
+    links: &Links,
+    params: &ResetParams,
+    intent: &ResetIntent,
+    new_password: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let mut users = db.lock().await;
+    let user = users
+        .get_mut(&params.user_id())
+        .ok_or(ServiceError::NotFound)?;
+    if user.is_locked() {
+        return Ok(PageOutcome::AccountLocked);
+    }
+    if !intent.is_confirmed(params) {
+        return Ok(intent.confirmation(user, params, true));
+    }
+    if let Some(error) = sanitize::password_error(new_password) {
+        return Ok(PageOutcome::ResetForm {
+            user: user.clone(),
+            error: Some(error),
+            expires: params.expires(),
+        });
+    }
+    let success = ResetParams::verify(user, params) && used_tokens.consume(params).await;
+    if success {
+        user.reset_password(new_password)
+            .map_err(|err| ServiceError::Hash(err.to_string()))?;
+        audit.record(AuditKind::PasswordReset, user.id);
+        links.spend(params).await;
+        send_revert_link(links, audit, user).await?;
+    } else {
+        audit.record(AuditKind::ResetTokenRejected, user.id);
+    }
+    Ok(PageOutcome::PasswordReset {
+        user: user.clone(),
+        success,
+    })
+}
+
+// "Was this you?" to the account's address after its password changes.
+pub async fn send_revert_link(
+    links: &Links,
+    audit: &AuditLog,
+    user: &User,
+) -> Result<(), UrlError> {
+    let link = links
+        .url(REVERT_PATHNAME, &RevertParams::from(user))
+        .await?;
+    notify::send(&user.email, "Your password was reset. Was this you?", &link);
+    audit.record(AuditKind::RevertLinkSent, user.id);
+    Ok(())
+}
+
+pub async fn revert_page(db: &UserDatabase, params: &RevertParams) -> PageOutcome {
+    let users = db.lock().await;
+    let user = users
+        .get(&params.user_id())
+        .filter(|user| RevertParams::verify(user, params));
+    if user.is_some_and(User::is_locked) {
+        return PageOutcome::AccountLocked;
+    }
+    PageOutcome::Revert {
+        user: user.cloned(),
+        reverted: false,
+    }
+}
+
+pub async fn revert_reset(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    params: &RevertParams,
+) -> PageOutcome {
+    let mut users = db.lock().await;
+    let user = users
+        .get_mut(&params.user_id())
+        .filter(|user| RevertParams::verify(user, params));
+    match user {
+        Some(user) if user.is_locked() => PageOutcome::AccountLocked,
+        Some(user) if user.previous_password.is_some() => {
+            user.revert_password();
+            audit.record(AuditKind::PasswordResetReverted, user.id);
+            PageOutcome::Revert {
+                user: Some(user.clone()),
+                reverted: true,
+            }
+        }
+        _ => PageOutcome::Revert {
+            user: None,
+            reverted: false,
+        },
+    }
+}
+
+pub async fn generate_reset_link(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    links: &Links,
+    throttle: &ResetThrottle,
+    id: UserId,
+) -> Result<PageOutcome, ServiceError> {
+    let users = db.lock().await;
+    let user = users.get(&id).ok_or(ServiceError::NotFound)?;
+    if let Err(throttled) = throttle.issue(user.id) {
+        return Ok(PageOutcome::ResetLinkThrottled { throttled });
+    }
+    let params = ResetParams::from(user);
+    audit.record(AuditKind::ResetLinkGenerated, user.id);
+    let link = links.url(RESET_PASSWORD_PATHNAME, &params).await?;
+    Ok(PageOutcome::ResetLink {
+        user: user.clone(),
+        link,
+        report_link: links.url(REPORT_PATHNAME, &params).await?,
+        expires: params.expires(),
+    })
+}
+
+pub async fn report_page(db: &UserDatabase, params: &ResetParams) -> PageOutcome {
+    let users = db.lock().await;
+    let user = users
+        .get(&params.user_id())
+        .filter(|user| ResetParams::verify(user, params));
+    PageOutcome::Report {
+        user: user.cloned(),
+        reported: false,
+    }
+}
+
+// For a reset link the recipient never asked for. It is spent as if it had
+// been used, so nobody can finish the reset with it, and the account is
+// flagged for an admin to look at.
+pub async fn report_link(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    used_tokens: &UsedTokenStore,
+    links: &Links,
+    params: &ResetParams,
+) -> PageOutcome {
+    let mut users = db.lock().await;
+    let user = match users
+        .get_mut(&params.user_id())
+        .filter(|user| ResetParams::verify(user, params))
+    {
+        Some(user) => user,
+        None => {
+            return PageOutcome::Report {
+                user: None,
+                reported: false,
+            }
+        }
+    };
+    used_tokens.consume(params).await;
+    links.spend(params).await;
+    user.reported_at = Some(chrono::Utc::now());
+    user.touch();
+    audit.record(AuditKind::LinkReported, user.id);
+    PageOutcome::Report {
+        user: Some(user.clone()),
+        reported: true,
+    }
+}
+
+pub async fn user_detail(
+    db: &UserDatabase,
+    gravatar: Gravatar,
+    id: UserId,
+) -> Result<PageOutcome, ServiceError> {
+    let users = db.lock().await;
+    // Old links to a merged-away account land on the account it became.
+    let id = users.merged(&id).map_or(id, |merged| merged.into);
+    let user = users.get(&id).ok_or(ServiceError::NotFound)?;
+    Ok(PageOutcome::UserDetail {
+        user: user.clone(),
+        gravatar,
+    })
+}
+
+pub async fn user_detail_by_username(
+    db: &UserDatabase,
+    gravatar: Gravatar,
+    username: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let users = db.lock().await;
+    let user = users
+        .find_by_username(username)
+        .ok_or(ServiceError::NotFound)?;
+    Ok(PageOutcome::UserDetail {
+        user: user.clone(),
+        gravatar,
+    })
+}
+
+pub async fn user_reply(
+    db: &UserDatabase,
+    terms: &Terms,
+    id: UserId,
+) -> Result<UserReply, ServiceError> {
+    let users = db.lock().await;
+    let user = users.get(&id).ok_or(ServiceError::NotFound)?;
+    Ok(UserReply::from_user(user, terms))
+}
+
+fn record_lock(audit: &AuditLog, user: &User) {
+    let kind = if user.is_locked() {
+        AuditKind::AccountLocked
+    } else {
+        AuditKind::AccountUnlocked
+    };
+    audit.record(kind, user.id);
+}
+
+pub async fn set_locked(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+    locked: bool,
+) -> Result<PageOutcome, ServiceError> {
+    let mut users = db.lock().await;
+    let user = users.get_mut(&id).ok_or(ServiceError::NotFound)?;
+    if user.set_locked(locked) {
+        record_lock(audit, user);
+    }
+    Ok(PageOutcome::LockChanged {
+        user: user.clone(),
+        gravatar,
+    })
+}
+
+pub async fn set_locked_reply(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    terms: &Terms,
+    id: UserId,
+    locked: bool,
+    version_matches: impl FnOnce(u64) -> bool,
+) -> Result<UserReply, ServiceError> {
+    let mut users = db.lock().await;
+    let user = users.get_mut(&id).ok_or(ServiceError::NotFound)?;
+    if !version_matches(user.version) {
+        return Err(ServiceError::PreconditionFailed);
+    }
+    if user.set_locked(locked) {
+        record_lock(audit, user);
+    }
+    Ok(UserReply::from_user(user, terms))
+}
+
+pub async fn require_user(db: &UserDatabase, id: UserId) -> Result<(), ServiceError> {
+    db.lock()
+        .await
+        .get(&id)
+        .map(|_| ())
+        .ok_or(ServiceError::NotFound)
+}
+
+pub async fn save_avatar(
+    db: &UserDatabase,
+    avatars: &Avatars,
+    gravatar: Gravatar,
+    id: UserId,
+    upload: Result<Vec<u8>, AvatarError>,
+) -> Result<PageOutcome, ServiceError> {
+    let saved = match upload {
+        Ok(image) => avatars.save(id, &image).await,
+        Err(err) => Err(err),
+    };
+    let mut users = db.lock().await;
+    let user = users.get_mut(&id).ok_or(ServiceError::NotFound)?;
+    let (saved, message) = match saved {
+        Ok(()) => {
+            user.has_avatar = true;
+            user.touch();
+            (true, "Avatar updated!".to_string())
+        }
+        Err(AvatarError::Storage(err)) => return Err(ServiceError::Storage(err.to_string())),
+        Err(err) => (false, format!("Sorry, {}.", err)),
+    };
+    Ok(PageOutcome::AvatarNotice {
+        user: user.clone(),
+        gravatar,
+        saved,
+        message,
+    })
+}
+
+pub async fn save_preferences(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+    preferences: NotificationPreferences,
+) -> Result<PageOutcome, ServiceError> {
+    let mut users = db.lock().await;
+    let user = users.get_mut(&id).ok_or(ServiceError::NotFound)?;
+    if user.preferences != preferences {
+        user.preferences = preferences;
+        user.touch();
+        audit.record(AuditKind::PreferencesChanged, user.id);
+    }
+    Ok(PageOutcome::PreferencesSaved {
+        user: user.clone(),
+        gravatar,
+    })
+}
+
+fn email_error(email: &str) -> Option<&'static str> {
+    if email.is_empty() {
+        Some("Email is required.")
+    } else if identity::has_hidden_characters(email) {
+        Some("That email address contains invisible or text-direction characters.")
+    } else {
+        sanitize::EMAIL.check(email)
+    }
+}
+
+async fn verification_link(
+    links: &Links,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    user: User,
+    email: Email,
+) -> Result<PageOutcome, ServiceError> {
+    let params = VerifyEmailParams::new(user.id, email);
+    let link = links.url(VERIFY_EMAIL_PATHNAME, &params).await?;
+    audit.record(AuditKind::EmailVerificationSent, user.id);
+    Ok(PageOutcome::VerificationLink {
+        user,
+        gravatar,
+        link: Ok((link, params.expires())),
+    })
+}
+
+pub async fn add_email_alias(
+    db: &UserDatabase,
+    links: &Links,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+    email: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let email = identity::normalize(&sanitize::text(email));
+    let mut users = db.lock().await;
+    let added = match email_error(&email) {
+        Some(error) => Err(error),
+        None => match users.add_alias(id, &email).ok_or(ServiceError::NotFound)? {
+            Ok(user) => Ok(user),
+            Err(UserError::EmailTaken) => Err("That email is already registered."),
+            Err(_) => return Err(ServiceError::BadRequest),
+        },
+    };
+    match added {
+        Ok(user) => {
+            drop(users);
+            audit.record(AuditKind::EmailAliasAdded, user.id);
+            verification_link(links, audit, gravatar, user, Email::new(email)).await
+        }
+        Err(error) => Ok(PageOutcome::VerificationLink {
+            user: users.get(&id).ok_or(ServiceError::NotFound)?.clone(),
+            gravatar,
+            link: Err(error),
+        }),
+    }
+}
+
+// Mints a fresh link for any address already on the account, primary or
+// alias, e.g. after the first one expired.
+pub async fn resend_verification(
+    db: &UserDatabase,
+    links: &Links,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+    email: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let user = db
+        .lock()
+        .await
+        .get(&id)
+        .ok_or(ServiceError::NotFound)?
+        .clone();
+    let email = Email::new(identity::normalize(&sanitize::text(email)));
+    if !user.owns_email(&email) {
+        return Err(ServiceError::BadRequest);
+    }
+    verification_link(links, audit, gravatar, user, email).await
+}
+
+pub async fn verify_email(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    params: &VerifyEmailParams,
+) -> PageOutcome {
+    if !VerifyEmailParams::verify(params) {
+        return PageOutcome::EmailVerified {
+            user: None,
+            gravatar,
+        };
+    }
+    let mut users = db.lock().await;
+    let user = users
+        .get_mut(&params.user_id())
+        .filter(|user| user.owns_email(params.email()));
+    let user = match user {
+        Some(user) if user.is_locked() => return PageOutcome::AccountLocked,
+        Some(user) => user,
+        None => {
+            return PageOutcome::EmailVerified {
+                user: None,
+                gravatar,
+            }
+        }
+    };
+    let before = user.version;
+    user.mark_verified(params.email());
+    if user.version != before {
+        audit.record(AuditKind::EmailVerified, user.id);
+    }
+    PageOutcome::EmailVerified {
+        user: Some(user.clone()),
+        gravatar,
+    }
+}
+
+// Each address gets its own link. The one to the current address doubles as
+// the "was this you?" notice: leaving it unfollowed is enough to stop the
+// change. Returns when the links expire.
+async fn send_email_change_links(
+    links: &Links,
+    audit: &AuditLog,
+    user: &User,
+    pending: &PendingEmailChange,
+) -> Result<UtcDateTime, ServiceError> {
+    let messages = [
+        (
+            EmailChangeSide::Old,
+            &user.email,
+            "Someone asked to move your account to a new email address. Was this you?",
+        ),
+        (
+            EmailChangeSide::New,
+            &pending.email,
+            "Confirm your new email address",
+        ),
+    ]
+    .map(|(side, to, subject)| (EmailChangeParams::new(user.id, side, pending), to, subject));
+    for (params, to, subject) in messages.iter() {
+        let link = links.url(CONFIRM_EMAIL_CHANGE_PATHNAME, params).await?;
+        notify::send(to, subject, &link);
+    }
+    audit.record(AuditKind::EmailChangeRequested, user.id);
+    Ok(messages[0].0.expires())
+}
+
+pub async fn request_email_change(
+    db: &UserDatabase,
+    links: &Links,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+    email: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let email = identity::normalize(&sanitize::text(email));
+    let mut users = db.lock().await;
+    if users.get(&id).ok_or(ServiceError::NotFound)?.is_locked() {
+        return Ok(PageOutcome::AccountLocked);
+    }
+    let requested = match email_error(&email) {
+        Some(error) => Err(error),
+        None => match users
+            .request_email_change(id, &email)
+            .ok_or(ServiceError::NotFound)?
+        {
+            Ok(user) => Ok(user),
+            Err(UserError::EmailTaken) => Err("That email is already registered."),
+            Err(_) => return Err(ServiceError::BadRequest),
+        },
+    };
+    match requested {
+        Ok(user) => {
+            drop(users);
+            let pending = user
+                .pending_email_change
+                .clone()
+                .ok_or(ServiceError::BadRequest)?;
+            let expires = send_email_change_links(links, audit, &user, &pending).await?;
+            Ok(PageOutcome::EmailChangeLinksSent {
+                user,
+                gravatar,
+                expires,
+            })
+        }
+        Err(error) => Ok(PageOutcome::EmailChangeNotice {
+            user: users.get(&id).ok_or(ServiceError::NotFound)?.clone(),
+            gravatar,
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+pub async fn cancel_email_change(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    id: UserId,
+) -> Result<PageOutcome, ServiceError> {
+    let mut users = db.lock().await;
+    let pending = users
+        .get(&id)
+        .ok_or(ServiceError::NotFound)?
+        .pending_email_change
+        .is_some();
+    let user = users
+        .cancel_email_change(id)
+        .ok_or(ServiceError::NotFound)?;
+    if pending {
+        audit.record(AuditKind::EmailChangeCancelled, id);
+    }
+    Ok(PageOutcome::EmailChangeNotice {
+        user,
+        gravatar,
+        success: true,
+        message: "Email change cancelled.",
+    })
+}
+
+pub async fn confirm_email_change(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    gravatar: Gravatar,
+    params: &EmailChangeParams,
+) -> PageOutcome {
+    if !EmailChangeParams::verify(params) {
+        return PageOutcome::EmailChangeRejected;
+    }
+    let mut users = db.lock().await;
+    match users.get(&params.user_id()) {
+        Some(user) if user.is_locked() => return PageOutcome::AccountLocked,
+        Some(_) => {}
+        None => return PageOutcome::EmailChangeRejected,
+    }
+    let (user, applied) =
+        match users.confirm_email_change(params.user_id(), params.side(), params.requested_at()) {
+            Some(confirmed) => confirmed,
+            None => return PageOutcome::EmailChangeRejected,
+        };
+    audit.record(AuditKind::EmailChangeConfirmed, user.id);
+    let message = if applied {
+        audit.record(AuditKind::EmailChanged, user.id);
+        "Email address changed."
+    } else {
+        "Confirmed. The change applies once the other address confirms too."
+    };
+    PageOutcome::EmailChangeNotice {
+        user,
+        gravatar,
+        success: true,
+        message,
+    }
+}
+
+pub async fn unsubscribe(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    params: &UnsubscribeParams,
+) -> Result<PageOutcome, ServiceError> {
+    let topic = params.topic();
+    if !UnsubscribeParams::verify(params) {
+        return Ok(PageOutcome::Unsubscribed {
+            topic,
+            success: false,
+        });
+    }
+    let mut users = db.lock().await;
+    let user = users
+        .get_mut(&params.user_id())
+        .ok_or(ServiceError::NotFound)?;
+    let before = user.preferences;
+    user.preferences.opt_out(topic);
+    if user.preferences != before {
+        user.touch();
+        audit.record(AuditKind::PreferencesChanged, user.id);
+    }
+    Ok(PageOutcome::Unsubscribed {
+        topic,
+        success: true,
+    })
+}
+
+async fn merge_form(db: &UserDatabase, error: Option<&'static str>) -> PageOutcome {
+    let users = db.lock().await;
+    let mut users: Vec<User> = users.values().cloned().collect();
+    users.sort_unstable_by_key(|user| user.id);
+    PageOutcome::MergeForm { users, error }
+}
+
+pub async fn merge_page(db: &UserDatabase) -> PageOutcome {
+    merge_form(db, None).await
+}
+
+pub async fn request_merge(
+    db: &UserDatabase,
+    primary: UserId,
+    duplicate: UserId,
+) -> Result<PageOutcome, ServiceError> {
+    if primary == duplicate {
+        return Ok(merge_form(db, Some("Pick two different accounts to merge.")).await);
+    }
+    let users = db.lock().await;
+    let (primary, duplicate) = match (users.get(&primary), users.get(&duplicate)) {
+        (Some(primary), Some(duplicate)) => (primary.clone(), duplicate.clone()),
+        _ => return Err(ServiceError::NotFound),
+    };
+    let params = MergeParams::new(primary.id, duplicate.id);
+    Ok(PageOutcome::MergeConfirm {
+        primary,
+        duplicate,
+        params,
+    })
+}
+
+// With `dry_run` the signed choice is checked and the merge is described,
+// but nothing is moved and the confirmation stays usable.
+pub async fn merge(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    params: &MergeParams,
+    dry_run: bool,
+) -> Result<PageOutcome, ServiceError> {
+    if !MergeParams::verify(params) {
+        return Ok(merge_form(
+            db,
+            Some("That confirmation has expired. Please pick the accounts again."),
+        )
+        .await);
+    }
+    if dry_run {
+        let users = db.lock().await;
+        let (primary, duplicate) =
+            match (users.get(&params.primary()), users.get(&params.duplicate())) {
+                (Some(primary), Some(duplicate)) if primary.id != duplicate.id => {
+                    (primary.clone(), duplicate.clone())
+                }
+                _ => {
+                    drop(users);
+                    return Ok(
+                        merge_form(db, Some("One of those accounts no longer exists.")).await,
+                    );
+                }
+            };
+        let moved_events = audit
+            .events()
+            .iter()
+            .filter(|event| event.user_id == Some(duplicate.id))
+            .count();
+        return Ok(PageOutcome::MergePreview {
+            primary,
+            duplicate,
+            params: params.clone(),
+            moved_events,
+        });
+    }
+    let merged = db.lock().await.merge(params.primary(), params.duplicate());
+    let (primary, duplicate) = match merged {
+        Some(merged) => merged,
+        None => {
+            return Ok(merge_form(db, Some("One of those accounts no longer exists.")).await);
+        }
+    };
+    let moved_events = audit.reassign(duplicate.id, primary.id);
+    audit.record(AuditKind::AccountsMerged, primary.id);
+    Ok(PageOutcome::Merged {
+        primary,
+        duplicate,
+        moved_events,
+    })
+}
+
+pub async fn invite(
+    links: &Links,
+    waitlist: &Waitlist,
+    domains: &AllowedDomains,
+    email: &str,
+) -> Result<PageOutcome, ServiceError> {
+    let email = sanitize::text(email);
+    if let Some(error) = sanitize::EMAIL.check(&email) {
+        return Ok(PageOutcome::SignupRejected {
+            message: error.to_string(),
+            open: waitlist.is_open().await,
+        });
+    }
+    if identity::has_hidden_characters(&email) {
+        return Ok(PageOutcome::SignupRejected {
+            message: "That email address contains invisible or text-direction characters."
+                .to_string(),
+            open: waitlist.is_open().await,
+        });
+    }
+    let email = identity::normalize(&email);
+    let email = email.as_str();
+    if !domains.allows(email) {
+        return Ok(PageOutcome::SignupRejected {
+            message: domains.rejection(),
+            open: waitlist.is_open().await,
+        });
+    }
+    if !waitlist.is_open().await {
+        if email.is_empty() {
+            return Err(ServiceError::BadRequest);
+        }
+        waitlist.join(email).await;
+        return Ok(PageOutcome::Waitlisted {
+            email: Email::new(email),
+        });
+    }
+    let params = CreateParams::from(email);
+    let link = links.url(CREATE_USER_PATHNAME, &params).await?;
+    Ok(PageOutcome::Invite {
+        email: Email::new(email),
+        link,
+        expires: params.expires(),
+    })
+}
+
+pub async fn stats(db: &UserDatabase, audit: &AuditLog) -> PageOutcome {
+    let users = db.lock().await;
+    PageOutcome::Stats {
+        stats: Stats::collect(&users, &audit.events(), chrono::Utc::now()),
+    }
+}
+
+pub async fn report(db: &UserDatabase, audit: &AuditLog) -> PageOutcome {
+    let users = db.lock().await;
+    PageOutcome::Export {
+        csv: stats::report_csv(&users, &audit.events()),
+        filename: format!("user-report-{}.csv", chrono::Utc::now().date_naive()),
+    }
+}
+
+pub async fn share_list(
+    audit: &AuditLog,
+    links: &Links,
+    filter: Option<&str>,
+) -> Result<PageOutcome, ServiceError> {
+    let filter = filter
+        .map(sanitize::text)
+        .filter(|filter| !filter.is_empty());
+    let params = ShareParams::mint(filter.as_deref());
+    audit.record(AuditKind::ShareLinkGenerated, None);
+    Ok(PageOutcome::ShareLink {
+        link: links.url(SHARED_LIST_PATHNAME, &params).await?,
+        filter,
+        expires: params.expires(),
+    })
+}
+
+pub async fn waitlist_page(waitlist: &Waitlist, notice: Option<String>) -> PageOutcome {
+    PageOutcome::Waitlist {
+        open: waitlist.is_open().await,
+        waiting: waitlist.entries().await,
+        notice,
+        invites: Vec::new(),
+        expires: None,
+    }
+}
+
+pub async fn set_registration(waitlist: &Waitlist, open: bool) -> PageOutcome {
+    waitlist.set_open(open).await;
+    let notice = if open {
+        "Registration is open."
+    } else {
+        "Registration is closed; new signups join the waitlist."
+    };
+    waitlist_page(waitlist, Some(notice.to_string())).await
+}
+
+pub fn maintenance_page(maintenance: &Maintenance) -> PageOutcome {
+    PageOutcome::Maintenance {
+        on: maintenance.is_on(),
+        notice: None,
+    }
+}
+
+pub fn set_maintenance(maintenance: &Maintenance, on: bool) -> PageOutcome {
+    maintenance.set(on);
+    let notice = if on {
+        "Maintenance mode is on."
+    } else {
+        "Maintenance mode is off; the site is back up."
+    };
+    PageOutcome::Maintenance {
+        on,
+        notice: Some(notice),
+    }
+}
+
+// Waitlisted addresses get invite links so they still work if the
+// deployment later switches to invite-only.
+pub async fn invite_waitlisted(
+    links: &Links,
+    waitlist: &Waitlist,
+    count: usize,
+) -> Result<PageOutcome, ServiceError> {
+    if count == 0 {
+        return Err(ServiceError::BadRequest);
+    }
+    let mut invites = Vec::new();
+    let mut expires = None;
+    for entry in waitlist.take(count).await {
+        let params = CreateParams::invite(entry.email.as_str());
+        let link = links.url(CREATE_USER_PATHNAME, &params).await?;
+        invites.push((entry.email, link));
+        expires = Some(params.expires());
+    }
+    let notice = match invites.len() {
+        0 => "Nobody is waiting.".to_string(),
+        1 => "Invited 1 address. Send each link to its address.".to_string(),
+        n => format!("Invited {} addresses. Send each link to its address.", n),
+    };
+    Ok(PageOutcome::Waitlist {
+        open: waitlist.is_open().await,
+        waiting: waitlist.entries().await,
+        notice: Some(notice),
+        invites,
+        expires,
+    })
+}
+
+pub async fn create_user(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    links: &Links,
+    policy: SignupPolicy<'_>,
+    params: &CreateParams,
+    form: SignupForm<'_>,
+) -> Result<PageOutcome, ServiceError> {
+    let SignupPolicy {
+        terms,
+        domains,
+        names,
+        screen_lookalikes,
+    } = policy;
+    let SignupForm {
+        name,
+        username,
+        password,
+        accepted_tos,
+    } = form;
+    let name = &sanitize::text(name);
+    let username = &sanitize::text(username);
+    let email = params.email();
+    if !CreateParams::verify(email, params) {
+        audit.record(AuditKind::InviteTokenRejected, None);
+        return Ok(PageOutcome::UserCreated { success: false });
+    }
+    // The link may predate a change to the allowlist.
+    if !domains.allows(email) {
+        return Ok(PageOutcome::CreateUserForm {
+            errors: vec!["Sorry, addresses on that email domain can no longer sign up here."],
+            terms: terms.clone(),
+        });
+    }
+    let mut errors = create_user_errors(name, username, password);
+    errors.extend(names.errors(name, username));
+    if !accepted_tos {
+        errors.push("You must accept the Terms of Service.");
+    }
+    if !errors.is_empty() {
+        return Ok(PageOutcome::CreateUserForm {
+            errors,
+            terms: terms.clone(),
+        });
+    }
+    let mut new_user = UserBuilder::new();
+    new_user
+        .with_email(email)
+        .with_password(password)
+        .with_name(name)
+        .with_username(username)
+        .with_tos_acceptance(terms.accept())
+        .with_lookalike_screening(screen_lookalikes);
+    let id = match db.add_user(new_user).await {
+        Ok(id) => id,
+        Err(UserError::UsernameTaken) => {
+            return Ok(PageOutcome::CreateUserForm {
+                errors: vec!["That username is already taken."],
+                terms: terms.clone(),
+            })
+        }
+        Err(UserError::HiddenCharacters) => {
+            return Ok(PageOutcome::CreateUserForm {
+                errors: vec!["That email address contains invisible or text-direction characters."],
+                terms: terms.clone(),
+            })
+        }
+        Err(UserError::Hash(err)) => return Err(ServiceError::Hash(err.to_string())),
+        Err(UserError::EmailTaken)
+        | Err(UserError::Incomplete)
+        | Err(UserError::InvalidUsername(_)) => return Err(ServiceError::BadRequest),
+    };
+    audit.record(AuditKind::UserCreated, id);
+    links.spend(params).await;
+    Ok(PageOutcome::UserCreated { success: true })
+}
+
+pub async fn bulk(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    links: &Links,
+    throttle: &ResetThrottle,
+    avatars: &Avatars,
+    params: BulkParams,
+    dry_run: bool,
+) -> Result<PageOutcome, ServiceError> {
+    let BulkParams { action, selected } = params;
+    let mut users = db.lock().await;
+    if !users.missing(&selected).is_empty() {
+        let outcomes = selected
+            .iter()
+            .map(|id| match users.get(id) {
+                Some(user) => BulkOutcome::skipped(*id, Some(&user.name), "not changed"),
+                None => BulkOutcome::skipped(*id, None, "not found"),
+            })
+            .collect();
+        return Ok(PageOutcome::BulkRejected { action, outcomes });
+    }
+    // Exports change nothing, so they go ahead either way.
+    if dry_run && action != BulkAction::Export {
+        let result = match action {
+            BulkAction::Delete => "would be deleted",
+            _ => "would get a reset link",
+        };
+        let outcomes = selected
+            .iter()
+            .filter_map(|id| users.get(id))
+            .map(|user| BulkOutcome::done(user, result))
+            .collect();
+        return Ok(PageOutcome::BulkPreview {
+            action,
+            selected,
+            outcomes,
+        });
+    }
+    let outcomes = match action {
+        BulkAction::Export => {
+            let csv = bulk::export_csv(selected.iter().filter_map(|id| users.get(id)));
+            return Ok(PageOutcome::Export {
+                csv,
+                filename: "users.csv".to_string(),
+            });
+        }
+        BulkAction::ResetLinks => {
+            let mut outcomes = Vec::with_capacity(selected.len());
+            for user in selected.iter().filter_map(|id| users.get(id)) {
+                if throttle.issue(user.id).is_err() {
+                    outcomes.push(BulkOutcome::skipped(
+                        user.id,
+                        Some(&user.name),
+                        "too many recent reset links",
+                    ));
+                    continue;
+                }
+                let params = ResetParams::from(user);
+                audit.record(AuditKind::ResetLinkGenerated, user.id);
+                let link = links.url(RESET_PASSWORD_PATHNAME, &params).await?;
+                outcomes.push(BulkOutcome::link(user, link));
+            }
+            outcomes
+        }
+        BulkAction::Delete => {
+            let removed = users
+                .remove_all(&selected)
+                .map_err(|_| ServiceError::BadRequest)?;
+            drop(users);
+            let mut outcomes = Vec::with_capacity(removed.len());
+            for user in removed {
+                audit.record(AuditKind::UserDeleted, user.id);
+                if let Err(err) = avatars.remove(user.id).await {
+                    reporting::report(
+                        ErrorEvent::new("storage_error", err.to_string()).with("user_id", user.id),
+                    );
+                }
+                outcomes.push(BulkOutcome::done(&user, "deleted"));
+            }
+            outcomes
+        }
+    };
+    Ok(PageOutcome::BulkApplied { action, outcomes })
+}
+
+pub async fn reset_links(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    links: &Links,
+    throttle: &ResetThrottle,
+    requested: Vec<String>,
+) -> Result<ResetLinksReply, ServiceError> {
+    let users = db.lock().await;
+    let mut reply = ResetLinksReply {
+        links: Vec::with_capacity(requested.len()),
+        not_found: Vec::new(),
+        throttled: Vec::new(),
+    };
+    for requested in requested {
+        let found = match requested.trim().parse::<UserId>() {
+            Ok(id) => users.get(&id),
+            Err(_) => users.find_by_email(&requested),
+        };
+        let user = match found {
+            Some(user) => user,
+            None => {
+                reply.not_found.push(requested);
+                continue;
+            }
+        };
+        if throttle.issue(user.id).is_err() {
+            reply.throttled.push(requested);
+            continue;
+        }
+        let params = ResetParams::from(user);
+        audit.record(AuditKind::ResetLinkGenerated, user.id);
+        reply.links.push(ResetLinkReply {
+            user: requested,
+            user_id: user.id,
+            link: links.url(RESET_PASSWORD_PATHNAME, &params).await?,
+            report_link: links.url(REPORT_PATHNAME, &params).await?,
+        });
+    }
+    Ok(reply)
+}
+
+pub async fn invite_links(
+    links: &Links,
+    emails: Vec<String>,
+) -> Result<InviteLinksReply, ServiceError> {
+    let mut reply = InviteLinksReply {
+        links: Vec::with_capacity(emails.len()),
+    };
+    for email in emails {
+        let email = &sanitize::text(&email);
+        if email.is_empty() || sanitize::EMAIL.check(email).is_some() {
+            return Err(ServiceError::BadRequest);
+        }
+        let params = CreateParams::invite(email);
+        reply.links.push(InviteLinkReply {
+            email: Email::new(email),
+            link: links.url(CREATE_USER_PATHNAME, &params).await?,
+            expires: params.expires(),
+        });
+    }
+    Ok(reply)
+}
+
+// Locked accounts, and passwords left unrotated past the grace period, are
+// refused.
+pub async fn verify_password(
+    db: &UserDatabase,
+    rotation: &PasswordRotation,
+    id: UserId,
+    candidate: &str,
+) -> bool {
+    db.lock().await.get(&id).is_some_and(|user| {
+        !user.is_locked()
+            && rotation.age(user, chrono::Utc::now()) != PasswordAge::Locked
+            && user.verify_password(candidate)
+    })
+}
+
+// Mints a reset link for every user whose password has passed the maximum
+// age and who hasn't been sent one for it yet.
+pub async fn request_rotations(
+    db: &UserDatabase,
+    audit: &AuditLog,
+    links: &Links,
+    rotation: &PasswordRotation,
+) -> Result<usize, ServiceError> {
+    let now = chrono::Utc::now();
+    let mut users = db.lock().await;
+    let due: Vec<UserId> = users
+        .values()
+        .filter(|user| rotation.link_due(user, now))
+        .map(|user| user.id)
+        .collect();
+    for &id in &due {
+        let user = match users.get_mut(&id) {
+            Some(user) => user,
+            None => continue,
+        };
+        let params = ResetParams::from(&*user);
+        let link = links.url(RESET_PASSWORD_PATHNAME, &params).await?;
+        let report_link = links.url(REPORT_PATHNAME, &params).await?;
+        user.rotation_requested_at = Some(now);
+        notify::send_with_report(
+            &user.email,
+            "Your password has expired. Please choose a new one.",
+            &link,
+            &report_link,
+        );
+        audit.record(AuditKind::PasswordRotationRequested, id);
+    }
+    Ok(due.len())
+}
+
+pub async fn email_available(db: &UserDatabase, email: &str) -> bool {
+    !email.is_empty() && !db.lock().await.email_taken(email)
+}
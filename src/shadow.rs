@@ -0,0 +1,72 @@
+use crate::core::{self, MacAlgorithm, Purpose, TokenVersion};
+use crate::env_vars::env_bool;
+use crate::metrics::Metrics;
+use secrecy::{ExposeSecret, SecretVec};
+use std::env;
+use std::sync::RwLock;
+
+static SHADOW: RwLock<Option<ShadowVerifier>> = RwLock::new(None);
+
+pub struct ShadowVerifier {
+    key: Option<SecretVec<u8>>,
+    current_version_only: bool,
+    metrics: Metrics,
+}
+
+impl ShadowVerifier {
+    pub fn from_env(metrics: Metrics) -> Option<Self> {
+        if !env_bool("APP_SHADOW_VERIFY").unwrap_or(false) {
+            return None;
+        }
+        Some(ShadowVerifier {
+            key: env::var("APP_SHADOW_SECRET_KEY")
+                .ok()
+                .filter(|key| !key.is_empty())
+                .map(|key| SecretVec::new(key.into_bytes())),
+            current_version_only: env_bool("APP_SHADOW_CURRENT_VERSION_ONLY").unwrap_or(false),
+            metrics,
+        })
+    }
+}
+
+pub fn install(shadow: Option<ShadowVerifier>) {
+    *SHADOW.write().unwrap() = shadow;
+}
+
+pub fn observe(
+    kind: &'static str,
+    purpose: Purpose,
+    live_key: &[u8],
+    payload: &[&[u8]],
+    token: &[u8],
+    live_accepts: impl Fn(TokenVersion, MacAlgorithm) -> bool,
+    live_result: bool,
+) {
+    let shadow = SHADOW.read().unwrap();
+    let shadow = match shadow.as_ref() {
+        Some(shadow) => shadow,
+        None => return,
+    };
+    let key = shadow
+        .key
+        .as_ref()
+        .map_or(live_key, |key| key.expose_secret().as_slice());
+    let shadow_result = if shadow.current_version_only {
+        core::verify_accepting(key, purpose, payload, token, |version, algorithm| {
+            version == TokenVersion::CURRENT && live_accepts(version, algorithm)
+        })
+    } else {
+        core::verify_accepting(key, purpose, payload, token, live_accepts)
+    };
+    shadow.metrics.incr_by("shadow_verify_checks_total", 1);
+    if shadow_result != live_result {
+        shadow.metrics.incr_by("shadow_verify_mismatches_total", 1);
+        eprintln!(
+            "shadow verification mismatch: kind={} version={:?} live={} shadow={}",
+            kind,
+            core::token_version(token),
+            live_result,
+            shadow_result
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::audit::{AuditEvent, AuditKind};
+use crate::bulk::csv_field;
+use crate::user::{UserId, UserStore};
+use crate::verify::UtcDateTime;
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap};
+
+const RECENT_RESET_DAYS: i64 = 7;
+
+// Figures for `/admin/stats`. Audit-derived counts only reach back as far as
+// the audit log's in-memory history does.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub total_users: usize,
+    pub verified: usize,
+    pub unverified: usize,
+    pub signups_per_day: Vec<(NaiveDate, usize)>,
+    pub recent_resets: usize,
+    pub rejected_reset_tokens: usize,
+    pub rejected_invite_tokens: usize,
+}
+
+impl Stats {
+    pub fn collect(users: &UserStore, events: &[AuditEvent], now: UtcDateTime) -> Self {
+        let verified = users
+            .values()
+            .filter(|user| user.verified_at.is_some())
+            .count();
+        let mut signups = BTreeMap::new();
+        for user in users.values() {
+            *signups.entry(user.created_at.date_naive()).or_insert(0) += 1;
+        }
+        let week_ago = now - chrono::Duration::days(RECENT_RESET_DAYS);
+        let count = |kind: AuditKind| events.iter().filter(|event| event.kind == kind).count();
+        Stats {
+            total_users: users.len(),
+            verified,
+            unverified: users.len() - verified,
+            signups_per_day: signups.into_iter().rev().collect(),
+            recent_resets: events
+                .iter()
+                .filter(|event| event.kind == AuditKind::PasswordReset && event.at >= week_ago)
+                .count(),
+            rejected_reset_tokens: count(AuditKind::ResetTokenRejected),
+            rejected_invite_tokens: count(AuditKind::InviteTokenRejected),
+        }
+    }
+}
+
+fn timestamp(at: Option<&UtcDateTime>) -> String {
+    at.map(|at| at.to_rfc3339()).unwrap_or_default()
+}
+
+// One row per user, oldest signup first. `last_reset` is blank when the
+// audit history holds no reset for that user.
+pub fn report_csv(users: &UserStore, events: &[AuditEvent]) -> String {
+    let mut last_resets: HashMap<UserId, UtcDateTime> = HashMap::new();
+    for event in events {
+        if event.kind == AuditKind::PasswordReset {
+            if let Some(user_id) = event.user_id {
+                last_resets.insert(user_id, event.at);
+            }
+        }
+    }
+    let mut rows: Vec<_> = users.values().collect();
+    rows.sort_by_key(|user| (user.created_at, user.id));
+    let mut csv =
+        String::from("id,username,name,email,created_at,verified,verified_at,last_reset\n");
+    for user in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            user.id,
+            csv_field(&user.username),
+            csv_field(&user.name),
+            csv_field(user.email.as_str()),
+            user.created_at.to_rfc3339(),
+            user.verified_at.is_some(),
+            timestamp(user.verified_at.as_ref()),
+            timestamp(last_resets.get(&user.id)),
+        ));
+    }
+    csv
+}
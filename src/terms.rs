@@ -0,0 +1,65 @@
+use crate::user::User;
+use crate::verify::UtcDateTime;
+use std::env;
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use warp::Filter;
+
+const DEFAULT_TOS_VERSION: &str = "1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TosAcceptance {
+    pub version: String,
+    pub accepted_at: UtcDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Terms {
+    version: Arc<str>,
+    url: Option<Arc<str>>,
+}
+
+impl Terms {
+    pub fn from_env() -> Result<Self, String> {
+        let version = env::var("APP_TOS_VERSION").unwrap_or_else(|_| DEFAULT_TOS_VERSION.into());
+        if version.trim().is_empty() {
+            return Err("APP_TOS_VERSION must not be empty".to_string());
+        }
+        Ok(Terms {
+            version: version.trim().into(),
+            url: env::var("APP_TOS_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .map(Into::into),
+        })
+    }
+
+    #[cfg(feature = "server")]
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn accept(&self) -> TosAcceptance {
+        TosAcceptance {
+            version: self.version.to_string(),
+            accepted_at: chrono::Utc::now(),
+        }
+    }
+
+    pub fn needs_acceptance(&self, user: &User) -> bool {
+        user.tos_accepted
+            .as_ref()
+            .is_none_or(|accepted| accepted.version != *self.version)
+    }
+}
@@ -0,0 +1,129 @@
+use crate::env_vars::env_secs;
+use crate::user::UserId;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+const DEFAULT_RESET_LINK_LIMIT: usize = 5;
+const DEFAULT_RESET_LINK_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttled {
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = self.retry_after.as_secs().div_ceil(60).max(1);
+        write!(
+            f,
+            "Too many reset links have been generated for this account recently. Try again in {} minute{}.",
+            minutes,
+            if minutes == 1 { "" } else { "s" }
+        )
+    }
+}
+
+// Caps how many reset links one user can be sent within a sliding window,
+// wherever they are issued from, so nobody can flood an inbox with them.
+// `APP_RESET_LINK_LIMIT` sets the cap (0 turns it off) and
+// `APP_RESET_LINK_WINDOW_SECS` the window.
+#[derive(Debug, Clone)]
+pub struct ResetThrottle {
+    issued: Arc<Mutex<HashMap<UserId, VecDeque<Instant>>>>,
+    limit: usize,
+    window: Duration,
+}
+
+impl ResetThrottle {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        ResetThrottle {
+            issued: Arc::new(Mutex::new(HashMap::new())),
+            limit,
+            window,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let limit = match env::var("APP_RESET_LINK_LIMIT") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| format!("APP_RESET_LINK_LIMIT must be a number, not {}", value))?,
+            Err(_) => DEFAULT_RESET_LINK_LIMIT,
+        };
+        let window = env_secs("APP_RESET_LINK_WINDOW_SECS")?.unwrap_or(DEFAULT_RESET_LINK_WINDOW);
+        Ok(ResetThrottle::new(limit, window))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    // Counts a link for `user`, or says how long until one can be issued.
+    pub fn issue(&self, user: UserId) -> Result<(), Throttled> {
+        self.issue_at(user, Instant::now())
+    }
+
+    fn issue_at(&self, user: UserId, now: Instant) -> Result<(), Throttled> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let mut issued = self.issued.lock().unwrap();
+        let times = issued.entry(user).or_default();
+        while times
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.window)
+        {
+            times.pop_front();
+        }
+        match times.front() {
+            Some(oldest) if times.len() >= self.limit => Err(Throttled {
+                retry_after: self.window - now.duration_since(*oldest),
+            }),
+            _ => {
+                times.push_back(now);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_past_the_limit_wait_for_the_window() {
+        let throttle = ResetThrottle::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(throttle.issue_at(1, start).is_ok());
+        assert!(throttle
+            .issue_at(1, start + Duration::from_secs(10))
+            .is_ok());
+        assert_eq!(
+            throttle.issue_at(1, start + Duration::from_secs(20)),
+            Err(Throttled {
+                retry_after: Duration::from_secs(40)
+            })
+        );
+        assert!(throttle
+            .issue_at(2, start + Duration::from_secs(20))
+            .is_ok());
+        assert!(throttle
+            .issue_at(1, start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn a_zero_limit_turns_it_off() {
+        let throttle = ResetThrottle::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!((0..10).all(|_| throttle.issue_at(1, now).is_ok()));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::metrics::Metrics;
+use crate::reporting::{self, ErrorEvent};
+use crate::user::UserId;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::time::{Duration, Instant};
+use warp::http::Method;
+use warp::Filter;
+
+const DEFAULT_SLOW_REQUEST: Duration = Duration::from_millis(500);
+
+pub struct RequestTiming {
+    started: Instant,
+    method: Method,
+    route: &'static str,
+    user_id: Option<UserId>,
+}
+
+pub fn slow_request_threshold() -> Result<Duration, String> {
+    match env::var("APP_SLOW_REQUEST_MS") {
+        Ok(millis) => millis.parse().map(Duration::from_millis).map_err(|_| {
+            format!(
+                "APP_SLOW_REQUEST_MS must be a number of milliseconds, not {}",
+                millis
+            )
+        }),
+        Err(_) => Ok(DEFAULT_SLOW_REQUEST),
+    }
+}
+
+pub fn route_of(path: &str) -> &'static str {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    match (segments.next(), segments.next()) {
+        (Some(""), None) => "/",
+        (Some("list"), None) => "/list",
+        (Some("list"), Some("bulk")) => "/list/bulk",
+        (Some("list"), Some("share")) => "/list/share",
+        (Some("shared"), Some("list")) => "/shared/list",
+        (Some("reset-password-generate"), Some(_)) => "/reset-password-generate/:id",
+        (Some("reset-password"), None) => "/reset-password",
+        (Some("new-user"), None) => "/new-user",
+        (Some("waitlist"), None) => "/waitlist",
+        (Some("waitlist"), Some("registration")) => "/waitlist/registration",
+        (Some("waitlist"), Some("invite")) => "/waitlist/invite",
+        (Some("create-user"), None) => "/create-user",
+        (Some("metrics"), None) => "/metrics",
+        (Some("readyz"), None) => "/readyz",
+        (Some("admin"), Some("stats")) => "/admin/stats",
+        (Some("admin"), Some("report.csv")) => "/admin/report.csv",
+        (Some("admin"), Some("merge")) => "/admin/merge",
+        (Some("admin"), Some("merge/confirm")) => "/admin/merge/confirm",
+        (Some("admin"), Some("maintenance")) => "/admin/maintenance",
+        (Some("events"), None) => "/events",
+        (Some("api"), Some("email-available")) => "/api/email-available",
+        (Some("api"), Some("reset-links")) => "/api/reset-links",
+        (Some("api"), Some("invites")) => "/api/invites",
+        (Some("api"), Some(rest)) if rest.starts_with("users/") && rest.ends_with("/lock") => {
+            "/api/users/:id/lock"
+        }
+        (Some("api"), Some(rest)) if rest.starts_with("users/") => "/api/users/:id",
+        (Some("api"), Some("internal/verify-password")) => "/api/internal/verify-password",
+        (Some("api-keys"), None) => "/api-keys",
+        (Some("api-keys"), Some(_)) => "/api-keys/:id/revoke",
+        (Some("graphql"), None) => "/graphql",
+        (Some(".well-known"), Some("security.txt")) => "/.well-known/security.txt",
+        (Some(".well-known"), Some("jwks.json")) => "/.well-known/jwks.json",
+        (Some("users"), Some(rest)) if rest.ends_with("/avatar") => "/users/:id/avatar",
+        (Some("users"), Some(rest)) if rest.ends_with("/preferences") => "/users/:id/preferences",
+        (Some("users"), Some(rest)) if rest.ends_with("/aliases") => "/users/:id/aliases",
+        (Some("users"), Some(rest)) if rest.ends_with("/lock") => "/users/:id/lock",
+        (Some("users"), Some(rest)) if rest.ends_with("/email-change") => "/users/:id/email-change",
+        (Some("users"), Some(rest)) if rest.ends_with("/email-change/cancel") => {
+            "/users/:id/email-change/cancel"
+        }
+        (Some("users"), Some(rest)) if rest.ends_with("/verification") => "/users/:id/verification",
+        (Some("users"), Some(_)) => "/users/:id",
+        (Some("user"), Some(_)) => "/user/@:username",
+        (Some("robots.txt"), None) => "/robots.txt",
+        (Some("unsubscribe"), None) => "/unsubscribe",
+        (Some("verify-email"), None) => "/verify-email",
+        (Some("confirm-email-change"), None) => "/confirm-email-change",
+        (Some("revert"), None) => "/revert",
+        (Some("report"), None) => "/report",
+        (Some("static"), Some("countdown.js")) => "/static/countdown.js",
+        _ => "other",
+    }
+}
+
+fn user_id_of(path: &str, query: &str) -> Option<UserId> {
+    if let Some(id) = path.strip_prefix("/reset-password-generate/") {
+        return id.parse().ok();
+    }
+    if let Some(rest) = path.strip_prefix("/users/") {
+        let id = rest.split('/').next().unwrap_or(rest);
+        return id.parse().ok();
+    }
+    serde_urlencoded::from_str::<HashMap<String, String>>(query)
+        .ok()?
+        .get("user_id")?
+        .parse()
+        .ok()
+}
+
+pub fn start() -> impl Filter<Extract = (RequestTiming,), Error = Infallible> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(
+            |method: Method, path: warp::path::FullPath, query: String| RequestTiming {
+                started: Instant::now(),
+                method,
+                route: route_of(path.as_str()),
+                user_id: user_id_of(path.as_str(), &query),
+            },
+        )
+}
+
+impl RequestTiming {
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn route(&self) -> &'static str {
+        self.route
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn finish(
+        self,
+        metrics: &Metrics,
+        slow_threshold: Duration,
+        response: &warp::reply::Response,
+    ) {
+        let elapsed = self.started.elapsed();
+        let status = response.status();
+        let error = response
+            .extensions()
+            .get::<ErrorEvent>()
+            .cloned()
+            .or_else(|| {
+                if status.is_server_error() {
+                    Some(ErrorEvent::new("server_error", status.to_string()))
+                } else {
+                    None
+                }
+            });
+        if let Some(error) = error {
+            let mut event = error
+                .with("method", &self.method)
+                .with("route", self.route)
+                .with("status", status.as_u16());
+            if let Some(user_id) = self.user_id {
+                event = event.with("user_id", user_id);
+            }
+            reporting::report(event);
+        }
+        metrics.observe_duration("http_request_duration_seconds", self.route, elapsed);
+        if elapsed >= slow_threshold {
+            let user_id = self
+                .user_id
+                .map_or_else(|| "-".to_string(), |id| id.to_string());
+            eprintln!(
+                "level=warn msg=\"slow request\" method={} route={} status={} user_id={} duration_ms={}",
+                self.method,
+                self.route,
+                status.as_u16(),
+                user_id,
+                elapsed.as_millis()
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::verify::{ResetParams, UtcDateTime};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::Filter;
+
+#[derive(Debug, Clone)]
+pub struct UsedTokenStore {
+    used: Arc<Mutex<HashMap<Vec<u8>, UtcDateTime>>>,
+}
+
+impl UsedTokenStore {
+    pub fn new() -> Self {
+        UsedTokenStore {
+            used: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    #[cfg(feature = "grpc")]
+    pub async fn is_used(&self, params: &ResetParams) -> bool {
+        self.used.lock().await.contains_key(params.token())
+    }
+
+    pub async fn consume(&self, params: &ResetParams) -> bool {
+        let mut used = self.used.lock().await;
+        if used.contains_key(params.token()) {
+            false
+        } else {
+            used.insert(params.token().to_vec(), params.expires());
+            true
+        }
+    }
+
+    pub async fn purge_expired(&self) -> usize {
+        let now = chrono::Utc::now();
+        let mut used = self.used.lock().await;
+        let before = used.len();
+        used.retain(|_, expires| *expires >= now);
+        before - used.len()
+    }
+}
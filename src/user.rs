@@ -1,17 +1,195 @@
+use crate::hashing;
+use crate::identity;
+use crate::ids::IdGenerator;
+use crate::pii::{Email, PasswordHash};
+use crate::terms::TosAcceptance;
+use crate::verify::UtcDateTime;
 use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::{Mutex, MutexGuard};
+#[cfg(feature = "server")]
 use warp::Filter;
 
 pub type UserId = u64;
 
+const USERNAME_MIN_LEN: usize = 3;
+const USERNAME_MAX_LEN: usize = 32;
+
 #[derive(Debug)]
+pub enum UserError {
+    Incomplete,
+    EmailTaken,
+    UsernameTaken,
+    InvalidUsername(&'static str),
+    HiddenCharacters,
+    Hash(bcrypt::BcryptError),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::Incomplete => {
+                write!(f, "name, username, email and password are all required")
+            }
+            UserError::EmailTaken => write!(f, "that email is already registered"),
+            UserError::UsernameTaken => write!(f, "that username is already taken"),
+            UserError::InvalidUsername(reason) => write!(f, "{}", reason),
+            UserError::HiddenCharacters => {
+                write!(f, "names and emails can't contain invisible characters")
+            }
+            UserError::Hash(err) => write!(f, "could not hash password: {}", err),
+        }
+    }
+}
+
+// What a user has agreed to be contacted about. Anything that sends a
+// message must check these first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NotificationPreferences {
+    pub email: bool,
+    pub sms: bool,
+    pub marketing: bool,
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences {
+            email: true,
+            sms: false,
+            marketing: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationTopic {
+    Email,
+    Sms,
+    Marketing,
+}
+
+impl NotificationTopic {
+    pub fn name(self) -> &'static str {
+        match self {
+            NotificationTopic::Email => "email",
+            NotificationTopic::Sms => "sms",
+            NotificationTopic::Marketing => "marketing",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            NotificationTopic::Email => "email about your account",
+            NotificationTopic::Sms => "text messages about your account",
+            NotificationTopic::Marketing => "news and offers",
+        }
+    }
+}
+
+impl FromStr for NotificationTopic {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "email" => Ok(NotificationTopic::Email),
+            "sms" => Ok(NotificationTopic::Sms),
+            "marketing" => Ok(NotificationTopic::Marketing),
+            _ => Err(format!("unknown notification topic {}", name)),
+        }
+    }
+}
+
+impl NotificationPreferences {
+    pub fn opt_out(&mut self, topic: NotificationTopic) {
+        match topic {
+            NotificationTopic::Email => self.email = false,
+            NotificationTopic::Sms => self.sms = false,
+            NotificationTopic::Marketing => self.marketing = false,
+        }
+    }
+}
+
+// An extra address on an account. It reaches the user and counts towards
+// duplicate checks from the moment it is added, but only stands in for them
+// once a link sent to it has been followed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailAlias {
+    pub email: Email,
+    pub verified_at: Option<UtcDateTime>,
+}
+
+// Which end of an email change a confirmation link was sent to. Each gets
+// its own link, and the change only applies once both have been followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailChangeSide {
+    Old,
+    New,
+}
+
+impl EmailChangeSide {
+    pub fn name(self) -> &'static str {
+        match self {
+            EmailChangeSide::Old => "old",
+            EmailChangeSide::New => "new",
+        }
+    }
+}
+
+// A requested switch of the primary address, waiting on confirmation from
+// both the current address and the new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEmailChange {
+    pub email: Email,
+    pub requested_at: UtcDateTime,
+    pub old_confirmed_at: Option<UtcDateTime>,
+    pub new_confirmed_at: Option<UtcDateTime>,
+}
+
+impl PendingEmailChange {
+    pub fn is_confirmed(&self) -> bool {
+        self.old_confirmed_at.is_some() && self.new_confirmed_at.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct User {
     pub id: UserId,
     pub name: String,
-    pub email: String,
-    pub bcrypt_password: String,
+    pub username: String,
+    pub email: Email,
+    pub bcrypt_password: PasswordHash,
+    pub has_avatar: bool,
+    pub tos_accepted: Option<TosAcceptance>,
+    pub lookalike_of: Option<UserId>,
+    pub created_at: UtcDateTime,
+    // Bumped on every change, so API clients can detect concurrent edits.
+    pub version: u64,
+    // Set once the user has followed a link sent to their address.
+    pub verified_at: Option<UtcDateTime>,
+    pub preferences: NotificationPreferences,
+    // Other addresses that reach this user, added by hand or carried over
+    // from merged accounts. Lookups and duplicate checks cover them too.
+    pub email_aliases: Vec<EmailAlias>,
+    pub password_changed_at: UtcDateTime,
+    // When the rotation job last sent a reset link for the current password.
+    pub rotation_requested_at: Option<UtcDateTime>,
+    // The hash a reset replaced, kept so the owner can revert a reset they
+    // didn't ask for.
+    pub previous_password: Option<PasswordHash>,
+    // Set by an admin. A locked account's password and links are refused.
+    pub locked_at: Option<UtcDateTime>,
+    pub pending_email_change: Option<PendingEmailChange>,
+    // Set when someone reports a link for this account that they didn't ask
+    // for, so an admin can look into it.
+    pub reported_at: Option<UtcDateTime>,
 }
 
 impl User {
@@ -20,35 +198,148 @@ impl User {
         let random_email = thread_rnd.gen::<u16>().to_string();
         User {
             id: thread_rnd.gen(),
+            username: name.to_lowercase(),
             name,
-            email: format!("user-{}@spookysoftware.dev", random_email),
-            bcrypt_password: bcrypt::hash(&random_password, 4).unwrap(),
+            email: Email::new(format!("user-{}@spookysoftware.dev", random_email)),
+            bcrypt_password: hashing::hash(&random_password)
+                .expect("hashing a generated demo password"),
+            has_avatar: false,
+            tos_accepted: None,
+            lookalike_of: None,
+            created_at: chrono::Utc::now(),
+            version: 1,
+            verified_at: None,
+            preferences: NotificationPreferences::default(),
+            email_aliases: Vec::new(),
+            password_changed_at: chrono::Utc::now(),
+            rotation_requested_at: None,
+            previous_password: None,
+            locked_at: None,
+            pending_email_change: None,
+            reported_at: None,
+        }
+    }
+
+    pub fn verify_password(&self, candidate: &str) -> bool {
+        hashing::verify(candidate, &self.bcrypt_password)
+    }
+
+    pub fn reset_password(&mut self, new_password: &str) -> Result<(), bcrypt::BcryptError> {
+        let replaced = std::mem::replace(&mut self.bcrypt_password, hashing::hash(new_password)?);
+        self.previous_password = Some(replaced);
+        self.password_changed_at = chrono::Utc::now();
+        self.rotation_requested_at = None;
+        self.verified_at.get_or_insert_with(chrono::Utc::now);
+        self.touch();
+        Ok(())
+    }
+
+    // Puts back the password the last reset replaced. False when there is
+    // nothing to go back to.
+    pub fn revert_password(&mut self) -> bool {
+        match self.previous_password.take() {
+            Some(previous) => {
+                self.bcrypt_password = previous;
+                self.password_changed_at = chrono::Utc::now();
+                self.rotation_requested_at = None;
+                self.touch();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_at.is_some()
+    }
+
+    // Returns whether anything changed.
+    pub fn set_locked(&mut self, locked: bool) -> bool {
+        if locked == self.is_locked() {
+            return false;
+        }
+        self.locked_at = if locked {
+            Some(chrono::Utc::now())
+        } else {
+            None
+        };
+        self.touch();
+        true
+    }
+
+    pub fn touch(&mut self) {
+        self.version += 1;
+    }
+
+    pub fn owns_email(&self, email: &Email) -> bool {
+        self.email == *email || self.email_aliases.iter().any(|alias| alias.email == *email)
+    }
+
+    // Stamps whichever of the user's addresses `email` is. Returns false when
+    // it isn't one of theirs.
+    pub fn mark_verified(&mut self, email: &Email) -> bool {
+        let verified_at = if self.email == *email {
+            &mut self.verified_at
+        } else {
+            match self
+                .email_aliases
+                .iter_mut()
+                .find(|alias| alias.email == *email)
+            {
+                Some(alias) => &mut alias.verified_at,
+                None => return false,
+            }
+        };
+        if verified_at.is_none() {
+            *verified_at = Some(chrono::Utc::now());
+            self.touch();
         }
+        true
     }
+}
 
-    pub fn reset_password(&mut self, new_password: &str) {
-        self.bcrypt_password = bcrypt::hash(new_password, 4).unwrap();
+// Usernames appear in URLs, so they stay within a small ASCII alphabet.
+// Case is kept for display but ignored when checking uniqueness.
+pub fn username_error(username: &str) -> Option<&'static str> {
+    if username.len() < USERNAME_MIN_LEN || username.len() > USERNAME_MAX_LEN {
+        return Some("Username must be 3 to 32 characters long.");
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Some("Username may only contain letters, numbers, `.`, `_` and `-`.");
     }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Some("Username must start with a letter or number.");
+    }
+    None
 }
 
 #[derive(Debug)]
 pub struct UserBuilder {
     requested_name: Option<String>,
-    requested_email: Option<String>,
-    requested_password: Option<String>,
+    requested_username: Option<String>,
+    requested_email: Option<Email>,
+    requested_password: Option<SecretString>,
+    tos_accepted: Option<TosAcceptance>,
+    screen_lookalikes: bool,
 }
 
 impl UserBuilder {
     pub fn new() -> Self {
         UserBuilder {
             requested_name: None,
+            requested_username: None,
             requested_email: None,
             requested_password: None,
+            tos_accepted: None,
+            screen_lookalikes: false,
         }
     }
 
     pub fn with_email(&mut self, email: &str) -> &mut Self {
-        self.requested_email = Some(email.to_string());
+        self.requested_email = Some(Email::new(email));
         self
     }
 
@@ -57,60 +348,389 @@ impl UserBuilder {
         self
     }
 
+    pub fn with_username(&mut self, username: &str) -> &mut Self {
+        self.requested_username = Some(username.trim().to_string());
+        self
+    }
+
     pub fn with_password(&mut self, password: &str) -> &mut Self {
-        self.requested_password = Some(password.to_string());
+        self.requested_password = Some(SecretString::new(password.to_string()));
+        self
+    }
+
+    pub fn with_tos_acceptance(&mut self, accepted: TosAcceptance) -> &mut Self {
+        self.tos_accepted = Some(accepted);
+        self
+    }
+
+    pub fn with_lookalike_screening(&mut self, enabled: bool) -> &mut Self {
+        self.screen_lookalikes = enabled;
         self
     }
 
-    fn build(self) -> Option<User> {
-        let name = self.requested_name?;
-        let email = self.requested_email?;
-        let password = self.requested_password?;
-        let rnd = &mut rand::thread_rng();
-        Some(User {
-            id: rnd.gen(),
+    fn build(self, id: UserId) -> Result<User, UserError> {
+        let name = self.requested_name.ok_or(UserError::Incomplete)?;
+        let username = self.requested_username.ok_or(UserError::Incomplete)?;
+        if let Some(reason) = username_error(&username) {
+            return Err(UserError::InvalidUsername(reason));
+        }
+        let email = self.requested_email.ok_or(UserError::Incomplete)?;
+        let password = self.requested_password.ok_or(UserError::Incomplete)?;
+        if identity::has_hidden_characters(&name) || identity::has_hidden_characters(email.as_str())
+        {
+            return Err(UserError::HiddenCharacters);
+        }
+        let name = identity::normalize(&name);
+        let email = Email::new(identity::normalize(email.as_str()));
+        let bcrypt_password = hashing::hash(password.expose_secret()).map_err(UserError::Hash)?;
+        let now = chrono::Utc::now();
+        Ok(User {
+            id,
             name,
+            username,
             email,
-            bcrypt_password: bcrypt::hash(&password, 4).unwrap(),
+            bcrypt_password,
+            has_avatar: false,
+            tos_accepted: self.tos_accepted,
+            lookalike_of: None,
+            created_at: now,
+            version: 1,
+            // Every signup arrives through an invite link mailed to the address.
+            verified_at: Some(now),
+            preferences: NotificationPreferences::default(),
+            email_aliases: Vec::new(),
+            password_changed_at: now,
+            rotation_requested_at: None,
+            previous_password: None,
+            locked_at: None,
+            pending_email_change: None,
+            reported_at: None,
         })
     }
 }
 
 pub type UserTable = HashMap<UserId, User>;
 
+fn email_key(email: &str) -> Email {
+    Email::new(identity::normalize(email.trim()).to_lowercase())
+}
+
+fn username_key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+// An account folded into another one. Kept so the merge can be looked into
+// or undone by hand; it no longer shows up anywhere.
+#[derive(Debug, Clone)]
+pub struct MergedUser {
+    pub user: User,
+    pub into: UserId,
+}
+
+#[derive(Debug)]
+pub struct UserStore {
+    users: UserTable,
+    emails: HashMap<Email, UserId>,
+    usernames: HashMap<String, UserId>,
+    merged: HashMap<UserId, MergedUser>,
+}
+
+impl UserStore {
+    fn from_table(users: UserTable) -> Self {
+        let emails = users
+            .values()
+            .flat_map(|user| {
+                std::iter::once(&user.email)
+                    .chain(user.email_aliases.iter().map(|alias| &alias.email))
+                    .map(move |email| (email_key(email.as_str()), user.id))
+            })
+            .collect();
+        let usernames = users
+            .values()
+            .map(|user| (username_key(&user.username), user.id))
+            .collect();
+        UserStore {
+            users,
+            emails,
+            usernames,
+            merged: HashMap::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self, id: &UserId) -> Option<&mut User> {
+        self.users.get_mut(id)
+    }
+
+    pub fn find_by_email(&self, email: &str) -> Option<&User> {
+        self.emails
+            .get(&email_key(email))
+            .and_then(|id| self.users.get(id))
+    }
+
+    pub fn find_by_username(&self, username: &str) -> Option<&User> {
+        self.usernames
+            .get(&username_key(username))
+            .and_then(|id| self.users.get(id))
+    }
+
+    pub fn email_taken(&self, email: &str) -> bool {
+        self.emails.contains_key(&email_key(email))
+    }
+
+    pub fn username_taken(&self, username: &str) -> bool {
+        self.usernames.contains_key(&username_key(username))
+    }
+
+    fn lookalike_of(&self, user: &User) -> Option<UserId> {
+        self.users
+            .values()
+            .filter(|existing| {
+                identity::is_lookalike(&user.name, &existing.name)
+                    || identity::is_lookalike(&user.username, &existing.username)
+                    || identity::is_lookalike(user.email.as_str(), existing.email.as_str())
+            })
+            .map(|existing| existing.id)
+            .min()
+    }
+
+    fn insert(&mut self, mut user: User, screen_lookalikes: bool) -> Result<UserId, UserError> {
+        if self.email_taken(user.email.as_str()) {
+            return Err(UserError::EmailTaken);
+        }
+        if self.username_taken(&user.username) {
+            return Err(UserError::UsernameTaken);
+        }
+        if screen_lookalikes {
+            user.lookalike_of = self.lookalike_of(&user);
+        }
+        let id = user.id;
+        self.emails.insert(email_key(user.email.as_str()), id);
+        self.usernames.insert(username_key(&user.username), id);
+        self.users.insert(id, user);
+        Ok(id)
+    }
+
+    pub fn missing(&self, ids: &[UserId]) -> Vec<UserId> {
+        ids.iter()
+            .copied()
+            .filter(|id| !self.users.contains_key(id))
+            .collect()
+    }
+
+    pub fn remove_all(&mut self, ids: &[UserId]) -> Result<Vec<User>, Vec<UserId>> {
+        let missing = self.missing(ids);
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+        let mut removed = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(user) = self.users.remove(id) {
+                // Aliases and merged-in usernames point here as well.
+                self.emails.retain(|_, owner| owner != id);
+                self.usernames.retain(|_, owner| owner != id);
+                removed.push(user);
+            }
+        }
+        Ok(removed)
+    }
+
+    // Folds `duplicate` into `primary`: its verified address becomes an alias,
+    // its aliases and username resolve to `primary`, and the account itself
+    // moves out of the table into `merged`.
+    pub fn merge(&mut self, primary: UserId, duplicate: UserId) -> Option<(User, User)> {
+        if primary == duplicate || !self.users.contains_key(&primary) {
+            return None;
+        }
+        let removed = self.users.remove(&duplicate)?;
+        if let Some(pending) = &removed.pending_email_change {
+            self.emails.remove(&email_key(pending.email.as_str()));
+        }
+        let mut aliases = removed.email_aliases.clone();
+        if removed.verified_at.is_some() {
+            aliases.insert(
+                0,
+                EmailAlias {
+                    email: removed.email.clone(),
+                    verified_at: removed.verified_at,
+                },
+            );
+        } else {
+            self.emails.remove(&email_key(removed.email.as_str()));
+        }
+        for alias in &aliases {
+            self.emails.insert(email_key(alias.email.as_str()), primary);
+        }
+        self.usernames
+            .insert(username_key(&removed.username), primary);
+        let user = self.users.get_mut(&primary)?;
+        for alias in aliases {
+            if !user.owns_email(&alias.email) {
+                user.email_aliases.push(alias);
+            }
+        }
+        user.touch();
+        let merged = user.clone();
+        self.merged.insert(
+            duplicate,
+            MergedUser {
+                user: removed.clone(),
+                into: primary,
+            },
+        );
+        Some((merged, removed))
+    }
+
+    // Adds an unverified alias. The address is reserved straight away, so
+    // nobody else can sign up with it while the link is outstanding. `None`
+    // when there is no such user.
+    pub fn add_alias(&mut self, id: UserId, email: &str) -> Option<Result<User, UserError>> {
+        let taken = self.email_taken(email);
+        let user = self.users.get_mut(&id)?;
+        if taken {
+            return Some(Err(UserError::EmailTaken));
+        }
+        user.email_aliases.push(EmailAlias {
+            email: Email::new(email),
+            verified_at: None,
+        });
+        user.touch();
+        let user = user.clone();
+        self.emails.insert(email_key(email), id);
+        Some(Ok(user))
+    }
+
+    // Records a pending switch of `id`'s primary address to `email`, replacing
+    // any earlier request and the links sent for it. The new address is
+    // reserved until the change applies or is cancelled.
+    pub fn request_email_change(
+        &mut self,
+        id: UserId,
+        email: &str,
+    ) -> Option<Result<User, UserError>> {
+        let taken = self.email_taken(email);
+        let user = self.users.get_mut(&id)?;
+        if taken {
+            return Some(Err(UserError::EmailTaken));
+        }
+        let replaced = user.pending_email_change.replace(PendingEmailChange {
+            email: Email::new(email),
+            requested_at: chrono::Utc::now(),
+            old_confirmed_at: None,
+            new_confirmed_at: None,
+        });
+        user.touch();
+        let user = user.clone();
+        if let Some(replaced) = replaced {
+            self.emails.remove(&email_key(replaced.email.as_str()));
+        }
+        self.emails.insert(email_key(email), id);
+        Some(Ok(user))
+    }
+
+    // Drops the pending change, if any, and frees the address it reserved.
+    pub fn cancel_email_change(&mut self, id: UserId) -> Option<User> {
+        let user = self.users.get_mut(&id)?;
+        if let Some(cancelled) = user.pending_email_change.take() {
+            user.touch();
+            self.emails.remove(&email_key(cancelled.email.as_str()));
+        }
+        self.users.get(&id).cloned()
+    }
+
+    // Stamps one side of the change requested at `requested_at`. Once both
+    // sides are in, the new address becomes the primary one, the old one is
+    // released and the flag comes back true. `None` when there is no such
+    // user or no such pending change.
+    pub fn confirm_email_change(
+        &mut self,
+        id: UserId,
+        side: EmailChangeSide,
+        requested_at: UtcDateTime,
+    ) -> Option<(User, bool)> {
+        let user = self.users.get_mut(&id)?;
+        let pending = user
+            .pending_email_change
+            .as_mut()
+            .filter(|pending| pending.requested_at == requested_at)?;
+        let confirmed_at = match side {
+            EmailChangeSide::Old => &mut pending.old_confirmed_at,
+            EmailChangeSide::New => &mut pending.new_confirmed_at,
+        };
+        confirmed_at.get_or_insert_with(chrono::Utc::now);
+        if !pending.is_confirmed() {
+            user.touch();
+            return Some((user.clone(), false));
+        }
+        let pending = user.pending_email_change.take()?;
+        let old = std::mem::replace(&mut user.email, pending.email);
+        user.verified_at = pending.new_confirmed_at;
+        user.touch();
+        let user = user.clone();
+        self.emails.remove(&email_key(old.as_str()));
+        Some((user, true))
+    }
+
+    pub fn merged(&self, id: &UserId) -> Option<&MergedUser> {
+        self.merged.get(id)
+    }
+}
+
+impl Deref for UserStore {
+    type Target = UserTable;
+
+    fn deref(&self) -> &UserTable {
+        &self.users
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UserDatabase {
-    db: Arc<Mutex<UserTable>>,
+    db: Arc<Mutex<UserStore>>,
+    ids: Arc<dyn IdGenerator>,
 }
 
-impl UserDatabase {
-    pub fn create_test_db() -> Self {
-        let mut users = HashMap::new();
-        let rnd = &mut rand::thread_rng();
+fn test_users() -> UserTable {
+    let mut users = HashMap::new();
+    let rnd = &mut rand::thread_rng();
 
-        let user = User::from(rnd, "Eric".into());
-        users.insert(user.id, user);
+    let user = User::from(rnd, "Eric".into());
+    users.insert(user.id, user);
 
-        let user = User::from(rnd, "Linus".into());
-        users.insert(user.id, user);
+    let user = User::from(rnd, "Linus".into());
+    users.insert(user.id, user);
 
-        let user = User::from(rnd, "Michelle".into());
-        users.insert(user.id, user);
+    let user = User::from(rnd, "Michelle".into());
+    users.insert(user.id, user);
 
-        let user = User::from(rnd, "Rogan".into());
-        users.insert(user.id, user);
+    let user = User::from(rnd, "Rogan".into());
+    users.insert(user.id, user);
 
-        let user = User::from(rnd, "Lily".into());
-        users.insert(user.id, user);
+    let user = User::from(rnd, "Lily".into());
+    users.insert(user.id, user);
 
-        let mut user = User::from(rnd, "Neo".into());
-        user.id = 1;
-        users.insert(1, user);
+    let mut user = User::from(rnd, "Neo".into());
+    user.id = 1;
+    users.insert(1, user);
 
-        let db = Arc::new(Mutex::new(users));
-        UserDatabase { db }
+    users
+}
+
+impl UserDatabase {
+    pub fn new(ids: Arc<dyn IdGenerator>) -> Self {
+        let db = Arc::new(Mutex::new(UserStore::from_table(HashMap::new())));
+        UserDatabase { db, ids }
+    }
+
+    pub fn create_test_db(ids: Arc<dyn IdGenerator>) -> Self {
+        let db = Arc::new(Mutex::new(UserStore::from_table(test_users())));
+        UserDatabase { db, ids }
     }
 
+    pub async fn reseed(&self) {
+        *self.lock().await = UserStore::from_table(test_users());
+    }
+
+    #[cfg(feature = "server")]
     pub fn inject(
         &self,
     ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
@@ -118,19 +738,13 @@ impl UserDatabase {
         warp::any().map(move || hanging_copy.clone())
     }
 
-    pub async fn lock(&self) -> MutexGuard<'_, UserTable> {
+    pub async fn lock(&self) -> MutexGuard<'_, UserStore> {
         self.db.lock().await
     }
 
-    pub async fn add_user(&self, built_user: UserBuilder) -> Result<(), ()> {
-        let real_user = built_user.build().ok_or(())?;
-        let mut users = self.lock().await;
-        let duplicate = users.values().any(|user| user.email == real_user.email);
-        if duplicate {
-            Err(())
-        } else {
-            users.insert(real_user.id, real_user);
-            Ok(())
-        }
+    pub async fn add_user(&self, built_user: UserBuilder) -> Result<UserId, UserError> {
+        let screen_lookalikes = built_user.screen_lookalikes;
+        let real_user = built_user.build(self.ids.next_id())?;
+        self.lock().await.insert(real_user, screen_lookalikes)
     }
 }
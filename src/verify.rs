@@ -1,96 +1,1290 @@
-use crate::user::{User, UserId};
-use hmac::Mac;
+use crate::core::{self, MacAlgorithm, MacAlgorithmSet, Purpose, TokenVersion};
+use crate::env_vars::{env_bool, env_list, env_secs};
+use crate::pii::Email;
+use crate::shadow;
+use crate::user::{NotificationTopic, PendingEmailChange, User, UserId};
+use secrecy::{ExposeSecret, SecretVec};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::env;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 
-type HmacSha3_256 = hmac::Hmac<sha3::Sha3_256>;
-type UtcDateTime = chrono::DateTime<chrono::Utc>;
+pub use crate::user::EmailChangeSide;
 
-const SECRET_KEY: &[u8; 19] = b"my super secret key";
+pub const MALFORMED_BASE64: &str = "malformed base64";
+
+pub type UtcDateTime = chrono::DateTime<chrono::Utc>;
+
+static SECRET_KEY: RwLock<Option<SecretVec<u8>>> = RwLock::new(None);
+static TOKEN_POLICY: RwLock<TokenPolicy> = RwLock::new(TokenPolicy::DEFAULT);
+
+pub const RESET_INTENT_COOKIE: &str = "reset_intent";
+pub const RESET_INTENT_LIFETIME: Duration = Duration::from_secs(10 * 60);
+pub const MERGE_CONFIRM_LIFETIME: Duration = Duration::from_secs(10 * 60);
+pub const REVERT_LINK_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const MAX_CLOCK_LEEWAY: Duration = Duration::from_secs(5 * 60);
+const MAX_LINK_TTL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenPolicy {
+    pub mac_algorithm: MacAlgorithm,
+    pub previous_mac_algorithms: MacAlgorithmSet,
+    pub reset_link_ttl: Duration,
+    pub max_reset_lifetime: Duration,
+    pub max_invite_age: Duration,
+    pub share_link_ttl: Duration,
+    pub verify_email_ttl: Duration,
+    pub email_change_ttl: Duration,
+    pub clock_leeway: Duration,
+    pub legacy_tokens_until: Option<SystemTime>,
+    pub encrypt_invite_email: bool,
+    pub invite_only: bool,
+}
+
+impl TokenPolicy {
+    const DEFAULT: TokenPolicy = TokenPolicy {
+        mac_algorithm: MacAlgorithm::Sha3_256,
+        previous_mac_algorithms: MacAlgorithmSet::EMPTY,
+        reset_link_ttl: Duration::from_secs(3 * 60 * 60),
+        max_reset_lifetime: Duration::from_secs(24 * 60 * 60),
+        max_invite_age: Duration::from_secs(7 * 24 * 60 * 60),
+        share_link_ttl: Duration::from_secs(24 * 60 * 60),
+        verify_email_ttl: Duration::from_secs(24 * 60 * 60),
+        email_change_ttl: Duration::from_secs(24 * 60 * 60),
+        clock_leeway: Duration::from_secs(30),
+        legacy_tokens_until: None,
+        encrypt_invite_email: false,
+        invite_only: false,
+    };
+
+    pub fn from_env() -> Result<Self, String> {
+        let mac_algorithm = match env::var("APP_MAC_ALGORITHM") {
+            Ok(name) => name
+                .parse()
+                .map_err(|err| format!("APP_MAC_ALGORITHM: {}", err))?,
+            Err(_) => MacAlgorithm::default(),
+        };
+        let mut previous_mac_algorithms = MacAlgorithmSet::EMPTY;
+        for name in env_list("APP_PREVIOUS_MAC_ALGORITHMS").unwrap_or_default() {
+            let algorithm = name
+                .parse()
+                .map_err(|err| format!("APP_PREVIOUS_MAC_ALGORITHMS: {}", err))?;
+            previous_mac_algorithms = previous_mac_algorithms.with(algorithm);
+        }
+        let mut clock_leeway =
+            env_secs("APP_CLOCK_LEEWAY_SECS")?.unwrap_or(Self::DEFAULT.clock_leeway);
+        if clock_leeway > MAX_CLOCK_LEEWAY {
+            eprintln!(
+                "APP_CLOCK_LEEWAY_SECS capped at {}s",
+                MAX_CLOCK_LEEWAY.as_secs()
+            );
+            clock_leeway = MAX_CLOCK_LEEWAY;
+        }
+        let legacy_tokens_until = match env::var("APP_LEGACY_TOKENS_UNTIL") {
+            Ok(until) => Some(
+                chrono::DateTime::parse_from_rfc3339(&until)
+                    .map(SystemTime::from)
+                    .map_err(|err| format!("APP_LEGACY_TOKENS_UNTIL: {}", err))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(TokenPolicy {
+            mac_algorithm,
+            previous_mac_algorithms,
+            clock_leeway,
+            legacy_tokens_until,
+            encrypt_invite_email: env_bool("APP_ENCRYPT_INVITE_EMAIL")
+                .unwrap_or(Self::DEFAULT.encrypt_invite_email),
+            invite_only: env_bool("APP_INVITE_ONLY").unwrap_or(Self::DEFAULT.invite_only),
+            reset_link_ttl: env_secs("APP_RESET_LINK_TTL_SECS")?
+                .unwrap_or(Self::DEFAULT.reset_link_ttl),
+            max_reset_lifetime: env_secs("APP_TOKEN_MAX_LIFETIME_SECS")?
+                .unwrap_or(Self::DEFAULT.max_reset_lifetime),
+            max_invite_age: env_secs("APP_INVITE_MAX_AGE_SECS")?
+                .unwrap_or(Self::DEFAULT.max_invite_age),
+            share_link_ttl: env_secs("APP_SHARE_LINK_TTL_SECS")?
+                .unwrap_or(Self::DEFAULT.share_link_ttl),
+            verify_email_ttl: env_secs("APP_VERIFY_EMAIL_TTL_SECS")?
+                .unwrap_or(Self::DEFAULT.verify_email_ttl),
+            email_change_ttl: env_secs("APP_EMAIL_CHANGE_TTL_SECS")?
+                .unwrap_or(Self::DEFAULT.email_change_ttl),
+        })
+    }
+
+    // Tokens in an older format are honoured only until
+    // `APP_LEGACY_TOKENS_UNTIL`. With no cutoff set, only the current format
+    // verifies.
+    fn accepts_version(&self, version: TokenVersion, now: SystemTime) -> bool {
+        version == TokenVersion::CURRENT
+            || self.legacy_tokens_until.is_some_and(|until| now < until)
+    }
+
+    // Only `APP_MAC_ALGORITHM` verifies, plus whatever is listed in
+    // `APP_PREVIOUS_MAC_ALGORITHMS` while links minted under an old one are
+    // still out there. The token's own algorithm byte is never enough.
+    fn accepts_algorithm(&self, algorithm: MacAlgorithm) -> bool {
+        algorithm == self.mac_algorithm || self.previous_mac_algorithms.contains(algorithm)
+    }
+
+    // Lifetimes that would make links useless. Checked at startup, along with
+    // the rest of the configuration.
+    pub fn problems(&self) -> Vec<String> {
+        let zero = Duration::from_secs(0);
+        let mut problems = Vec::new();
+        if self.reset_link_ttl == zero {
+            problems.push("APP_RESET_LINK_TTL_SECS must be more than zero".to_string());
+        }
+        if self.reset_link_ttl > self.max_reset_lifetime {
+            problems.push(format!(
+                "APP_RESET_LINK_TTL_SECS ({}s) is longer than APP_TOKEN_MAX_LIFETIME_SECS ({}s), so reset links would never verify",
+                self.reset_link_ttl.as_secs(),
+                self.max_reset_lifetime.as_secs()
+            ));
+        }
+        if self.max_invite_age == zero {
+            problems.push("APP_INVITE_MAX_AGE_SECS must be more than zero".to_string());
+        }
+        if self.share_link_ttl == zero {
+            problems.push("APP_SHARE_LINK_TTL_SECS must be more than zero".to_string());
+        }
+        if self.verify_email_ttl == zero {
+            problems.push("APP_VERIFY_EMAIL_TTL_SECS must be more than zero".to_string());
+        }
+        if self.email_change_ttl == zero {
+            problems.push("APP_EMAIL_CHANGE_TTL_SECS must be more than zero".to_string());
+        }
+        if self.reset_link_ttl > MAX_LINK_TTL
+            || self.max_invite_age > MAX_LINK_TTL
+            || self.share_link_ttl > MAX_LINK_TTL
+            || self.verify_email_ttl > MAX_LINK_TTL
+            || self.email_change_ttl > MAX_LINK_TTL
+        {
+            problems.push("link lifetimes are capped at 366 days".to_string());
+        }
+        problems
+    }
+}
+
+pub fn set_token_policy(policy: TokenPolicy) {
+    *TOKEN_POLICY.write().unwrap() = policy;
+}
+
+fn token_policy() -> TokenPolicy {
+    *TOKEN_POLICY.read().unwrap()
+}
+
+pub fn reset_link_ttl() -> Duration {
+    token_policy().reset_link_ttl
+}
+
+pub fn invite_link_ttl() -> Duration {
+    token_policy().max_invite_age
+}
+
+pub fn share_link_ttl() -> Duration {
+    token_policy().share_link_ttl
+}
+
+pub fn verify_email_ttl() -> Duration {
+    token_policy().verify_email_ttl
+}
+
+pub fn email_change_ttl() -> Duration {
+    token_policy().email_change_ttl
+}
+
+pub fn invite_only() -> bool {
+    token_policy().invite_only
+}
+
+fn mac_algorithm() -> MacAlgorithm {
+    token_policy().mac_algorithm
+}
+
+fn accepts_token(version: TokenVersion, algorithm: MacAlgorithm) -> bool {
+    let policy = token_policy();
+    policy.accepts_version(version, SystemTime::now()) && policy.accepts_algorithm(algorithm)
+}
+
+fn verify_token(kind: &'static str, purpose: Purpose, payload: &[&[u8]], token: &[u8]) -> bool {
+    with_secret_key(|key| {
+        let valid = core::verify_accepting(key, purpose, payload, token, accepts_token);
+        shadow::observe(kind, purpose, key, payload, token, accepts_token, valid);
+        valid
+    })
+}
+
+fn sign(purpose: Purpose, payload: &[&[u8]]) -> Vec<u8> {
+    with_secret_key(|key| core::sign(mac_algorithm(), key, purpose, payload))
+}
+
+fn check_lifetime(issued_at: SystemTime, expires: SystemTime, max_lifetime: Duration) -> bool {
+    let now = SystemTime::now();
+    let no_leeway = Duration::from_secs(0);
+    if core::within_lifetime(issued_at, expires, max_lifetime, no_leeway, now) {
+        return true;
+    }
+    let leeway = token_policy().clock_leeway;
+    let accepted = core::within_lifetime(issued_at, expires, max_lifetime, leeway, now);
+    if accepted {
+        eprintln!(
+            "accepted token within the {}s clock-skew leeway",
+            leeway.as_secs()
+        );
+    }
+    accepted
+}
+
+pub fn set_secret_key(key: SecretVec<u8>) {
+    *SECRET_KEY.write().unwrap() = Some(key);
+}
+
+pub struct PublicKeys {
+    pub purposes: Vec<(Purpose, [u8; 32])>,
+}
+
+pub fn public_keys() -> Option<PublicKeys> {
+    if !mac_algorithm().is_asymmetric() {
+        return None;
+    }
+    Some(with_secret_key(|key| PublicKeys {
+        purposes: Purpose::ALL
+            .iter()
+            .map(|purpose| (*purpose, core::purpose_public_key(key, *purpose)))
+            .collect(),
+    }))
+}
+
+fn with_secret_key<R>(f: impl FnOnce(&[u8]) -> R) -> R {
+    let key = SECRET_KEY.read().unwrap();
+    let key = key.as_ref().expect("secret key has not been loaded");
+    f(key.expose_secret())
+}
 
 fn as_base64<S: serde::Serializer>(key: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
-    serializer.serialize_str(&base64::encode(key))
+    serializer.serialize_str(&base64::encode_config(key, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_base64(string: &str) -> Result<Vec<u8>, String> {
+    // Older links used the standard alphabet, and a `+` that went through form
+    // decoding comes back as a space.
+    let normalized: String = string
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' | ' ' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+    base64::decode_config(&normalized, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| format!("{}: {}", MALFORMED_BASE64, err))
 }
 
 fn from_base64<'d, D: serde::Deserializer<'d>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
-    String::deserialize(deserializer).and_then(|string| {
-        base64::decode(string).map_err(|err| serde::de::Error::custom(err.to_string()))
+    String::deserialize(deserializer)
+        .and_then(|string| decode_base64(&string).map_err(serde::de::Error::custom))
+}
+
+fn as_optional_base64<S: serde::Serializer>(
+    value: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => as_base64(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn from_optional_base64<'d, D: serde::Deserializer<'d>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    Option::<String>::deserialize(deserializer).and_then(|string| {
+        string
+            .map(|string| decode_base64(&string).map_err(serde::de::Error::custom))
+            .transpose()
     })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CreateParams {
-    email: String,
+// `email` and `sealed_email` are exclusive; a sealed link carries the address
+// encrypted under the create purpose, with `iat` as associated data.
+#[derive(Serialize, Deserialize)]
+struct CreateParamsWire {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<Email>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "as_optional_base64",
+        deserialize_with = "from_optional_base64"
+    )]
+    sealed_email: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    invited: bool,
+    iat: UtcDateTime,
     #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
     token: Vec<u8>,
 }
 
-impl CreateParams {
-    fn accum_mac(email: &str) -> HmacSha3_256 {
-        let mut mac = HmacSha3_256::new_varkey(SECRET_KEY).unwrap();
-        mac.input(email.as_bytes());
-        mac
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "CreateParamsWire", try_from = "CreateParamsWire")]
+pub struct CreateParams {
+    email: Email,
+    sealed_email: Option<Vec<u8>>,
+    invited: bool,
+    iat: UtcDateTime,
+    token: Vec<u8>,
+}
+
+impl From<CreateParams> for CreateParamsWire {
+    fn from(params: CreateParams) -> Self {
+        let CreateParams {
+            email,
+            sealed_email,
+            invited,
+            iat,
+            token,
+        } = params;
+        CreateParamsWire {
+            email: Some(email).filter(|_| sealed_email.is_none()),
+            sealed_email,
+            invited,
+            iat,
+            token,
+        }
     }
+}
+
+impl TryFrom<CreateParamsWire> for CreateParams {
+    type Error = String;
 
+    fn try_from(wire: CreateParamsWire) -> Result<Self, Self::Error> {
+        let email = match (wire.email, &wire.sealed_email) {
+            (Some(email), None) => email,
+            (None, Some(sealed)) => {
+                let iat = wire.iat.to_string().into_bytes();
+                with_secret_key(|key| core::open(key, Purpose::Create, &iat, sealed))
+                    .and_then(|email| String::from_utf8(email).ok())
+                    .map(Email::new)
+                    .ok_or_else(|| "the email could not be decrypted".to_string())?
+            }
+            (Some(_), Some(_)) => {
+                return Err("only one of `email` and `sealed_email` may be given".to_string())
+            }
+            (None, None) => return Err("missing field `email`".to_string()),
+        };
+        Ok(CreateParams {
+            email,
+            sealed_email: wire.sealed_email,
+            invited: wire.invited,
+            iat: wire.iat,
+            token: wire.token,
+        })
+    }
+}
+
+impl CreateParams {
     pub fn email(&self) -> &str {
-        &self.email
+        self.email.as_str()
+    }
+
+    pub fn expires(&self) -> UtcDateTime {
+        self.iat
+            + chrono::Duration::from_std(invite_link_ttl()).expect("invite link TTL out of range")
+    }
+
+    // Public signup links sign exactly what they always did, so links minted
+    // before the `invited` claim existed still verify.
+    fn payload<'a>(email: &'a str, iat: &'a [u8], invited: bool) -> Vec<&'a [u8]> {
+        let mut payload = vec![email.as_bytes(), iat];
+        if invited {
+            payload.push(b"invited");
+        }
+        payload
     }
 
     pub fn verify(email: &str, params: &Self) -> bool {
-        let mac = Self::accum_mac(email);
-        mac.verify(params.token.as_slice()).is_ok()
+        let policy = token_policy();
+        if policy.invite_only && !params.invited {
+            return false;
+        }
+        let max_age = policy.max_invite_age;
+        let issued_at = SystemTime::from(params.iat);
+        let iat = params.iat.to_string().into_bytes();
+        check_lifetime(issued_at, issued_at + max_age, max_age)
+            && verify_token(
+                "create",
+                Purpose::Create,
+                &Self::payload(email, &iat, params.invited),
+                &params.token,
+            )
     }
-}
 
-impl From<&str> for CreateParams {
-    fn from(email: &str) -> Self {
-        let mac = CreateParams::accum_mac(email);
-        let token = Vec::from(mac.result().code().as_slice());
+    pub fn invite(email: &str) -> Self {
+        CreateParams::mint(email, token_policy().encrypt_invite_email, true)
+    }
+
+    fn mint(email: &str, seal: bool, invited: bool) -> Self {
+        let iat = chrono::Utc::now();
+        let iat_bytes = iat.to_string().into_bytes();
+        let token = sign(Purpose::Create, &Self::payload(email, &iat_bytes, invited));
+        let sealed_email = if seal {
+            Some(with_secret_key(|key| {
+                core::seal(key, Purpose::Create, &iat_bytes, email.as_bytes())
+            }))
+        } else {
+            None
+        };
         CreateParams {
-            email: email.to_string(),
+            email: Email::new(email),
+            sealed_email,
+            invited,
+            iat,
             token,
         }
     }
 }
 
+impl From<&str> for CreateParams {
+    fn from(email: &str) -> Self {
+        CreateParams::mint(email, token_policy().encrypt_invite_email, false)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResetParams {
     user_id: UserId,
+    iat: UtcDateTime,
     expires: UtcDateTime,
     #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
     token: Vec<u8>,
 }
 
 impl ResetParams {
-    fn accum_mac(user: &User, expires: &UtcDateTime) -> HmacSha3_256 {
-        let mut mac = HmacSha3_256::new_varkey(SECRET_KEY).unwrap();
-        mac.input(&user.id.to_string().into_bytes());
-        mac.input(&expires.to_string().into_bytes());
-        mac
+    fn payload(user: &User, iat: &UtcDateTime, expires: &UtcDateTime) -> [Vec<u8>; 3] {
+        [
+            user.id.to_string().into_bytes(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
     }
 
     pub fn user_id(&self) -> UserId {
         self.user_id
     }
 
+    pub fn expires(&self) -> UtcDateTime {
+        self.expires
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    fn intent_payload(&self, until: u64) -> [Vec<u8>; 4] {
+        [
+            b"reset-intent".to_vec(),
+            self.user_id.to_string().into_bytes(),
+            self.token.clone(),
+            until.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn intent(&self) -> String {
+        let until = (SystemTime::now() + RESET_INTENT_LIFETIME)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let [kind, id, token, until_bytes] = self.intent_payload(until);
+        let mac = sign(Purpose::ResetIntent, &[&kind, &id, &token, &until_bytes]);
+        format!(
+            "{}.{}",
+            until,
+            base64::encode_config(mac, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn verify_intent(&self, intent: &str) -> bool {
+        let (until, mac) = match intent.split_once('.') {
+            Some((until, mac)) => (until, mac),
+            None => return false,
+        };
+        let until: u64 = match until.parse() {
+            Ok(until) => until,
+            Err(_) => return false,
+        };
+        let mac = match base64::decode_config(mac, base64::URL_SAFE_NO_PAD) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let [kind, id, token, until_bytes] = self.intent_payload(until);
+        now <= until
+            && verify_token(
+                "reset_intent",
+                Purpose::ResetIntent,
+                &[&kind, &id, &token, &until_bytes],
+                &mac,
+            )
+    }
+
     pub fn verify(user: &User, params: &Self) -> bool {
-        let expires = params.expires;
-        if chrono::Utc::now() > expires {
-            return false;
-        }
-        let mac = Self::accum_mac(user, &expires);
-        mac.verify(params.token.as_slice()).is_ok()
+        let [id, expires, iat] = Self::payload(user, &params.iat, &params.expires);
+        check_lifetime(
+            params.iat.into(),
+            params.expires.into(),
+            token_policy().max_reset_lifetime,
+        ) && verify_token(
+            "reset",
+            Purpose::Reset,
+            &[&id, &expires, &iat],
+            &params.token,
+        )
     }
 }
 
 impl From<&User> for ResetParams {
     fn from(user: &User) -> Self {
-        let expires = chrono::Utc::now() + chrono::Duration::hours(3);
-        let mac = Self::accum_mac(user, &expires);
-        let token = Vec::from(mac.result().code().as_slice());
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(reset_link_ttl()).expect("reset link TTL out of range");
+        let [id, expires_bytes, iat_bytes] = Self::payload(user, &iat, &expires);
+        let token = sign(Purpose::Reset, &[&id, &expires_bytes, &iat_bytes]);
         ResetParams {
             user_id: user.id,
+            iat,
             expires,
             token,
         }
     }
 }
+
+// Read-only access to the user list, optionally narrowed to users whose name,
+// username or email contains `filter`. Not tied to any user, so the only way
+// to withdraw one early is to rotate the secret key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
+    iat: UtcDateTime,
+    expires: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl ShareParams {
+    fn payload(filter: Option<&str>, iat: &UtcDateTime, expires: &UtcDateTime) -> [Vec<u8>; 3] {
+        [
+            filter.unwrap_or("").as_bytes().to_vec(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn mint(filter: Option<&str>) -> Self {
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(share_link_ttl()).expect("share link TTL out of range");
+        let [filter_bytes, expires_bytes, iat_bytes] = Self::payload(filter, &iat, &expires);
+        ShareParams {
+            filter: filter.map(str::to_string),
+            iat,
+            expires,
+            token: sign(Purpose::Share, &[&filter_bytes, &expires_bytes, &iat_bytes]),
+        }
+    }
+
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_deref()
+    }
+
+    pub fn expires(&self) -> UtcDateTime {
+        self.expires
+    }
+
+    pub fn verify(params: &Self) -> bool {
+        let [filter, expires, iat] = Self::payload(params.filter(), &params.iat, &params.expires);
+        check_lifetime(
+            params.iat.into(),
+            params.expires.into(),
+            token_policy().share_link_ttl,
+        ) && verify_token(
+            "share",
+            Purpose::Share,
+            &[&filter, &expires, &iat],
+            &params.token,
+        )
+    }
+}
+
+// Goes in the footer and `List-Unsubscribe` header of each message, so it
+// has no expiry: a link in an old email should still work. It only ever
+// turns a topic off.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsubscribeParams {
+    user_id: UserId,
+    topic: NotificationTopic,
+    iat: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl UnsubscribeParams {
+    fn payload(user_id: UserId, topic: NotificationTopic, iat: &UtcDateTime) -> [Vec<u8>; 3] {
+        [
+            user_id.to_string().into_bytes(),
+            topic.name().as_bytes().to_vec(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn new(user_id: UserId, topic: NotificationTopic) -> Self {
+        let iat = chrono::Utc::now();
+        let [id, topic_bytes, iat_bytes] = Self::payload(user_id, topic, &iat);
+        UnsubscribeParams {
+            user_id,
+            topic,
+            iat,
+            token: sign(Purpose::Unsubscribe, &[&id, &topic_bytes, &iat_bytes]),
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn topic(&self) -> NotificationTopic {
+        self.topic
+    }
+
+    pub fn verify(params: &Self) -> bool {
+        let [id, topic, iat] = Self::payload(params.user_id, params.topic, &params.iat);
+        verify_token(
+            "unsubscribe",
+            Purpose::Unsubscribe,
+            &[&id, &topic, &iat],
+            &params.token,
+        )
+    }
+}
+
+// Carries an admin's choice of accounts from the merge form to the
+// confirmation step, so what gets merged is exactly what was shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeParams {
+    primary: UserId,
+    duplicate: UserId,
+    iat: UtcDateTime,
+    expires: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl MergeParams {
+    fn payload(
+        primary: UserId,
+        duplicate: UserId,
+        iat: &UtcDateTime,
+        expires: &UtcDateTime,
+    ) -> [Vec<u8>; 4] {
+        [
+            primary.to_string().into_bytes(),
+            duplicate.to_string().into_bytes(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn new(primary: UserId, duplicate: UserId) -> Self {
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(MERGE_CONFIRM_LIFETIME)
+                .expect("merge confirmation lifetime out of range");
+        let [primary_bytes, duplicate_bytes, expires_bytes, iat_bytes] =
+            Self::payload(primary, duplicate, &iat, &expires);
+        MergeParams {
+            primary,
+            duplicate,
+            iat,
+            expires,
+            token: sign(
+                Purpose::Merge,
+                &[&primary_bytes, &duplicate_bytes, &expires_bytes, &iat_bytes],
+            ),
+        }
+    }
+
+    pub fn primary(&self) -> UserId {
+        self.primary
+    }
+
+    pub fn duplicate(&self) -> UserId {
+        self.duplicate
+    }
+
+    pub fn verify(params: &Self) -> bool {
+        let [primary, duplicate, expires, iat] = Self::payload(
+            params.primary,
+            params.duplicate,
+            &params.iat,
+            &params.expires,
+        );
+        check_lifetime(
+            params.iat.into(),
+            params.expires.into(),
+            MERGE_CONFIRM_LIFETIME,
+        ) && verify_token(
+            "merge",
+            Purpose::Merge,
+            &[&primary, &duplicate, &expires, &iat],
+            &params.token,
+        )
+    }
+}
+
+// Proves the holder can read mail sent to `email`, one of `user_id`'s
+// addresses. Following it marks just that address verified.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailParams {
+    user_id: UserId,
+    email: Email,
+    iat: UtcDateTime,
+    expires: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl VerifyEmailParams {
+    fn payload(
+        user_id: UserId,
+        email: &Email,
+        iat: &UtcDateTime,
+        expires: &UtcDateTime,
+    ) -> [Vec<u8>; 4] {
+        [
+            user_id.to_string().into_bytes(),
+            email.as_str().as_bytes().to_vec(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn new(user_id: UserId, email: Email) -> Self {
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(verify_email_ttl())
+                .expect("email verification link TTL out of range");
+        let [id, email_bytes, expires_bytes, iat_bytes] =
+            Self::payload(user_id, &email, &iat, &expires);
+        VerifyEmailParams {
+            user_id,
+            email,
+            iat,
+            expires,
+            token: sign(
+                Purpose::VerifyEmail,
+                &[&id, &email_bytes, &expires_bytes, &iat_bytes],
+            ),
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn email(&self) -> &Email {
+        &self.email
+    }
+
+    pub fn expires(&self) -> UtcDateTime {
+        self.expires
+    }
+
+    pub fn verify(params: &Self) -> bool {
+        let [id, email, expires, iat] =
+            Self::payload(params.user_id, &params.email, &params.iat, &params.expires);
+        check_lifetime(params.iat.into(), params.expires.into(), verify_email_ttl())
+            && verify_token(
+                "verify_email",
+                Purpose::VerifyEmail,
+                &[&id, &email, &expires, &iat],
+                &params.token,
+            )
+    }
+}
+
+// One of the two links an email change sends out, to the current address or
+// the new one. Bound to the pending change's request time, so asking again or
+// cancelling kills the links already sent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailChangeParams {
+    user_id: UserId,
+    side: EmailChangeSide,
+    email: Email,
+    requested_at: UtcDateTime,
+    iat: UtcDateTime,
+    expires: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl EmailChangeParams {
+    // The side leads, so these never sign the same bytes as a
+    // `VerifyEmailParams`, which starts with the user id.
+    fn payload(
+        user_id: UserId,
+        side: EmailChangeSide,
+        email: &Email,
+        requested_at: &UtcDateTime,
+        iat: &UtcDateTime,
+        expires: &UtcDateTime,
+    ) -> [Vec<u8>; 6] {
+        [
+            format!("email-change-{}:", side.name()).into_bytes(),
+            user_id.to_string().into_bytes(),
+            email.as_str().as_bytes().to_vec(),
+            requested_at.to_string().into_bytes(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn new(user_id: UserId, side: EmailChangeSide, pending: &PendingEmailChange) -> Self {
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(email_change_ttl())
+                .expect("email change link TTL out of range");
+        let [side_bytes, id, email, requested_at, expires_bytes, iat_bytes] = Self::payload(
+            user_id,
+            side,
+            &pending.email,
+            &pending.requested_at,
+            &iat,
+            &expires,
+        );
+        EmailChangeParams {
+            user_id,
+            side,
+            email: pending.email.clone(),
+            requested_at: pending.requested_at,
+            iat,
+            expires,
+            token: sign(
+                Purpose::EmailChange,
+                &[
+                    &side_bytes,
+                    &id,
+                    &email,
+                    &requested_at,
+                    &expires_bytes,
+                    &iat_bytes,
+                ],
+            ),
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn side(&self) -> EmailChangeSide {
+        self.side
+    }
+
+    pub fn requested_at(&self) -> UtcDateTime {
+        self.requested_at
+    }
+
+    pub fn expires(&self) -> UtcDateTime {
+        self.expires
+    }
+
+    pub fn verify(params: &Self) -> bool {
+        let [side, id, email, requested_at, expires, iat] = Self::payload(
+            params.user_id,
+            params.side,
+            &params.email,
+            &params.requested_at,
+            &params.iat,
+            &params.expires,
+        );
+        let kind = match params.side {
+            EmailChangeSide::Old => "email_change_old",
+            EmailChangeSide::New => "email_change_new",
+        };
+        check_lifetime(params.iat.into(), params.expires.into(), email_change_ttl())
+            && verify_token(
+                kind,
+                Purpose::EmailChange,
+                &[&side, &id, &email, &requested_at, &expires, &iat],
+                &params.token,
+            )
+    }
+}
+
+// Sent to the account's address after a password reset, so the owner can undo
+// a reset they didn't make. Bound to that reset's timestamp: once the
+// password changes again, by a revert or anything else, the link is dead.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevertParams {
+    user_id: UserId,
+    changed_at: UtcDateTime,
+    iat: UtcDateTime,
+    expires: UtcDateTime,
+    #[serde(serialize_with = "as_base64", deserialize_with = "from_base64")]
+    token: Vec<u8>,
+}
+
+impl RevertParams {
+    fn payload(
+        user_id: UserId,
+        changed_at: &UtcDateTime,
+        iat: &UtcDateTime,
+        expires: &UtcDateTime,
+    ) -> [Vec<u8>; 4] {
+        [
+            user_id.to_string().into_bytes(),
+            changed_at.to_string().into_bytes(),
+            expires.to_string().into_bytes(),
+            iat.to_string().into_bytes(),
+        ]
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn verify(user: &User, params: &Self) -> bool {
+        let [id, changed_at, expires, iat] = Self::payload(
+            user.id,
+            &user.password_changed_at,
+            &params.iat,
+            &params.expires,
+        );
+        check_lifetime(
+            params.iat.into(),
+            params.expires.into(),
+            REVERT_LINK_LIFETIME,
+        ) && verify_token(
+            "revert",
+            Purpose::Revert,
+            &[&id, &changed_at, &expires, &iat],
+            &params.token,
+        )
+    }
+}
+
+impl From<&User> for RevertParams {
+    fn from(user: &User) -> Self {
+        let iat = chrono::Utc::now();
+        let expires = iat
+            + chrono::Duration::from_std(REVERT_LINK_LIFETIME)
+                .expect("revert link lifetime out of range");
+        let [id, changed_at, expires_bytes, iat_bytes] =
+            Self::payload(user.id, &user.password_changed_at, &iat, &expires);
+        RevertParams {
+            user_id: user.id,
+            changed_at: user.password_changed_at,
+            iat,
+            expires,
+            token: sign(
+                Purpose::Revert,
+                &[&id, &changed_at, &expires_bytes, &iat_bytes],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use proptest::prelude::*;
+
+    // 0000-01-01T00:00:00Z through 9999-12-31T23:59:59Z, the range RFC 3339 can spell.
+    const MIN_TIMESTAMP: i64 = -62_167_219_200;
+    const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+    fn timestamp() -> impl Strategy<Value = UtcDateTime> {
+        let secs = prop_oneof![
+            Just(MIN_TIMESTAMP),
+            Just(0),
+            Just(MAX_TIMESTAMP),
+            MIN_TIMESTAMP..=MAX_TIMESTAMP,
+        ];
+        let nanos = prop_oneof![Just(0), Just(999_999_999), 0..1_000_000_000u32];
+        (secs, nanos).prop_map(|(secs, nanos)| chrono::Utc.timestamp_opt(secs, nanos).unwrap())
+    }
+
+    fn email() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z0-9._%+-]{1,16}@[a-z0-9.-]{1,16}",
+            r#"[ +&=#?%/"<>\\]{0,8}@[^\x00]{0,8}"#,
+            any::<String>(),
+        ]
+    }
+
+    fn round_trip<T: Serialize + serde::de::DeserializeOwned>(params: &T) -> T {
+        let query = serde_urlencoded::to_string(params).unwrap();
+        serde_urlencoded::from_str(&query).unwrap()
+    }
+
+    fn test_user(id: UserId) -> User {
+        User {
+            id,
+            name: "Test".into(),
+            username: "test".into(),
+            email: Email::new("test@example.com"),
+            bcrypt_password: crate::pii::PasswordHash::new(String::new()),
+            has_avatar: false,
+            tos_accepted: None,
+            lookalike_of: None,
+            created_at: chrono::Utc::now(),
+            version: 1,
+            verified_at: None,
+            preferences: Default::default(),
+            email_aliases: Vec::new(),
+            password_changed_at: chrono::Utc::now(),
+            rotation_requested_at: None,
+            previous_password: None,
+            locked_at: None,
+            pending_email_change: None,
+            reported_at: None,
+        }
+    }
+
+    fn with_test_key() {
+        set_secret_key(SecretVec::new(b"verify property test key".to_vec()));
+    }
+
+    #[test]
+    fn legacy_tokens_need_an_open_window() {
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(60 * 60);
+        let policy = |until| TokenPolicy {
+            legacy_tokens_until: until,
+            ..TokenPolicy::DEFAULT
+        };
+        for version in &[TokenVersion::V0, TokenVersion::V1] {
+            assert!(!policy(None).accepts_version(*version, now));
+            assert!(!policy(Some(now - hour)).accepts_version(*version, now));
+            assert!(policy(Some(now + hour)).accepts_version(*version, now));
+        }
+        assert!(policy(None).accepts_version(TokenVersion::CURRENT, now));
+        assert!(policy(Some(now - hour)).accepts_version(TokenVersion::CURRENT, now));
+    }
+
+    #[test]
+    fn tokens_without_a_purpose_never_verify() {
+        // A bare SHA3-256 MAC under the master key, as V0 links carried.
+        let key = b"legacy purpose test key";
+        let payload: [&[u8]; 2] = [b"42", b"2020-01-01T00:00:00Z"];
+        let mut mac = <hmac::Hmac<sha3::Sha3_256> as hmac::Mac>::new_varkey(key).unwrap();
+        for part in &payload {
+            hmac::Mac::input(&mut mac, part);
+        }
+        let v0_token = hmac::Mac::result(mac).code().to_vec();
+        assert_eq!(core::token_version(&v0_token), Some(TokenVersion::V0));
+        for purpose in Purpose::ALL.iter() {
+            assert!(!core::verify_accepting(
+                key,
+                *purpose,
+                &payload,
+                &v0_token,
+                |_, _| true
+            ));
+        }
+    }
+
+    #[test]
+    fn reset_tokens_do_not_verify_as_revert_tokens() {
+        with_test_key();
+        let user = test_user(7);
+        let mut params = RevertParams::from(&user);
+        assert!(RevertParams::verify(&user, &params));
+        let [id, changed_at, expires, iat] = RevertParams::payload(
+            user.id,
+            &user.password_changed_at,
+            &params.iat,
+            &params.expires,
+        );
+        params.token = sign(Purpose::Reset, &[&id, &changed_at, &expires, &iat]);
+        assert!(!RevertParams::verify(&user, &params));
+    }
+
+    #[test]
+    fn only_the_configured_algorithm_verifies() {
+        let key = b"algorithm test key";
+        let payload: [&[u8]; 1] = [b"42"];
+        let token = core::sign(MacAlgorithm::Sha256, key, Purpose::Reset, &payload);
+        assert!(core::verify(
+            MacAlgorithm::Sha256,
+            key,
+            Purpose::Reset,
+            &payload,
+            &token
+        ));
+        assert!(!core::verify(
+            MacAlgorithm::Sha3_256,
+            key,
+            Purpose::Reset,
+            &payload,
+            &token
+        ));
+
+        let policy = TokenPolicy::DEFAULT;
+        assert!(!policy.accepts_algorithm(MacAlgorithm::Sha256));
+        let migrating = TokenPolicy {
+            previous_mac_algorithms: MacAlgorithmSet::EMPTY.with(MacAlgorithm::Sha256),
+            ..policy
+        };
+        assert!(migrating.accepts_algorithm(MacAlgorithm::Sha256));
+        assert!(migrating.accepts_algorithm(MacAlgorithm::Sha3_256));
+        assert!(!migrating.accepts_algorithm(MacAlgorithm::Blake3));
+    }
+
+    #[test]
+    fn moving_bytes_between_parts_breaks_the_token() {
+        let key = b"framing test key";
+        let token = core::sign(
+            MacAlgorithm::Sha3_256,
+            key,
+            Purpose::VerifyEmail,
+            &[b"1", b"23@x.com"],
+        );
+        assert!(core::verify(
+            MacAlgorithm::Sha3_256,
+            key,
+            Purpose::VerifyEmail,
+            &[b"1", b"23@x.com"],
+            &token
+        ));
+        assert!(!core::verify(
+            MacAlgorithm::Sha3_256,
+            key,
+            Purpose::VerifyEmail,
+            &[b"12", b"3@x.com"],
+            &token
+        ));
+        assert!(!core::verify(
+            MacAlgorithm::Sha3_256,
+            key,
+            Purpose::EmailChange,
+            &[b"1", b"23@x.com"],
+            &token
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn create_params_round_trip(
+            email in email(),
+            iat in timestamp(),
+            invited in any::<bool>(),
+            token in prop::collection::vec(any::<u8>(), 0..80),
+        ) {
+            let params = CreateParams {
+                email: Email::new(email),
+                sealed_email: None,
+                invited,
+                iat,
+                token,
+            };
+            let parsed = round_trip(&params);
+            prop_assert_eq!(parsed.email, params.email);
+            prop_assert_eq!(parsed.iat, params.iat);
+            prop_assert_eq!(parsed.token, params.token);
+        }
+
+        #[test]
+        fn sealed_create_params_round_trip(email in email()) {
+            with_test_key();
+            let params = CreateParams::mint(&email, true, false);
+            let query = serde_urlencoded::to_string(&params).unwrap();
+            prop_assert!(!query.split('&').any(|pair| pair.starts_with("email=")));
+            let parsed: CreateParams = serde_urlencoded::from_str(&query).unwrap();
+            prop_assert_eq!(parsed.email(), email.as_str());
+            prop_assert!(CreateParams::verify(&email, &parsed));
+        }
+
+        #[test]
+        fn invited_claim_is_signed(email in email()) {
+            with_test_key();
+            let invited = CreateParams::mint(&email, false, true);
+            let query = serde_urlencoded::to_string(&invited).unwrap();
+            let parsed: CreateParams = serde_urlencoded::from_str(&query).unwrap();
+            prop_assert!(CreateParams::verify(&email, &parsed));
+            let stripped = CreateParams { invited: false, ..parsed };
+            prop_assert!(!CreateParams::verify(&email, &stripped));
+            let public = CreateParams::mint(&email, false, false);
+            let forged = CreateParams { invited: true, ..public };
+            prop_assert!(!CreateParams::verify(&email, &forged));
+        }
+
+        #[test]
+        fn reset_params_round_trip(
+            user_id in any::<UserId>(),
+            iat in timestamp(),
+            expires in timestamp(),
+            token in prop::collection::vec(any::<u8>(), 0..80),
+        ) {
+            let params = ResetParams { user_id, iat, expires, token };
+            let parsed = round_trip(&params);
+            prop_assert_eq!(parsed.user_id, params.user_id);
+            prop_assert_eq!(parsed.iat, params.iat);
+            prop_assert_eq!(parsed.expires, params.expires);
+            prop_assert_eq!(parsed.token, params.token);
+        }
+
+        #[test]
+        fn mutated_create_token_fails(
+            email in email(),
+            index in any::<prop::sample::Index>(),
+            flip in 1..=255u8,
+        ) {
+            with_test_key();
+            let mut params = CreateParams::from(email.as_str());
+            prop_assert!(CreateParams::verify(&email, &params));
+            let index = index.index(params.token.len());
+            params.token[index] ^= flip;
+            prop_assert!(!CreateParams::verify(&email, &params));
+        }
+
+        #[test]
+        fn mutated_reset_token_fails(
+            user_id in any::<UserId>(),
+            index in any::<prop::sample::Index>(),
+            flip in 1..=255u8,
+        ) {
+            with_test_key();
+            let user = test_user(user_id);
+            let mut params = ResetParams::from(&user);
+            prop_assert!(ResetParams::verify(&user, &params));
+            let index = index.index(params.token.len());
+            params.token[index] ^= flip;
+            prop_assert!(!ResetParams::verify(&user, &params));
+        }
+
+        #[test]
+        fn tokens_only_verify_for_their_purpose(
+            payload in prop::collection::vec(any::<u8>(), 0..64),
+            signed_for in any::<prop::sample::Index>(),
+            checked_for in any::<prop::sample::Index>(),
+        ) {
+            with_test_key();
+            let signed_for = Purpose::ALL[signed_for.index(Purpose::ALL.len())];
+            let checked_for = Purpose::ALL[checked_for.index(Purpose::ALL.len())];
+            let token = sign(signed_for, &[&payload]);
+            prop_assert_eq!(
+                verify_token("test", checked_for, &[&payload], &token),
+                signed_for == checked_for
+            );
+        }
+    }
+}
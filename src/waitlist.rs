@@ -0,0 +1,93 @@
+use crate::env_vars::env_bool;
+use crate::pii::Email;
+use crate::verify::UtcDateTime;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::Filter;
+
+#[derive(Debug, Clone)]
+pub struct WaitlistEntry {
+    pub email: Email,
+    pub joined: UtcDateTime,
+}
+
+#[derive(Debug)]
+struct Registration {
+    open: bool,
+    waiting: VecDeque<WaitlistEntry>,
+}
+
+// Whether `/new-user` mints signup links right away or queues the address
+// for an admin to invite later. Unlike `Feature::OpenRegistration` this can
+// be flipped while the server is running.
+#[derive(Debug, Clone)]
+pub struct Waitlist {
+    registration: Arc<Mutex<Registration>>,
+}
+
+fn same_address(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+impl Waitlist {
+    pub fn new(open: bool) -> Self {
+        Waitlist {
+            registration: Arc::new(Mutex::new(Registration {
+                open,
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Waitlist::new(env_bool("APP_REGISTRATION_OPEN").unwrap_or(true))
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub async fn is_open(&self) -> bool {
+        self.registration.lock().await.open
+    }
+
+    pub async fn set_open(&self, open: bool) {
+        self.registration.lock().await.open = open;
+    }
+
+    pub async fn join(&self, email: &str) -> bool {
+        let mut registration = self.registration.lock().await;
+        if registration
+            .waiting
+            .iter()
+            .any(|entry| same_address(entry.email.as_str(), email))
+        {
+            return false;
+        }
+        registration.waiting.push_back(WaitlistEntry {
+            email: Email::new(email.trim()),
+            joined: chrono::Utc::now(),
+        });
+        true
+    }
+
+    pub async fn entries(&self) -> Vec<WaitlistEntry> {
+        self.registration
+            .lock()
+            .await
+            .waiting
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn take(&self, count: usize) -> Vec<WaitlistEntry> {
+        let mut registration = self.registration.lock().await;
+        let count = count.min(registration.waiting.len());
+        registration.waiting.drain(..count).collect()
+    }
+}
@@ -0,0 +1,101 @@
+use hmac::Mac;
+use std::time::Duration;
+
+// Each webhook request carries `X-Webhook-Signature: t=<unix seconds>,v1=<hex>`,
+// where the hex is HMAC-SHA256 over `<unix seconds>.<raw body>` keyed with the
+// endpoint's secret. Receivers should recompute it over the exact bytes they
+// received, compare in constant time, and reject timestamps more than a few
+// minutes from their own clock to stop replays. `verify` does all of that.
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+fn mac(secret: &[u8], timestamp: i64, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret).unwrap();
+    mac.input(timestamp.to_string().as_bytes());
+    mac.input(b".");
+    mac.input(body);
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let code = mac(secret, timestamp, body).result().code();
+    format!("t={},v1={}", timestamp, to_hex(&code))
+}
+
+pub fn verify(secret: &[u8], header: &str, body: &[u8], now: i64, tolerance: Duration) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.extend(from_hex(value)),
+            _ => {}
+        }
+    }
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    if (now - timestamp).unsigned_abs() > tolerance.as_secs() {
+        return false;
+    }
+    signatures
+        .iter()
+        .any(|signature| mac(secret, timestamp, body).verify(signature).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"endpoint secret";
+    const BODY: &[u8] = br#"{"kind":"panic"}"#;
+
+    #[test]
+    fn signed_payloads_verify() {
+        let header = sign(SECRET, 1_600_000_000, BODY);
+        assert!(header.starts_with("t=1600000000,v1="));
+        assert!(verify(
+            SECRET,
+            &header,
+            BODY,
+            1_600_000_060,
+            DEFAULT_TOLERANCE
+        ));
+    }
+
+    #[test]
+    fn tampering_and_replays_are_rejected() {
+        let header = sign(SECRET, 1_600_000_000, BODY);
+        let now = 1_600_000_000;
+        assert!(!verify(SECRET, &header, b"{}", now, DEFAULT_TOLERANCE));
+        assert!(!verify(
+            b"other secret",
+            &header,
+            BODY,
+            now,
+            DEFAULT_TOLERANCE
+        ));
+        let moved = header.replace("t=1600000000", "t=1600000001");
+        assert!(!verify(SECRET, &moved, BODY, now, DEFAULT_TOLERANCE));
+        assert!(!verify(SECRET, &header, BODY, now + 301, DEFAULT_TOLERANCE));
+        assert!(!verify(SECRET, "v1=00", BODY, now, DEFAULT_TOLERANCE));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::core::Purpose;
+use crate::env_vars::env_list;
+use std::env;
+use warp::Filter;
+
+const DEFAULT_ROBOTS_DISALLOW: &[&str] = &[
+    "/reset-password",
+    "/create-user",
+    "/new-user",
+    "/shared",
+    "/unsubscribe",
+    "/verify-email",
+    "/confirm-email-change",
+    "/revert",
+    "/report",
+];
+
+#[derive(Debug, Clone)]
+pub struct WellKnown {
+    security_contact: Option<String>,
+    security_expires: Option<String>,
+    security_policy: Option<String>,
+    robots_disallow: Vec<String>,
+}
+
+impl WellKnown {
+    pub fn from_env() -> Self {
+        WellKnown {
+            security_contact: env::var("APP_SECURITY_CONTACT").ok(),
+            security_expires: env::var("APP_SECURITY_EXPIRES").ok(),
+            security_policy: env::var("APP_SECURITY_POLICY").ok(),
+            robots_disallow: env_list("APP_ROBOTS_DISALLOW").unwrap_or_else(|| {
+                DEFAULT_ROBOTS_DISALLOW
+                    .iter()
+                    .map(|path| path.to_string())
+                    .collect()
+            }),
+        }
+    }
+
+    pub fn inject(
+        &self,
+    ) -> impl Filter<Extract = (Self,), Error = std::convert::Infallible> + Clone {
+        let hanging_copy = self.clone();
+        warp::any().map(move || hanging_copy.clone())
+    }
+
+    pub fn security_txt(&self) -> Option<String> {
+        let contact = self.security_contact.as_ref()?;
+        let expires = self.security_expires.clone().unwrap_or_else(|| {
+            (chrono::Utc::now() + chrono::Duration::days(365))
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        });
+        let mut body = format!("Contact: {}\nExpires: {}\n", contact, expires);
+        if let Some(policy) = &self.security_policy {
+            body.push_str(&format!("Policy: {}\n", policy));
+        }
+        Some(body)
+    }
+
+    pub fn robots_txt(&self) -> String {
+        let mut body = String::from("User-agent: *\n");
+        for path in &self.robots_disallow {
+            body.push_str(&format!("Disallow: {}\n", path));
+        }
+        body
+    }
+}
+
+fn jwk(kid_prefix: &str, public_key: &[u8; 32]) -> serde_json::Value {
+    let digest = blake3::hash(public_key);
+    let kid = base64::encode_config(&digest.as_bytes()[..8], base64::URL_SAFE_NO_PAD);
+    serde_json::json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "alg": "EdDSA",
+        "use": "sig",
+        "kid": format!("{}-{}", kid_prefix, kid),
+        "x": base64::encode_config(public_key, base64::URL_SAFE_NO_PAD),
+    })
+}
+
+pub fn jwks(purpose_keys: &[(Purpose, [u8; 32])]) -> serde_json::Value {
+    let keys: Vec<serde_json::Value> = purpose_keys
+        .iter()
+        .map(|(purpose, public_key)| jwk(purpose.name(), public_key))
+        .collect();
+    serde_json::json!({ "keys": keys })
+}
@@ -0,0 +1,49 @@
+// Counts down to `data-expires-at` (epoch millis) on any element that has it,
+// and once the instant passes swaps in `data-expired-text` and disables the
+// forms and links inside the nearest `[data-expires-scope]`.
+(function () {
+  function remaining(ms) {
+    var secs = Math.ceil(ms / 1000);
+    var days = Math.floor(secs / 86400);
+    var hours = Math.floor((secs % 86400) / 3600);
+    var minutes = Math.floor((secs % 3600) / 60);
+    var parts = [];
+    if (days) parts.push(days + "d");
+    if (days || hours) parts.push(hours + "h");
+    if (days || hours || minutes) parts.push(minutes + "m");
+    parts.push((secs % 60) + "s");
+    return parts.join(" ");
+  }
+
+  function expire(el) {
+    el.textContent = el.getAttribute("data-expired-text");
+    el.classList.add("text-red-700", "font-bold");
+    var scope = el.closest("[data-expires-scope]");
+    if (!scope) return;
+    scope.querySelectorAll("input, button").forEach(function (control) {
+      control.disabled = true;
+    });
+    scope.querySelectorAll("a[href]").forEach(function (link) {
+      link.removeAttribute("href");
+      link.classList.add("opacity-50", "cursor-not-allowed");
+    });
+  }
+
+  function tick() {
+    var now = Date.now();
+    document.querySelectorAll("[data-expires-at]").forEach(function (el) {
+      var left = Number(el.getAttribute("data-expires-at")) - now;
+      if (left <= 0) {
+        el.removeAttribute("data-expires-at");
+        expire(el);
+      } else {
+        el.textContent = "Expires in " + remaining(left) + ".";
+      }
+    });
+  }
+
+  document.addEventListener("DOMContentLoaded", function () {
+    tick();
+    setInterval(tick, 1000);
+  });
+})();
@@ -0,0 +1,76 @@
+#![allow(dead_code)]
+
+use no_db_verify::config::Config;
+use no_db_verify::server::{self, App};
+use no_db_verify::verify;
+use secrecy::SecretVec;
+use warp::http::{header, Response};
+use warp::hyper::body::Bytes;
+
+pub fn app() -> App {
+    verify::set_secret_key(SecretVec::new(b"integration test key".to_vec()));
+    App::from_config(Config {
+        demo: true,
+        ..Config::from_env().expect("the test environment should configure cleanly")
+    })
+}
+
+pub async fn get(app: &App, path: &str, cookie: Option<&str>) -> Response<Bytes> {
+    let mut request = warp::test::request().method("GET").path(path);
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+    request.reply(&server::routes(app)).await
+}
+
+pub async fn post_form(app: &App, path: &str, body: &str, cookie: Option<&str>) -> Response<Bytes> {
+    let mut request = warp::test::request()
+        .method("POST")
+        .path(path)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(body);
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+    request.reply(&server::routes(app)).await
+}
+
+pub fn body(response: &Response<Bytes>) -> String {
+    String::from_utf8_lossy(response.body()).into_owned()
+}
+
+pub fn link_to(response: &Response<Bytes>, pathname: &str) -> String {
+    let page = body(response);
+    page.split("href=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .map(unescape)
+        .find(|href| href.starts_with(pathname))
+        .unwrap_or_else(|| panic!("no link to {} in {}", pathname, page))
+}
+
+pub fn cookie(response: &Response<Bytes>) -> String {
+    let set_cookie = response
+        .headers()
+        .get(header::SET_COOKIE)
+        .expect("response did not set a cookie")
+        .to_str()
+        .unwrap();
+    set_cookie.split(';').next().unwrap().to_string()
+}
+
+fn unescape(html: &str) -> String {
+    html.replace("&#x2f;", "/")
+        .replace("&#x27;", "'")
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+pub async fn invite(app: &App, email: &str) -> String {
+    let body = format!("requested_email={}", email.replace('@', "%40"));
+    let response = post_form(app, "/new-user", &body, None).await;
+    assert_eq!(response.status(), 200);
+    link_to(&response, server::CREATE_USER_PATHNAME)
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::{body, invite, post_form};
+use no_db_verify::verify::{self, TokenPolicy};
+use std::time::Duration;
+
+#[tokio::test]
+async fn expired_invite_link_is_rejected() {
+    let app = common::app();
+    verify::set_token_policy(TokenPolicy {
+        max_invite_age: Duration::from_secs(1),
+        clock_leeway: Duration::from_secs(0),
+        ..app.config.token_policy
+    });
+    let link = invite(&app, "late@example.com").await;
+    tokio::time::delay_for(Duration::from_millis(2100)).await;
+
+    let response = post_form(
+        &app,
+        &link,
+        "requested_name=Late&requested_username=late&requested_password=hunter2",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert!(body(&response).contains("That token seems no good."));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .find_by_email("late@example.com")
+        .is_none());
+}
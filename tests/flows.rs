@@ -0,0 +1,1090 @@
+mod common;
+
+use common::{body, cookie, get, invite, link_to, post_form};
+use no_db_verify::access_log::AccessLog;
+use no_db_verify::api_keys::{ApiKeys, NewKeyParams, Scope};
+use no_db_verify::config::Config;
+use no_db_verify::domains::AllowedDomains;
+use no_db_verify::features::Feature;
+use no_db_verify::ids::IdStrategy;
+use no_db_verify::limits::Limits;
+use no_db_verify::rotation::PasswordRotation;
+use no_db_verify::server::{
+    self, App, CONFIRM_EMAIL_CHANGE_PATHNAME, CREATE_USER_PATHNAME, REPORT_PATHNAME,
+    RESET_PASSWORD_PATHNAME, VERIFY_EMAIL_PATHNAME,
+};
+use no_db_verify::verify::{self, EmailChangeSide};
+use std::time::Duration;
+
+const SERVICE_KEY: &str = "flow test service key";
+
+#[tokio::test]
+async fn reset_link_sets_a_new_password() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    assert_eq!(generated.status(), 200);
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+
+    let confirmation = get(&app, &link, None).await;
+    assert_eq!(confirmation.status(), 200);
+    let intent = cookie(&confirmation);
+    let form_link = link_to(&confirmation, RESET_PASSWORD_PATHNAME);
+    let form = get(&app, &form_link, Some(&intent)).await;
+    assert_eq!(form.status(), 200);
+    assert!(body(&form).contains("requested_password"));
+
+    let reset = post_form(
+        &app,
+        &form_link,
+        "requested_password=correct-horse",
+        Some(&intent),
+    )
+    .await;
+    assert_eq!(reset.status(), 200);
+    assert!(body(&reset).contains("Reset Password was successful!"));
+
+    let users = app.users.lock().await;
+    let hash = users.get(&1).unwrap().bcrypt_password.as_str();
+    assert!(bcrypt::verify("correct-horse", hash).unwrap());
+    assert!(!bcrypt::verify("wrong-horse", hash).unwrap());
+}
+
+#[tokio::test]
+async fn reset_link_only_works_once() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let confirmation = get(&app, &link, None).await;
+    let intent = cookie(&confirmation);
+    let form_link = link_to(&confirmation, RESET_PASSWORD_PATHNAME);
+
+    let first = post_form(&app, &form_link, "requested_password=first", Some(&intent)).await;
+    assert!(body(&first).contains("Reset Password was successful!"));
+    let second = post_form(&app, &form_link, "requested_password=second", Some(&intent)).await;
+    assert!(body(&second).contains("That token seems no good."));
+
+    let users = app.users.lock().await;
+    assert!(bcrypt::verify("first", users.get(&1).unwrap().bcrypt_password.as_str()).unwrap());
+}
+
+#[tokio::test]
+async fn reset_post_without_confirmation_is_refused() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let refused = post_form(&app, &link, "requested_password=sneaky", None).await;
+    assert_eq!(refused.status(), 403);
+}
+
+#[tokio::test]
+async fn invite_link_creates_a_user() {
+    let app = common::app();
+    let link = invite(&app, "new@example.com").await;
+    assert_eq!(get(&app, &link, None).await.status(), 200);
+
+    let created = post_form(
+        &app,
+        &link,
+        "requested_name=New&requested_username=new&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert_eq!(created.status(), 200);
+    assert!(body(&created).contains("User was created!"));
+
+    let users = app.users.lock().await;
+    let user = users.find_by_email("new@example.com").unwrap();
+    assert_eq!(user.name, "New");
+    assert_eq!(user.username, "new");
+    assert!(bcrypt::verify("hunter2", user.bcrypt_password.as_str()).unwrap());
+    let accepted = user.tos_accepted.as_ref().unwrap();
+    assert_eq!(accepted.version, app.config.terms.version());
+    assert!(!app.config.terms.needs_acceptance(user));
+}
+
+#[tokio::test]
+async fn usernames_are_validated_unique_and_addressable() {
+    let app = common::app();
+    let first = invite(&app, "first@example.com").await;
+    let created = post_form(
+        &app,
+        &first,
+        "requested_name=First&requested_username=Ghost&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&created).contains("User was created!"));
+    let profile = get(&app, "/user/@ghost", None).await;
+    assert_eq!(profile.status(), 200);
+    assert!(body(&profile).contains("@Ghost"));
+    assert_eq!(get(&app, "/user/ghost", None).await.status(), 404);
+
+    let second = invite(&app, "second@example.com").await;
+    let taken = post_form(
+        &app,
+        &second,
+        "requested_name=Second&requested_username=GHOST&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&taken).contains("That username is already taken."));
+    let invalid = post_form(
+        &app,
+        &second,
+        "requested_name=Second&requested_username=no+spaces&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&invalid).contains("Username may only contain letters, numbers"));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .find_by_email("second@example.com")
+        .is_none());
+}
+
+#[tokio::test]
+async fn signup_requires_accepting_the_terms() {
+    let app = common::app();
+    let link = invite(&app, "terms@example.com").await;
+
+    let refused = post_form(
+        &app,
+        &link,
+        "requested_name=Terms&requested_username=terms&requested_password=hunter2",
+        None,
+    )
+    .await;
+    assert_eq!(refused.status(), 200);
+    assert!(body(&refused).contains("You must accept the Terms of Service."));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .find_by_email("terms@example.com")
+        .is_none());
+
+    let users = app.users.lock().await;
+    assert!(users
+        .values()
+        .all(|user| app.config.terms.needs_acceptance(user)));
+}
+
+#[tokio::test]
+async fn tampered_invite_link_is_rejected() {
+    let app = common::app();
+    let link = invite(&app, "new@example.com").await;
+    let tampered = link.replace("new%40example.com", "evil%40example.com");
+    assert_ne!(link, tampered);
+    assert!(tampered.starts_with(CREATE_USER_PATHNAME));
+
+    let response = post_form(
+        &app,
+        &tampered,
+        "requested_name=Evil&requested_username=evil&requested_password=hunter2",
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert!(body(&response).contains("That token seems no good."));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .find_by_email("evil@example.com")
+        .is_none());
+}
+
+#[tokio::test]
+async fn duplicate_email_is_not_created_twice() {
+    let app = common::app();
+    let first = invite(&app, "twice@example.com").await;
+    let second = invite(&app, "Twice@Example.com").await;
+    let form =
+        "requested_name=Twice&requested_username=twice&requested_password=hunter2&accept_tos=on";
+
+    let created = post_form(&app, &first, form, None).await;
+    assert!(body(&created).contains("User was created!"));
+    let duplicate = post_form(&app, &second, form, None).await;
+    assert_eq!(duplicate.status(), 400);
+}
+
+#[tokio::test]
+async fn closed_registration_waitlists_then_invites() {
+    let app = common::app();
+    let closed = post_form(&app, "/waitlist/registration", "open=false", None).await;
+    assert_eq!(closed.status(), 200);
+
+    let queued = post_form(
+        &app,
+        "/new-user",
+        "requested_email=wait%40example.com",
+        None,
+    )
+    .await;
+    assert_eq!(queued.status(), 200);
+    assert!(body(&queued).contains("is on the waitlist"));
+    assert!(!body(&queued).contains(CREATE_USER_PATHNAME));
+    assert!(body(&get(&app, "/waitlist", None).await).contains("wait@example.com"));
+
+    let minted = post_form(&app, "/waitlist/invite", "count=5", None).await;
+    assert_eq!(minted.status(), 200);
+    assert!(body(&minted).contains("Invited 1 address."));
+    let link = link_to(&minted, CREATE_USER_PATHNAME);
+    assert!(body(&get(&app, "/waitlist", None).await).contains("Nobody is waiting."));
+
+    let created = post_form(
+        &app,
+        &link,
+        "requested_name=Wait&requested_username=wait&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&created).contains("User was created!"));
+}
+
+#[tokio::test]
+async fn signups_are_limited_to_allowed_domains() {
+    let open = common::app();
+    let minted_earlier = invite(&open, "outsider@example.com").await;
+    let app = App::from_config(Config {
+        allowed_domains: AllowedDomains::new(vec!["@spookysoftware.dev".to_string()]),
+        ..open.config.clone()
+    });
+
+    let refused = post_form(
+        &app,
+        "/new-user",
+        "requested_email=evil%40example.com",
+        None,
+    )
+    .await;
+    assert_eq!(refused.status(), 400);
+    assert!(body(&refused).contains("only @spookysoftware.dev addresses can sign up"));
+    invite(&app, "Ghost@SpookySoftware.dev").await;
+
+    let created = post_form(
+        &app,
+        &minted_earlier,
+        "requested_name=Outsider&requested_username=outsider&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&created).contains("can no longer sign up here"));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .find_by_email("outsider@example.com")
+        .is_none());
+}
+
+#[tokio::test]
+async fn reserved_names_are_rejected_inline() {
+    let app = common::app();
+    let link = invite(&app, "impostor@example.com").await;
+    let refused = post_form(
+        &app,
+        &link,
+        "requested_name=Site+Support&requested_username=the_Admin&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert_eq!(refused.status(), 200);
+    let page = body(&refused);
+    assert!(page.contains("That name is reserved or not allowed."));
+    assert!(page.contains("That username is reserved or not allowed."));
+
+    let allowed = post_form(
+        &app,
+        &link,
+        "requested_name=Badminton+Fan&requested_username=badminton&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&allowed).contains("User was created!"));
+}
+
+#[tokio::test]
+async fn hidden_characters_are_rejected_inline() {
+    let app = common::app();
+    let hidden = post_form(
+        &app,
+        "/new-user",
+        "requested_email=ne%E2%80%8Bo%40example.com",
+        None,
+    )
+    .await;
+    assert_eq!(hidden.status(), 400);
+    assert!(body(&hidden).contains("invisible or text-direction characters"));
+
+    let link = invite(&app, "bidi@example.com").await;
+    let refused = post_form(
+        &app,
+        &link,
+        "requested_name=%E2%80%AEoeN&requested_username=bidi&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(
+        body(&refused).contains("Name can&#x27;t contain invisible or text-direction characters.")
+    );
+}
+
+#[tokio::test]
+async fn form_fields_are_length_checked_and_stripped() {
+    let app = common::app();
+    let link = invite(&app, "long@example.com").await;
+    let too_long = post_form(
+        &app,
+        &link,
+        &format!(
+            "requested_name={}&requested_username=long&requested_password={}&accept_tos=on",
+            "n".repeat(101),
+            "p".repeat(73)
+        ),
+        None,
+    )
+    .await;
+    let page = body(&too_long);
+    assert!(page.contains("Name must be at most 100 characters."));
+    assert!(page.contains("Password must be at most 72 bytes."));
+
+    let created = post_form(
+        &app,
+        &link,
+        "requested_name=%07Trinity%0D%0A&requested_username=trin&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&created).contains("User was created!"));
+    let users = app.users.lock().await;
+    let user = users.find_by_email("long@example.com").unwrap();
+    assert_eq!(user.name, "Trinity");
+}
+
+fn stat(page: &str, label: &str) -> String {
+    let after = &page[page.find(label).unwrap()..];
+    let cell = &after[after.find("<td").unwrap()..];
+    let value = &cell[cell.find('>').unwrap() + 1..];
+    value[..value.find('<').unwrap()].trim().to_string()
+}
+
+#[tokio::test]
+async fn stats_page_counts_users_and_rejected_tokens() {
+    let app = common::app();
+    let link = invite(&app, "counted@example.com").await;
+    let tampered = link.replace("counted%40example.com", "forged%40example.com");
+    post_form(
+        &app,
+        &tampered,
+        "requested_name=Forged&requested_username=forged&requested_password=hunter2",
+        None,
+    )
+    .await;
+    let created = post_form(
+        &app,
+        &link,
+        "requested_name=Counted&requested_username=counted&requested_password=hunter2&accept_tos=on",
+        None,
+    )
+    .await;
+    assert!(body(&created).contains("User was created!"));
+
+    let stats = get(&app, "/admin/stats", None).await;
+    assert_eq!(stats.status(), 200);
+    let page = body(&stats);
+    assert_eq!(stat(&page, "Total users"), "7");
+    assert_eq!(stat(&page, "Verified"), "1");
+    assert_eq!(stat(&page, "Unverified"), "6");
+    assert_eq!(stat(&page, "Rejected invite tokens"), "1");
+    assert_eq!(stat(&page, "Rejected reset tokens"), "0");
+}
+
+#[tokio::test]
+async fn posts_past_the_concurrency_limit_get_a_busy_page() {
+    let mut app = common::app();
+    app.limits = Limits::new(0, Duration::from_secs(30));
+
+    let busy = post_form(
+        &app,
+        "/new-user",
+        "requested_email=busy%40example.com",
+        None,
+    )
+    .await;
+    assert_eq!(busy.status(), 503);
+    assert_eq!(busy.headers()["retry-after"], "1");
+    assert!(body(&busy).contains("we&#x27;re busy right now"));
+
+    assert_eq!(get(&app, "/list", None).await.status(), 200);
+    assert_eq!(get(&app, "/no-such-page", None).await.status(), 404);
+}
+
+#[tokio::test]
+async fn readyz_reports_backend_circuits() {
+    let app = common::app();
+    let ready = get(&app, "/readyz", None).await;
+    assert_eq!(ready.status(), 200);
+    assert!(body(&ready).contains("\"ready\":true"));
+}
+
+#[tokio::test]
+async fn report_downloads_as_csv() {
+    let app = common::app();
+    let report = get(&app, "/admin/report.csv", None).await;
+    assert_eq!(report.status(), 200);
+    assert_eq!(report.headers()["content-type"], "text/csv; charset=utf-8");
+    let disposition = report.headers()["content-disposition"].to_str().unwrap();
+    assert!(disposition.starts_with("attachment; filename=\"user-report-"));
+    let csv = body(&report);
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("id,username,name,email,created_at,verified,verified_at,last_reset")
+    );
+    assert_eq!(lines.count(), 6);
+}
+
+#[tokio::test]
+async fn list_is_available_as_json() {
+    let app = common::app();
+    let listing = get(&app, "/list?format=json", None).await;
+    assert_eq!(listing.status(), 200);
+    assert_eq!(listing.headers()["content-type"], "application/json");
+    let listing: serde_json::Value = serde_json::from_slice(listing.body()).unwrap();
+    let users = listing["users"].as_array().unwrap();
+    assert_eq!(users.len(), 6);
+    assert_eq!(users[0]["id"], 1);
+    assert_eq!(users[0]["username"], "neo");
+    assert!(users[0].get("password_hash").is_none());
+
+    assert_eq!(get(&app, "/list?format=xml", None).await.status(), 400);
+}
+
+#[tokio::test]
+async fn debug_errors_show_request_details_only_when_enabled() {
+    let quiet = common::app();
+    let hidden = get(&quiet, "/list?format=xml", None).await;
+    assert_eq!(hidden.status(), 400);
+    assert!(!body(&hidden).contains("format=xml"));
+
+    let app = App::from_config(Config {
+        debug_errors: true,
+        ..quiet.config.clone()
+    });
+    let shown = get(&app, "/list?format=xml", None).await;
+    assert_eq!(shown.status(), 400);
+    let page = body(&shown);
+    assert!(page.contains("GET &#x2f;list?format=xml"));
+    assert!(page.contains("BadRequest"));
+}
+
+#[tokio::test]
+async fn required_tls_marks_cookies_secure() {
+    let plain = common::app();
+    let page = get(&plain, "/list", None).await;
+    assert!(page.headers().get("strict-transport-security").is_none());
+
+    let app = App::from_config(Config {
+        require_tls: true,
+        ..plain.config.clone()
+    });
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    assert!(generated
+        .headers()
+        .get("strict-transport-security")
+        .is_some());
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let confirmation = get(&app, &link, None).await;
+    let set_cookie = confirmation.headers()["set-cookie"].to_str().unwrap();
+    assert!(set_cookie.ends_with("; Secure"));
+}
+
+#[tokio::test]
+async fn expiries_render_as_readable_local_times() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let page = body(&generated);
+    assert!(page.contains(" UTC."));
+    assert!(!page.contains("Z."));
+}
+
+#[tokio::test]
+async fn reset_pages_carry_a_countdown() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    assert!(body(&generated).contains("data-expires-at=\""));
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let confirmation = get(&app, &link, None).await;
+    assert!(body(&confirmation).contains("data-expires-at=\""));
+
+    let script = get(&app, "/static/countdown.js", None).await;
+    assert_eq!(script.status(), 200);
+    assert!(script.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/javascript"));
+    assert!(body(&script).contains("data-expired-text"));
+}
+
+#[tokio::test]
+async fn share_links_show_a_read_only_filtered_list() {
+    let app = common::app();
+    let minted = get(&app, "/list/share?filter=linus", None).await;
+    assert_eq!(minted.status(), 200);
+    let link = link_to(&minted, "/shared/list");
+
+    let shared = get(&app, &link, None).await;
+    assert_eq!(shared.status(), 200);
+    let page = body(&shared);
+    assert!(page.contains("@linus"));
+    assert!(!page.contains("@michelle"));
+    assert!(!page.contains("/reset-password-generate/"));
+
+    let widened = link.replace("filter=linus", "filter=i");
+    assert_eq!(get(&app, &widened, None).await.status(), 403);
+}
+
+#[tokio::test]
+async fn notification_preferences_are_saved() {
+    let app = common::app();
+    let detail = get(&app, "/users/1", None).await;
+    assert!(body(&detail).contains("/users/1/preferences"));
+
+    let saved = post_form(&app, "/users/1/preferences", "sms=on&marketing=on", None).await;
+    assert_eq!(saved.status(), 200);
+    assert!(body(&saved).contains("Notification preferences saved."));
+    let (preferences, version) = {
+        let users = app.users.lock().await;
+        let user = users.get(&1).unwrap();
+        (user.preferences, user.version)
+    };
+    assert!(!preferences.email && preferences.sms && preferences.marketing);
+
+    let unchanged = post_form(&app, "/users/1/preferences", "sms=on&marketing=on", None).await;
+    assert_eq!(unchanged.status(), 200);
+    assert_eq!(app.users.lock().await.get(&1).unwrap().version, version);
+}
+
+#[tokio::test]
+async fn unsubscribe_links_turn_a_topic_off() {
+    let app = common::app();
+    post_form(&app, "/users/1/preferences", "email=on&marketing=on", None).await;
+    let params = verify::UnsubscribeParams::new(1, "marketing".parse().unwrap());
+    let query = serde_urlencoded::to_string(&params).unwrap();
+
+    let unsubscribed = get(&app, &format!("/unsubscribe?{}", query), None).await;
+    assert_eq!(unsubscribed.status(), 200);
+    assert!(body(&unsubscribed).contains("news and offers"));
+    let preferences = app.users.lock().await.get(&1).unwrap().preferences;
+    assert!(preferences.email && !preferences.marketing);
+
+    let retargeted = query.replace("topic=marketing", "topic=email");
+    let refused = get(&app, &format!("/unsubscribe?{}", retargeted), None).await;
+    assert_eq!(refused.status(), 403);
+    assert!(app.users.lock().await.get(&1).unwrap().preferences.email);
+}
+
+#[tokio::test]
+async fn duplicate_accounts_merge_into_the_primary() {
+    let app = common::app();
+    let (duplicate_id, duplicate_email) = {
+        let mut users = app.users.lock().await;
+        let id = *users.keys().find(|id| **id != 1).unwrap();
+        let duplicate = users.get_mut(&id).unwrap();
+        duplicate.verified_at = Some(chrono::Utc::now());
+        (id, duplicate.email.clone())
+    };
+
+    let request = format!("primary=1&duplicate={}", duplicate_id);
+    let review = post_form(&app, "/admin/merge", &request, None).await;
+    assert_eq!(review.status(), 200);
+    assert!(body(&review).contains("name=\"token\""));
+    let same = post_form(&app, "/admin/merge", "primary=1&duplicate=1", None).await;
+    assert_eq!(same.status(), 400);
+
+    let params = serde_urlencoded::to_string(verify::MergeParams::new(1, duplicate_id)).unwrap();
+    let merged = post_form(&app, "/admin/merge/confirm", &params, None).await;
+    assert_eq!(merged.status(), 200);
+    assert!(body(&merged).contains("was merged into"));
+
+    let users = app.users.lock().await;
+    assert!(users.get(&duplicate_id).is_none());
+    let alias = &users.get(&1).unwrap().email_aliases[0];
+    assert!(alias.email == duplicate_email && alias.verified_at.is_some());
+    drop(users);
+    let old_link = get(&app, &format!("/users/{}", duplicate_id), None).await;
+    assert_eq!(old_link.status(), 200);
+    assert!(body(&old_link).contains("@neo"));
+}
+
+#[tokio::test]
+async fn dry_runs_change_nothing() {
+    let app = common::app();
+    let duplicate_id = *app.users.lock().await.keys().find(|id| **id != 1).unwrap();
+    let users_before = app.users.lock().await.len();
+
+    let form = format!("action=delete&selected=1&selected={}", duplicate_id);
+    let preview = post_form(&app, "/list/bulk?dry_run=true", &form, None).await;
+    assert_eq!(preview.status(), 200);
+    let page = body(&preview);
+    assert!(page.contains("would apply to 2 users"));
+    assert!(page.contains("would be deleted"));
+    assert!(page.contains("name=\"selected\" value=\"1\""));
+    assert_eq!(app.users.lock().await.len(), users_before);
+
+    let params = serde_urlencoded::to_string(verify::MergeParams::new(1, duplicate_id)).unwrap();
+    let events_before = app.audit.events().len();
+    let preview = post_form(&app, "/admin/merge/confirm?dry_run=true", &params, None).await;
+    assert_eq!(preview.status(), 200);
+    assert!(body(&preview).contains("would be merged into"));
+    assert!(app.users.lock().await.get(&duplicate_id).is_some());
+    assert_eq!(app.audit.events().len(), events_before);
+
+    let merged = post_form(&app, "/admin/merge/confirm", &params, None).await;
+    assert!(body(&merged).contains("was merged into"));
+}
+
+#[tokio::test]
+async fn email_aliases_are_verified_one_by_one() {
+    let app = common::app();
+    let added = post_form(&app, "/users/1/aliases", "email=Neo%40Work.example", None).await;
+    assert_eq!(added.status(), 200);
+    assert!(body(&added).contains("Valid for 1 day"));
+    let link = link_to(&added, VERIFY_EMAIL_PATHNAME);
+    {
+        let users = app.users.lock().await;
+        let user = users.find_by_email("neo@work.example").unwrap();
+        assert_eq!(user.id, 1);
+        assert!(user.email_aliases[0].verified_at.is_none());
+    }
+
+    let taken = post_form(&app, "/users/1/aliases", "email=neo%40work.example", None).await;
+    assert_eq!(taken.status(), 400);
+    assert!(body(&taken).contains("already registered"));
+    let signup = invite(&app, "neo@work.example").await;
+    let form =
+        "requested_name=Other&requested_username=other&requested_password=hunter2&accept_tos=on";
+    assert_eq!(post_form(&app, &signup, form, None).await.status(), 400);
+
+    let verified = get(&app, &link, None).await;
+    assert_eq!(verified.status(), 200);
+    assert!(body(&verified).contains("Email address verified."));
+    let users = app.users.lock().await;
+    let user = users.get(&1).unwrap();
+    assert!(user.email_aliases[0].verified_at.is_some());
+    assert!(user.verified_at.is_none());
+    drop(users);
+
+    let forged = link.replace("user_id=1", "user_id=2");
+    assert_eq!(get(&app, &forged, None).await.status(), 403);
+}
+
+#[tokio::test]
+async fn stale_passwords_are_flagged_in_the_list() {
+    let plain = common::app();
+    let hour = Duration::from_secs(60 * 60);
+    let app = App::from_config(Config {
+        password_rotation: PasswordRotation::new(Some(hour), 24 * hour),
+        ..plain.config.clone()
+    });
+    {
+        let mut users = app.users.lock().await;
+        let now = chrono::Utc::now();
+        users.get_mut(&1).unwrap().password_changed_at = now - chrono::Duration::hours(2);
+        let other = *users.keys().find(|id| **id != 1).unwrap();
+        users.get_mut(&other).unwrap().password_changed_at = now - chrono::Duration::days(2);
+    }
+
+    let page = body(&get(&app, "/list", None).await);
+    assert_eq!(page.matches("Password expired").count(), 1);
+    assert_eq!(page.matches("Password locked").count(), 1);
+    let json = body(&get(&app, "/list?format=json", None).await);
+    assert!(json.contains("\"password_age\":\"locked\""));
+
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let confirmation = get(&app, &link, None).await;
+    let intent = cookie(&confirmation);
+    let form_link = link_to(&confirmation, RESET_PASSWORD_PATHNAME);
+    post_form(
+        &app,
+        &form_link,
+        "requested_password=correct-horse",
+        Some(&intent),
+    )
+    .await;
+    let page = body(&get(&app, "/list", None).await);
+    assert!(!page.contains("Password expired"));
+    assert!(!body(&get(&plain, "/list", None).await).contains("Password locked"));
+}
+
+#[tokio::test]
+async fn unwanted_resets_can_be_reverted() {
+    let app = common::app();
+    let before = app
+        .users
+        .lock()
+        .await
+        .get(&1)
+        .unwrap()
+        .bcrypt_password
+        .clone();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let confirmation = get(&app, &link, None).await;
+    let intent = cookie(&confirmation);
+    let form_link = link_to(&confirmation, RESET_PASSWORD_PATHNAME);
+    post_form(
+        &app,
+        &form_link,
+        "requested_password=correct-horse",
+        Some(&intent),
+    )
+    .await;
+
+    let params = verify::RevertParams::from(app.users.lock().await.get(&1).unwrap());
+    let revert = format!("/revert?{}", serde_urlencoded::to_string(&params).unwrap());
+    let page = get(&app, &revert, None).await;
+    assert_eq!(page.status(), 200);
+    assert!(body(&page).contains("was just reset"));
+
+    let reverted = post_form(&app, &revert, "", None).await;
+    assert_eq!(reverted.status(), 200);
+    assert!(body(&reverted).contains("back to what it was"));
+    assert!(app.users.lock().await.get(&1).unwrap().bcrypt_password == before);
+    assert_eq!(post_form(&app, &revert, "", None).await.status(), 403);
+}
+
+#[tokio::test]
+async fn locked_accounts_refuse_reset_links() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+
+    let locked = post_form(&app, "/users/1/lock", "locked=true", None).await;
+    assert_eq!(locked.status(), 200);
+    assert!(body(&locked).contains("Account locked."));
+    let refused = get(&app, &link, None).await;
+    assert_eq!(refused.status(), 403);
+    assert!(body(&refused).contains("contact support"));
+
+    let unlocked = post_form(&app, "/users/1/lock", "locked=false", None).await;
+    assert!(body(&unlocked).contains("Account unlocked."));
+    assert_eq!(get(&app, &link, None).await.status(), 200);
+}
+
+#[tokio::test]
+async fn email_changes_wait_for_both_addresses() {
+    let app = common::app();
+    let old_email = app.users.lock().await.get(&1).unwrap().email.clone();
+    let requested = post_form(
+        &app,
+        "/users/1/email-change",
+        "email=neo%40new.example",
+        None,
+    )
+    .await;
+    assert_eq!(requested.status(), 200);
+    assert!(body(&requested).contains("Waiting for confirmation"));
+    assert!(body(&requested).contains("Valid for 1 day"));
+
+    let pending = app
+        .users
+        .lock()
+        .await
+        .get(&1)
+        .unwrap()
+        .pending_email_change
+        .clone()
+        .unwrap();
+    let link = |side| {
+        let params = verify::EmailChangeParams::new(1, side, &pending);
+        format!(
+            "{}?{}",
+            CONFIRM_EMAIL_CHANGE_PATHNAME,
+            serde_urlencoded::to_string(&params).unwrap()
+        )
+    };
+    let old_link = link(EmailChangeSide::Old);
+    let new_link = link(EmailChangeSide::New);
+
+    let forged = old_link.replace("side=old", "side=new");
+    assert_eq!(get(&app, &forged, None).await.status(), 403);
+    let half = get(&app, &new_link, None).await;
+    assert_eq!(half.status(), 200);
+    assert!(body(&half).contains("once the other address confirms"));
+    assert_eq!(app.users.lock().await.get(&1).unwrap().email, old_email);
+
+    let done = get(&app, &old_link, None).await;
+    assert_eq!(done.status(), 200);
+    assert!(body(&done).contains("Email address changed."));
+    let users = app.users.lock().await;
+    let user = users.get(&1).unwrap();
+    assert_eq!(user.email.as_str(), "neo@new.example");
+    assert!(user.pending_email_change.is_none());
+    assert!(users.find_by_email(old_email.as_str()).is_none());
+    drop(users);
+    assert_eq!(get(&app, &old_link, None).await.status(), 403);
+}
+
+#[tokio::test]
+async fn reported_reset_links_stop_working() {
+    let app = common::app();
+    let generated = get(&app, "/reset-password-generate/1", None).await;
+    let link = link_to(&generated, RESET_PASSWORD_PATHNAME);
+    let report = link_to(&generated, REPORT_PATHNAME);
+
+    let page = get(&app, &report, None).await;
+    assert_eq!(page.status(), 200);
+    assert!(body(&page).contains("report it here"));
+    let reported = post_form(&app, &report, "", None).await;
+    assert_eq!(reported.status(), 200);
+    assert!(body(&reported).contains("no longer works"));
+    assert!(app
+        .users
+        .lock()
+        .await
+        .get(&1)
+        .unwrap()
+        .reported_at
+        .is_some());
+
+    let confirmation = get(&app, &link, None).await;
+    let intent = cookie(&confirmation);
+    let form_link = link_to(&confirmation, RESET_PASSWORD_PATHNAME);
+    let refused = post_form(&app, &form_link, "requested_password=sneaky", Some(&intent)).await;
+    assert!(body(&refused).contains("That token seems no good."));
+}
+
+#[tokio::test]
+async fn snowflake_ids_carry_the_node_id() {
+    let plain = common::app();
+    let app = App::from_config(Config {
+        id_strategy: IdStrategy::Snowflake { node: 5 },
+        ..plain.config.clone()
+    });
+    let mut ids = Vec::new();
+    for name in ["first", "second"].iter() {
+        let email = format!("{}@example.com", name);
+        let link = invite(&app, &email).await;
+        let form = format!(
+            "requested_name={0}&requested_username={0}&requested_password=hunter2&accept_tos=on",
+            name
+        );
+        assert_eq!(post_form(&app, &link, &form, None).await.status(), 200);
+        ids.push(app.users.lock().await.find_by_email(&email).unwrap().id);
+    }
+    assert!(ids[0] < ids[1]);
+    assert!(ids.iter().all(|id| (id >> 12) & 0x3ff == 5));
+}
+
+#[tokio::test]
+async fn requests_are_logged_as_json_lines() {
+    let path = std::env::temp_dir().join(format!("access-log-{}.jsonl", std::process::id()));
+    let plain = common::app();
+    let app = App::from_config(Config {
+        access_log: AccessLog::to_file(&path).unwrap(),
+        ..plain.config.clone()
+    });
+
+    let listed = get(&app, "/users/1", None).await;
+    let generated_id = listed.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(generated_id.len(), 16);
+    let forwarded = warp::test::request()
+        .path("/nowhere")
+        .header("x-request-id", "edge-42")
+        .reply(&server::routes(&app))
+        .await;
+    assert_eq!(forwarded.headers()["x-request-id"], "edge-42");
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["route"], "/users/:id");
+    assert_eq!(lines[0]["method"], "GET");
+    assert_eq!(lines[0]["status"], 200);
+    assert_eq!(lines[0]["request_id"], generated_id.as_str());
+    assert!(lines[0]["latency_ms"].is_number());
+    assert_eq!(lines[1]["route"], "other");
+    assert_eq!(lines[1]["status"], 404);
+    assert_eq!(lines[1]["request_id"], "edge-42");
+}
+
+#[tokio::test]
+async fn maintenance_mode_closes_everything_but_health_checks() {
+    let app = common::app();
+    let started = post_form(&app, "/admin/maintenance", "on=true", None).await;
+    assert_eq!(started.status(), 200);
+    assert!(body(&started).contains("End Maintenance"));
+
+    let list = get(&app, "/list", None).await;
+    assert_eq!(list.status(), 503);
+    assert!(body(&list).contains("down for maintenance"));
+    let reset = post_form(&app, "/reset-password", "", None).await;
+    assert_eq!(reset.status(), 503);
+    assert_eq!(get(&app, "/readyz", None).await.status(), 200);
+    assert_eq!(get(&app, "/admin/maintenance", None).await.status(), 200);
+
+    post_form(&app, "/admin/maintenance", "on=false", None).await;
+    assert_eq!(get(&app, "/list", None).await.status(), 200);
+}
+
+#[tokio::test]
+async fn every_page_carries_the_app_name() {
+    let plain = common::app();
+    let app = App::from_config(Config {
+        app_name: "Acme Accounts".to_string(),
+        ..plain.config.clone()
+    });
+    for path in &[
+        "/list",
+        "/users/1",
+        "/new-user",
+        "/reset-password?user_id=1",
+    ] {
+        let page = get(&app, path, None).await;
+        assert!(
+            body(&page).contains(" · Acme Accounts</title>"),
+            "{} is missing the app name",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn path_variants_redirect_to_one_spelling() {
+    let app = common::app();
+    for (path, location) in &[
+        ("/LIST", "/list"),
+        ("/list/?format=json", "/list?format=json"),
+        ("//users//1/", "/users/1"),
+        ("/USER/@Neo", "/user/@Neo"),
+    ] {
+        let redirected = get(&app, path, None).await;
+        assert_eq!(redirected.status(), 308, "{}", path);
+        assert_eq!(redirected.headers()["location"], *location);
+    }
+    assert_eq!(get(&app, "/list", None).await.status(), 200);
+    assert_eq!(get(&app, "/nowhere/", None).await.status(), 404);
+}
+
+#[tokio::test]
+async fn reset_links_are_throttled_per_user() {
+    let app = common::app();
+    for _ in 0..5 {
+        let generated = get(&app, "/reset-password-generate/1", None).await;
+        assert_eq!(generated.status(), 200);
+    }
+    let refused = get(&app, "/reset-password-generate/1", None).await;
+    assert_eq!(refused.status(), 429);
+    assert!(refused.headers().contains_key("retry-after"));
+    assert!(body(&refused).contains("Too many reset links"));
+
+    let bulk = post_form(&app, "/list/bulk", "action=reset_links&selected=1", None).await;
+    assert!(body(&bulk).contains("too many recent reset links"));
+
+    let other_id = *app.users.lock().await.keys().find(|id| **id != 1).unwrap();
+    let other = get(
+        &app,
+        &format!("/reset-password-generate/{}", other_id),
+        None,
+    )
+    .await;
+    assert_eq!(other.status(), 200);
+}
+
+#[tokio::test]
+async fn client_keys_are_only_minted_with_a_service_key() {
+    let plain = common::app();
+    let refused = post_form(&plain, "/api-keys", "name=ci&scope=read_users", None).await;
+    assert_eq!(refused.status(), 404);
+
+    let app = App::from_config(Config {
+        api_keys: ApiKeys::new(&[SERVICE_KEY.to_string()]).unwrap(),
+        ..plain.config.clone()
+    });
+    let anonymous = post_form(&app, "/api-keys", "name=ci&scope=read_users", None).await;
+    assert_eq!(anonymous.status(), 401);
+    assert_eq!(get(&app, "/api-keys", None).await.status(), 401);
+
+    let created = warp::test::request()
+        .method("POST")
+        .path("/api-keys")
+        .header("authorization", format!("Bearer {}", SERVICE_KEY))
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body("name=ci&scope=read_users")
+        .reply(&server::routes(&app))
+        .await;
+    assert_eq!(created.status(), 200);
+    assert!(body(&created).contains("ndv_"));
+}
+
+#[tokio::test]
+async fn a_stale_if_match_does_not_change_the_lock() {
+    let plain = common::app();
+    let mut features = plain.config.features.clone();
+    features.enable(Feature::ApiEnabled);
+    let app = App::from_config(Config {
+        features,
+        ..plain.config.clone()
+    });
+    let (_, key) = app
+        .config
+        .api_keys
+        .create(NewKeyParams {
+            name: "ops".to_string(),
+            scopes: vec![Scope::ReadUsers, Scope::ManageUsers],
+        })
+        .await;
+    let authorization = format!("Bearer {}", key);
+    let routes = server::routes(&app);
+    let read = || {
+        warp::test::request()
+            .path("/api/users/1")
+            .header("authorization", &authorization)
+            .reply(&routes)
+    };
+    let lock = |locked: bool, if_match: String| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/users/1/lock")
+            .header("authorization", &authorization)
+            .header("content-type", "application/json")
+            .header("if-match", if_match)
+            .body(format!("{{\"locked\":{}}}", locked))
+            .reply(&routes)
+    };
+    let etag = |response: &warp::http::Response<_>| {
+        response.headers()["etag"].to_str().unwrap().to_string()
+    };
+
+    let before = etag(&read().await);
+    let locked = lock(true, before.clone()).await;
+    assert_eq!(locked.status(), 200);
+    assert_ne!(etag(&locked), before);
+
+    let stale = lock(false, before).await;
+    assert_eq!(stale.status(), 412);
+    let weak = lock(false, format!("W/{}", etag(&locked))).await;
+    assert_eq!(weak.status(), 412);
+    assert!(body(&read().await).contains("\"locked\":true"));
+}
+
+#[tokio::test]
+async fn oversized_bulk_forms_are_refused() {
+    let app = common::app();
+    let form = format!("action=delete{}", "&selected=1".repeat(8 * 1024));
+    let refused = post_form(&app, "/list/bulk", &form, None).await;
+    assert_eq!(refused.status(), 413);
+    assert!(app.users.lock().await.contains_key(&1));
+}